@@ -0,0 +1,261 @@
+use crate::corporate_actions;
+use crate::error::Error;
+use crate::trades::{self, Trade};
+use crate::tx_types::TxType;
+use crate::utils;
+use chrono::NaiveDate;
+use serde::{Serialize,Deserialize};
+use std::collections::{BTreeMap, BTreeSet};
+use tokio_postgres::Row;
+use tracing::info;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct CashEntry {
+    pub handle: String,
+    pub account_name: String,
+    pub currency: String,
+    pub activity: TxType,
+    pub trade_id: Option<i32>,
+    pub trade_date: NaiveDate,
+    pub settlement_date: NaiveDate,
+    pub amount: f64,
+    pub description: String
+}
+
+impl From<Row> for CashEntry {
+    fn from(row: tokio_postgres::Row) -> Self {
+        let activity: String = row.get("activity");
+        Self {
+            handle: row.get("handle"),
+            account_name: row.get("account_name"),
+            currency: row.get("currency"),
+            activity: activity.parse().unwrap_or(TxType::Other),
+            trade_id: row.get("trade_id"),
+            trade_date: row.get("trade_date"),
+            settlement_date: row.get("settlement_date"),
+            amount: row.get("amount"),
+            description: row.get("description"),
+        }
+    }
+}
+
+/// settled is what's actually in the account on the day, projected counts everything traded but not yet settled
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct CashBalance {
+    pub handle: String,
+    pub account_name: String,
+    pub currency: String,
+    pub balance_date: NaiveDate,
+    pub settled: f64,
+    pub projected: f64
+}
+
+impl From<&Trade> for CashEntry {
+    fn from(t: &Trade) -> Self {
+        let activity = t.tx();
+        let sign = activity.cash_sign().unwrap_or(t.net_amount.signum());
+        Self {
+            handle: t.handle.clone(),
+            account_name: t.account_name.to_uppercase(),
+            currency: t.currency.clone(),
+            activity,
+            trade_id: t.id,
+            trade_date: utils::to_date(t.trade_date),
+            settlement_date: utils::to_date(t.settlement_date),
+            amount: sign * t.net_amount.abs(),
+            description: format!("{} {}", t.tx_type, t.security_ticker)
+        }
+    }
+}
+
+pub async fn build_cash_tables(client: &tokio_postgres::Client) -> Result<(), Error> {
+
+    client.query("CREATE TABLE cash_ledger (id SERIAL PRIMARY KEY,
+        handle VARCHAR NOT NULL,
+        account_name VARCHAR NOT NULL,
+        currency VARCHAR NOT NULL,
+        activity VARCHAR NOT NULL,
+        trade_id INT,
+        trade_date DATE NOT NULL,
+        settlement_date DATE NOT NULL,
+        amount FLOAT8 NOT NULL,
+        description VARCHAR NOT NULL
+        )", &[]).await?;
+
+    client.query("CREATE TABLE cash_balances (id SERIAL PRIMARY KEY,
+        handle VARCHAR NOT NULL,
+        account_name VARCHAR NOT NULL,
+        currency VARCHAR NOT NULL,
+        balance_date DATE NOT NULL,
+        settled FLOAT8 NOT NULL,
+        projected FLOAT8 NOT NULL,
+        UNIQUE (handle, account_name, currency, balance_date)
+        )", &[]).await?;
+
+    Ok(())
+}
+
+pub async fn drop_cash_tables(client: &tokio_postgres::Client) -> Result<(), Error> {
+
+    client.query("drop TABLE cash_ledger", &[]).await?;
+    client.query("drop TABLE cash_balances", &[]).await?;
+
+    Ok(())
+}
+
+async fn clean_cash(client: &tokio_postgres::Client, handle: &str) -> Result<(), Error> {
+
+    let statement = client.prepare("delete from cash_ledger where handle = $1").await?;
+    client.execute(&statement,&[&handle]).await?;
+    let statement = client.prepare("delete from cash_balances where handle = $1").await?;
+    client.execute(&statement,&[&handle]).await?;
+
+    Ok(())
+}
+
+async fn insert_cash_entry(client: &tokio_postgres::Client, e: &CashEntry) -> Result<(), Error> {
+
+    let statement = client.prepare("INSERT INTO cash_ledger (
+        handle,
+        account_name,
+        currency,
+        activity,
+        trade_id,
+        trade_date,
+        settlement_date,
+        amount,
+        description
+        ) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)").await?;
+
+    client.execute(&statement,&[
+        &e.handle,
+        &e.account_name,
+        &e.currency,
+        &e.activity.to_string(),
+        &e.trade_id,
+        &e.trade_date,
+        &e.settlement_date,
+        &e.amount,
+        &e.description
+        ]).await?;
+    Ok(())
+}
+
+async fn insert_cash_balance(client: &tokio_postgres::Client, b: &CashBalance) -> Result<(), Error> {
+
+    let statement = client.prepare("INSERT INTO cash_balances (
+        handle,
+        account_name,
+        currency,
+        balance_date,
+        settled,
+        projected
+        ) VALUES ($1, $2, $3, $4, $5, $6)").await?;
+
+    client.execute(&statement,&[
+        &b.handle,
+        &b.account_name,
+        &b.currency,
+        &b.balance_date,
+        &b.settled,
+        &b.projected
+        ]).await?;
+    Ok(())
+}
+
+pub async fn get_ledger(client: &tokio_postgres::Client, handle: &str) -> Result<Vec<CashEntry>, Error> {
+
+    let rows = client.query("SELECT * FROM cash_ledger WHERE handle = $1 ORDER BY settlement_date, id", &[&handle]).await?;
+    Ok(rows.into_iter().map(CashEntry::from).collect())
+}
+
+/// Balances per account and currency at the end of the day.
+pub fn balances(ledger: &[CashEntry], balance_date: NaiveDate) -> Vec<CashBalance> {
+
+    let mut g: BTreeMap<(String,String), CashBalance> = BTreeMap::new();
+
+    for e in ledger.iter().filter(|e| e.trade_date <= balance_date) {
+        let b = g.entry((e.account_name.clone(), e.currency.clone())).or_insert(CashBalance {
+            handle: e.handle.clone(),
+            account_name: e.account_name.clone(),
+            currency: e.currency.clone(),
+            balance_date,
+            settled: 0.,
+            projected: 0.
+        });
+        b.projected += e.amount;
+        if e.settlement_date <= balance_date {
+            b.settled += e.amount;
+        }
+    }
+
+    g.into_values().collect()
+}
+
+/// Cash from trades and from corporate actions, which pay out on the effective date without ever being in the trades table.
+pub fn ledger(trades: &[Trade], actions: &[corporate_actions::CorporateAction]) -> Vec<CashEntry> {
+
+    let events = corporate_actions::position_events(&corporate_actions::apply(trades, actions), actions);
+    trades.iter().chain(events.iter().filter(|e| e.net_amount != 0.)).map(CashEntry::from).collect()
+}
+
+/// Rebuilds the ledger for the handle off the trades table and writes out a balance for every day that has activity.
+pub async fn post(client: &tokio_postgres::Client, handle: &str) -> Result<(), Error> {
+
+    info!("first I'll clean up cash for {:?}", handle);
+    clean_cash(client, handle).await?;
+
+    let all_trades = trades::get_all_trades(client, handle).await?;
+    let actions = corporate_actions::get_corporate_actions(client).await?;
+    let ledger = ledger(&all_trades, &actions);
+    for e in &ledger {
+        insert_cash_entry(client, e).await?;
+    }
+
+    let days: BTreeSet<NaiveDate> = ledger.iter().flat_map(|e| [e.trade_date, e.settlement_date]).collect();
+    for d in days {
+        for b in balances(&ledger, d) {
+            info!("{:?}", b);
+            insert_cash_balance(client, &b).await?;
+        }
+    }
+
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::fx;
+
+    fn entry(trade_day: u32, settle_day: u32, activity: TxType, amount: f64) -> CashEntry {
+        CashEntry {
+            handle: "rivernorth".to_string(),
+            account_name: "RN1".to_string(),
+            currency: fx::DEFAULT_CURRENCY.to_string(),
+            activity,
+            trade_id: None,
+            trade_date: NaiveDate::from_ymd_opt(2019, 11, trade_day).unwrap(),
+            settlement_date: NaiveDate::from_ymd_opt(2019, 11, settle_day).unwrap(),
+            amount,
+            description: "".to_string()
+        }
+    }
+
+    #[test]
+    fn balances_split_settled_and_projected() {
+        let ledger = vec![
+            entry(1, 1, TxType::Subscription, 1000.),
+            entry(4, 6, TxType::Buy, -400.),
+            entry(5, 5, TxType::Dividend, 10.),
+        ];
+
+        let b = &balances(&ledger, NaiveDate::from_ymd_opt(2019, 11, 5).unwrap())[0];
+        assert_eq!((b.settled, b.projected), (1010., 610.));
+
+        let b = &balances(&ledger, NaiveDate::from_ymd_opt(2019, 11, 6).unwrap())[0];
+        assert_eq!((b.settled, b.projected), (610., 610.));
+    }
+}
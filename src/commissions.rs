@@ -0,0 +1,251 @@
+use crate::error::{self, Error};
+use crate::trades::Trade;
+use crate::utils;
+use serde::{Serialize,Deserialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::time::SystemTime;
+use tokio_postgres::Row;
+use tracing::info;
+
+/// the period on the rows that cover every month
+pub const ALL_PERIODS: &str = "ALL";
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Dimension {
+    Broker,
+    Trader,
+    Account
+}
+
+impl Dimension {
+    pub const ALL: [Dimension; 3] = [Dimension::Broker, Dimension::Trader, Dimension::Account];
+
+    fn name_of(&self, t: &Trade) -> String {
+        match self {
+            Dimension::Broker => t.broker.trim().to_uppercase(),
+            Dimension::Trader => t.trader.trim().to_uppercase(),
+            Dimension::Account => t.account_name.trim().to_uppercase()
+        }
+    }
+}
+
+impl fmt::Display for Dimension {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Dimension::Broker => write!(f, "BROKER"),
+            Dimension::Trader => write!(f, "TRADER"),
+            Dimension::Account => write!(f, "ACCOUNT"),
+        }
+    }
+}
+
+/// The share of a handle's commission a broker is meant to get, 0.25 for a quarter.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct CommissionTarget {
+    pub handle: String,
+    pub broker: String,
+    pub target_share: f64
+}
+
+impl From<Row> for CommissionTarget {
+    fn from(row: tokio_postgres::Row) -> Self {
+        Self {
+            handle: row.get("handle"),
+            broker: row.get("broker"),
+            target_share: row.get("target_share"),
+        }
+    }
+}
+
+/// Commission paid to a broker, by a trader or in an account over a month, or over everything when period is ALL.
+/// Amounts are in the fund's base currency. commission_share is of the handle's commission for the same period,
+/// target_share is only there for brokers with a target.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct CommissionSummary {
+    pub handle: String,
+    pub dimension: String,
+    pub name: String,
+    pub period: String,
+    pub currency: String,
+    pub trades: i32,
+    pub shares: f64,
+    pub notional: f64,
+    pub commission: f64,
+    pub fee: f64,
+    pub cents_per_share: f64,
+    pub bps_of_notional: f64,
+    pub commission_share: f64,
+    pub target_share: Option<f64>
+}
+
+pub async fn build_targets_table(client: &tokio_postgres::Client) -> Result<(), Error> {
+
+    client.query("CREATE TABLE commission_targets (id SERIAL PRIMARY KEY,
+        handle VARCHAR NOT NULL,
+        broker VARCHAR NOT NULL,
+        target_share FLOAT8 NOT NULL,
+        UNIQUE (handle, broker)
+        )", &[]).await?;
+
+    Ok(())
+}
+
+pub async fn drop_targets_table(client: &tokio_postgres::Client) -> Result<(), Error> {
+
+    client.query("drop TABLE commission_targets", &[]).await?;
+
+    Ok(())
+}
+
+/// Expects handle, broker and target_share columns, a reload replaces a broker's target.
+pub async fn load_targets(client: &tokio_postgres::Client) -> Result<(), Error> {
+    let ifile = "/tmp/commission_targets.csv";
+
+    for (row, r) in utils::read_csv_records(ifile, 3)? {
+        let target = CommissionTarget {
+            handle: r[0].trim().to_string(),
+            broker: r[1].trim().to_uppercase(),
+            target_share: error::parse_field(ifile, row, "target_share", &r[2])?
+        };
+        info!("{:?}", target);
+        client.execute("INSERT INTO commission_targets (handle, broker, target_share) VALUES ($1, $2, $3)
+            ON CONFLICT (handle, broker) DO UPDATE SET target_share = EXCLUDED.target_share",
+            &[&target.handle, &target.broker, &target.target_share]).await?;
+    }
+
+    Ok(())
+}
+
+pub async fn get_commission_targets(client: &tokio_postgres::Client, handle: &str) -> Result<Vec<CommissionTarget>, Error> {
+
+    let rows = client.query("SELECT * FROM commission_targets WHERE handle = $1", &[&handle]).await?;
+    Ok(rows.into_iter().map(CommissionTarget::from).collect())
+}
+
+pub(crate) async fn insert_commission_summary(client: &tokio_postgres::Client, summary: &CommissionSummary) -> Result<(), Error> {
+
+    let statement = client.prepare("INSERT INTO commission_summaries (
+        handle,
+        dimension,
+        name,
+        period,
+        currency,
+        trades,
+        shares,
+        notional,
+        commission,
+        fee,
+        cents_per_share,
+        bps_of_notional,
+        commission_share,
+        target_share,
+        inserted_at,
+        updated_at
+        ) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16)").await?;
+
+    client.execute(&statement,&[
+        &summary.handle,
+        &summary.dimension,
+        &summary.name,
+        &summary.period,
+        &summary.currency,
+        &summary.trades,
+        &summary.shares,
+        &summary.notional,
+        &summary.commission,
+        &summary.fee,
+        &summary.cents_per_share,
+        &summary.bps_of_notional,
+        &summary.commission_share,
+        &summary.target_share,
+        &SystemTime::now(),
+        &SystemTime::now()
+        ]).await?;
+    Ok(())
+}
+
+/// Buys and sells by dimension, name and month plus an ALL row for each name.
+/// base_rates takes a trade's id to the rate summarize used on its net amount, a missing one counts as 1.
+pub fn summaries(handle: &str, trades: &[Trade], base_rates: &HashMap<i32, f64>, targets: &[CommissionTarget], currency: &str) -> Vec<CommissionSummary> {
+
+    let executions: Vec<&Trade> = trades.iter().filter(|t| t.tx().is_trade()).collect();
+    let rate = |t: &Trade| *base_rates.get(&t.id.unwrap_or(0)).unwrap_or(&1.);
+    let month = |t: &Trade| utils::to_date(t.trade_date).format("%Y-%m").to_string();
+
+    let mut totals: HashMap<String, f64> = HashMap::new();
+    for t in &executions {
+        *totals.entry(month(t)).or_insert(0.) += t.commission * rate(t);
+        *totals.entry(ALL_PERIODS.to_string()).or_insert(0.) += t.commission * rate(t);
+    }
+
+    let mut v: Vec<CommissionSummary> = Vec::new();
+    for dimension in Dimension::ALL {
+        let mut g: BTreeMap<(String, String), Vec<&Trade>> = BTreeMap::new();
+        for t in &executions {
+            g.entry((dimension.name_of(t), month(t))).or_default().push(t);
+            g.entry((dimension.name_of(t), ALL_PERIODS.to_string())).or_default().push(t);
+        }
+
+        for ((name, period), ts) in g {
+            let shares: f64 = ts.iter().map(|t| t.quantity.abs()).sum();
+            let notional: f64 = ts.iter().map(|t| (t.quantity * t.price).abs() * rate(t)).sum();
+            let commission: f64 = ts.iter().map(|t| t.commission * rate(t)).sum();
+            let total = totals[&period];
+            v.push(CommissionSummary {
+                handle: handle.to_string(),
+                dimension: dimension.to_string(),
+                target_share: match dimension {
+                    Dimension::Broker => targets.iter().find(|x| x.handle == handle && x.broker.eq_ignore_ascii_case(&name)).map(|x| x.target_share),
+                    _ => None
+                },
+                name,
+                period,
+                currency: currency.to_string(),
+                trades: ts.len() as i32,
+                shares,
+                notional,
+                commission,
+                fee: ts.iter().map(|t| t.fee * rate(t)).sum(),
+                cents_per_share: if shares != 0. { commission / shares * 100. } else { 0. },
+                bps_of_notional: if notional != 0. { commission / notional * 10000. } else { 0. },
+                commission_share: if total != 0. { commission / total } else { 0. }
+            });
+        }
+    }
+
+    v
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn trade(id: i32, month: u32, broker: &str, quantity: f64, commission: f64) -> Trade {
+        Trade { commission, broker: broker.to_string(), trader: "JS".to_string(), ..Trade::test(id, "BUY", 4, quantity, 10.).at(month, 4, 16) }
+    }
+
+    #[test]
+    fn cents_per_share_bps_and_share_against_target() {
+        let trades = vec![trade(1, 10, "gs", 1000., 30.), trade(2, 11, "GS", 1000., 10.), trade(3, 11, "MS", 500., 10.)];
+        let targets = vec![CommissionTarget { handle: "rivernorth".to_string(), broker: "GS".to_string(), target_share: 0.5 }];
+        let v = summaries("rivernorth", &trades, &HashMap::new(), &targets, "USD");
+
+        let find = |dimension: &str, name: &str, period: &str| v.iter().find(|s| s.dimension == dimension && s.name == name && s.period == period).unwrap();
+        let gs = find("BROKER", "GS", ALL_PERIODS);
+        assert_eq!(gs.trades, 2);
+        assert!((gs.cents_per_share - 2.).abs() < 1e-9);
+        assert!((gs.bps_of_notional - 20.).abs() < 1e-9);
+        assert!((gs.commission_share - 0.8).abs() < 1e-9);
+        assert_eq!(gs.target_share, Some(0.5));
+
+        let ms = find("BROKER", "MS", "2019-11");
+        assert!((ms.commission_share - 0.5).abs() < 1e-9);
+        assert_eq!(ms.target_share, None);
+
+        assert_eq!(find("TRADER", "JS", ALL_PERIODS).trades, 3);
+        assert_eq!(v.iter().filter(|s| s.dimension == "ACCOUNT").count(), 3);
+    }
+}
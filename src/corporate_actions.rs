@@ -0,0 +1,334 @@
+use crate::error::{self, Error};
+use crate::positions;
+use crate::trades::Trade;
+use crate::tx_types::TxType;
+use crate::utils;
+use chrono::NaiveDate;
+use serde::{Serialize,Deserialize};
+use std::fmt;
+use std::str::FromStr;
+use tokio_postgres::Row;
+use tracing::info;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ActionType {
+    Split,
+    ReverseSplit,
+    SymbolChange,
+    SpinOff,
+    CashMerger,
+    StockMerger
+}
+
+impl fmt::Display for ActionType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ActionType::Split => write!(f, "SPLIT"),
+            ActionType::ReverseSplit => write!(f, "REVERSE_SPLIT"),
+            ActionType::SymbolChange => write!(f, "SYMBOL_CHANGE"),
+            ActionType::SpinOff => write!(f, "SPIN_OFF"),
+            ActionType::CashMerger => write!(f, "CASH_MERGER"),
+            ActionType::StockMerger => write!(f, "STOCK_MERGER"),
+        }
+    }
+}
+
+impl FromStr for ActionType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_uppercase().replace([' ', '-'], "_").as_ref() {
+            "SPLIT" => Ok(ActionType::Split),
+            "REVERSE_SPLIT" => Ok(ActionType::ReverseSplit),
+            "SYMBOL_CHANGE" | "TICKER_CHANGE" | "CUSIP_CHANGE" => Ok(ActionType::SymbolChange),
+            "SPIN_OFF" | "SPINOFF" => Ok(ActionType::SpinOff),
+            "CASH_MERGER" => Ok(ActionType::CashMerger),
+            "STOCK_MERGER" => Ok(ActionType::StockMerger),
+            other => Err(format!("unknown corporate action {}", other))
+        }
+    }
+}
+
+/// ratio is new shares per old share, so a 2 for 1 split is 2 and a 1 for 10 reverse split is 0.1.
+/// cash_amount is cash per old share for a cash merger and the price the new shares come in at for a spin-off.
+/// For spin-offs new_ticker is the spun off company, the parent keeps its shares.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct CorporateAction {
+    pub id: Option<i32>,
+    pub action_type: ActionType,
+    pub security_ticker: String,
+    pub cusip: String,
+    pub new_ticker: String,
+    pub new_cusip: String,
+    pub ratio: f64,
+    pub cash_amount: f64,
+    pub effective_date: NaiveDate
+}
+
+impl From<Row> for CorporateAction {
+    fn from(row: tokio_postgres::Row) -> Self {
+        let action_type: String = row.get("action_type");
+        Self {
+            id: Some(row.get("id")),
+            action_type: action_type.parse().unwrap_or(ActionType::SymbolChange),
+            security_ticker: row.get("security_ticker"),
+            cusip: row.get("cusip"),
+            new_ticker: row.get("new_ticker"),
+            new_cusip: row.get("new_cusip"),
+            ratio: row.get("ratio"),
+            cash_amount: row.get("cash_amount"),
+            effective_date: row.get("effective_date"),
+        }
+    }
+}
+
+impl CorporateAction {
+    fn touches(&self, t: &Trade) -> bool {
+        t.security_ticker.eq_ignore_ascii_case(&self.security_ticker) ||
+        (!self.cusip.is_empty() && t.cusip.eq_ignore_ascii_case(&self.cusip))
+    }
+
+    fn rekey(&self, t: &mut Trade) {
+        if !self.new_ticker.is_empty() {
+            t.security_ticker = self.new_ticker.clone();
+        }
+        if !self.new_cusip.is_empty() {
+            t.cusip = self.new_cusip.clone();
+        }
+    }
+
+    fn rescale(&self, t: &mut Trade) {
+        if self.ratio > 0. {
+            t.quantity *= self.ratio;
+            t.price /= self.ratio;
+        }
+    }
+
+    /// A trade made out of a cash merger or spin-off, so positions pick it up like any other.
+    /// Only a cash merger moves cash, spun off shares are handed out for nothing.
+    fn synthetic_trade(&self, p: &positions::Position, security_ticker: &str, cusip: &str, tx_type: TxType, quantity: f64, price: f64) -> Trade {
+        let ts = self.effective_date.and_hms_opt(16, 0, 0).unwrap().timestamp();
+        let cash = if self.action_type == ActionType::CashMerger { -quantity * price } else { 0. };
+        Trade {
+            id: None,
+            handle: p.handle.clone(),
+            filename: "corporate_actions".to_string(),
+            filehash: "".to_string(),
+            row: self.id.unwrap_or(0),
+            account_name: p.account_name.clone(),
+            account_number: "".to_string(),
+            account_type: "".to_string(),
+            account_id: None,
+            security_description: self.action_type.to_string(),
+            security_ticker: security_ticker.to_string(),
+            asset_class: "".to_string(),
+            security_type: "".to_string(),
+            tx_type: tx_type.to_string(),
+            source_tx_type: self.action_type.to_string(),
+            cusip: cusip.to_string(),
+            price,
+            quantity,
+            commission: 0.,
+            fee: 0.,
+            principal: cash,
+            net_amount: cash,
+            currency: p.currency.clone(),
+            trade_date: ts,
+            settlement_date: ts,
+            broker: "".to_string(),
+            trader: "".to_string()
+        }
+    }
+}
+
+pub async fn build_corporate_actions_table(client: &tokio_postgres::Client) -> Result<(), Error> {
+
+    client.query("CREATE TABLE corporate_actions (id SERIAL PRIMARY KEY,
+        action_type VARCHAR NOT NULL,
+        security_ticker VARCHAR NOT NULL,
+        cusip VARCHAR NOT NULL,
+        new_ticker VARCHAR NOT NULL,
+        new_cusip VARCHAR NOT NULL,
+        ratio FLOAT8 NOT NULL,
+        cash_amount FLOAT8 NOT NULL,
+        effective_date DATE NOT NULL
+        )", &[]).await?;
+
+    Ok(())
+}
+
+pub async fn drop_corporate_actions_table(client: &tokio_postgres::Client) -> Result<(), Error> {
+
+    client.query("drop TABLE corporate_actions", &[]).await?;
+
+    Ok(())
+}
+
+pub async fn insert_corporate_action(client: &tokio_postgres::Client, a: &CorporateAction) -> Result<(), Error> {
+
+    let statement = client.prepare("INSERT INTO corporate_actions (
+        action_type,
+        security_ticker,
+        cusip,
+        new_ticker,
+        new_cusip,
+        ratio,
+        cash_amount,
+        effective_date
+        ) VALUES ($1, $2, $3, $4, $5, $6, $7, $8)").await?;
+
+    client.execute(&statement,&[
+        &a.action_type.to_string(),
+        &a.security_ticker,
+        &a.cusip,
+        &a.new_ticker,
+        &a.new_cusip,
+        &a.ratio,
+        &a.cash_amount,
+        &a.effective_date
+        ]).await?;
+    Ok(())
+}
+
+pub async fn get_corporate_actions(client: &tokio_postgres::Client) -> Result<Vec<CorporateAction>, Error> {
+
+    let rows = client.query("SELECT * FROM corporate_actions ORDER BY effective_date, id", &[]).await?;
+    Ok(rows.into_iter().map(CorporateAction::from).collect())
+}
+
+/// Expects action_type, security_ticker, cusip, new_ticker, new_cusip, ratio, cash_amount, effective_date (yyyy-mm-dd) columns.
+/// A blank ratio is 1 and a blank cash_amount 0.
+pub async fn load(client: &tokio_postgres::Client) -> Result<(), Error> {
+    let ifile = "/tmp/corporate_actions.csv";
+
+    for (row, r) in utils::read_csv_records(ifile, 8)? {
+        let a = CorporateAction {
+            id: None,
+            action_type: error::parse_field(ifile, row, "action_type", &r[0])?,
+            security_ticker: r[1].trim().to_uppercase(),
+            cusip: r[2].trim().to_uppercase(),
+            new_ticker: r[3].trim().to_uppercase(),
+            new_cusip: r[4].trim().to_uppercase(),
+            ratio: error::parse_field_or(ifile, row, "ratio", &r[5], 1.)?,
+            cash_amount: error::parse_field_or(ifile, row, "cash_amount", &r[6], 0.)?,
+            effective_date: error::parse_field(ifile, row, "effective_date", &r[7])?
+        };
+        info!("{:?}", a);
+        insert_corporate_action(client, &a).await?;
+    }
+
+    Ok(())
+}
+
+/// Restates trades from before each action in today's terms, so history lines up with the security as it trades now.
+/// Quantities and prices get scaled through splits and stock mergers, identifiers follow symbol changes and mergers.
+/// Actions are applied in effective date order so a chain of renames ends up on the latest ticker.
+pub fn apply(trades: &[Trade], actions: &[CorporateAction]) -> Vec<Trade> {
+
+    let mut adjusted: Vec<Trade> = trades.to_vec();
+    let mut ordered: Vec<&CorporateAction> = actions.iter().collect();
+    ordered.sort_by_key(|a| a.effective_date);
+
+    for a in ordered {
+        for t in adjusted.iter_mut().filter(|t| utils::to_date(t.trade_date) < a.effective_date) {
+            if !a.touches(t) {
+                continue
+            }
+            match a.action_type {
+                ActionType::Split | ActionType::ReverseSplit => a.rescale(t),
+                ActionType::SymbolChange => a.rekey(t),
+                ActionType::StockMerger => {
+                    a.rescale(t);
+                    a.rekey(t);
+                },
+                ActionType::CashMerger | ActionType::SpinOff => {}
+            }
+        }
+    }
+
+    adjusted
+}
+
+/// Cash mergers and spin-offs don't restate anything, they close out or hand out a position on the effective date.
+/// A cash merger sells a long out and covers a short, either way at cash_amount a share. Takes trades that have already been through apply.
+pub fn position_events(trades: &[Trade], actions: &[CorporateAction]) -> Vec<Trade> {
+
+    let mut events: Vec<Trade> = Vec::new();
+
+    for a in actions.iter().filter(|a| matches!(a.action_type, ActionType::CashMerger | ActionType::SpinOff)) {
+        let eve = a.effective_date.pred_opt().unwrap_or(a.effective_date).and_hms_opt(23, 59, 59).unwrap().timestamp();
+        let held = positions::derive_positions(trades, eve);
+        for p in held.iter().filter(|p| p.security_ticker.eq_ignore_ascii_case(&a.security_ticker) && p.quantity != 0.) {
+            match a.action_type {
+                ActionType::CashMerger => {
+                    let tx_type = if p.quantity > 0. { TxType::Sell } else { TxType::BuyToCover };
+                    events.push(a.synthetic_trade(p, &p.security_ticker, &p.cusip, tx_type, -p.quantity, a.cash_amount))
+                },
+                ActionType::SpinOff => events.push(a.synthetic_trade(p, &a.new_ticker, &a.new_cusip, TxType::Buy, p.quantity * a.ratio, a.cash_amount)),
+                _ => {}
+            }
+        }
+    }
+
+    events
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn trade(ticker: &str, day: u32, quantity: f64, price: f64) -> Trade {
+        Trade { security_ticker: ticker.to_string(), ..Trade::test(day as i32, "BUY", day, quantity, price) }
+    }
+
+    fn action(action_type: ActionType, ticker: &str, new_ticker: &str, ratio: f64, cash_amount: f64, day: u32) -> CorporateAction {
+        CorporateAction {
+            id: None,
+            action_type,
+            security_ticker: ticker.to_string(),
+            cusip: "".to_string(),
+            new_ticker: new_ticker.to_string(),
+            new_cusip: "".to_string(),
+            ratio,
+            cash_amount,
+            effective_date: NaiveDate::from_ymd_opt(2019, 11, day).unwrap()
+        }
+    }
+
+    #[test]
+    fn apply_splits_and_renames() {
+        let trades = vec![trade("ABC", 1, 100., 10.), trade("ABC", 10, 50., 5.)];
+        let actions = vec![action(ActionType::SymbolChange, "ABC", "XYZ", 1., 0., 15), action(ActionType::Split, "ABC", "", 2., 0., 5)];
+
+        let adjusted = apply(&trades, &actions);
+        assert_eq!((adjusted[0].security_ticker.as_str(), adjusted[0].quantity, adjusted[0].price), ("XYZ", 200., 5.));
+        assert_eq!((adjusted[1].security_ticker.as_str(), adjusted[1].quantity, adjusted[1].price), ("XYZ", 50., 5.));
+    }
+
+    #[test]
+    fn cash_merger_closes_the_position() {
+        let trades = vec![trade("ABC", 1, 100., 10.)];
+        let actions = vec![action(ActionType::CashMerger, "ABC", "", 1., 12., 5), action(ActionType::SpinOff, "ABC", "SPN", 0.5, 3., 3)];
+
+        let events = position_events(&trades, &actions);
+        assert_eq!(events.len(), 2);
+        assert_eq!((events[0].security_ticker.as_str(), events[0].quantity, events[0].price), ("ABC", -100., 12.));
+        assert_eq!((events[0].tx(), events[0].net_amount), (TxType::Sell, 1200.));
+        assert_eq!((events[1].security_ticker.as_str(), events[1].quantity, events[1].net_amount), ("SPN", 50., 0.));
+    }
+
+    #[test]
+    fn cash_merger_covers_a_short() {
+        let trades = vec![Trade { security_ticker: "ABC".to_string(), ..Trade::test(1, "SELL_SHORT", 1, -100., 10.) }];
+        let actions = vec![action(ActionType::CashMerger, "ABC", "", 1., 12., 5)];
+
+        let events = position_events(&trades, &actions);
+        assert_eq!((events[0].tx(), events[0].quantity, events[0].net_amount), (TxType::BuyToCover, 100., -1200.));
+
+        let after = NaiveDate::from_ymd_opt(2019, 11, 6).unwrap().and_hms_opt(0, 0, 0).unwrap().timestamp();
+        let held = positions::reconstruct(&trades, &actions, after);
+        assert!(held.iter().all(|p| p.quantity == 0.));
+    }
+}
@@ -0,0 +1,105 @@
+use std::fmt::Display;
+use std::str::FromStr;
+use thiserror::Error;
+
+/// Everything the crate can fail with, carrying enough to find the file, row and column that did it.
+#[derive(Debug, Error)]
+pub enum Error {
+    /// Couldn't open or read a file.
+    #[error("{file}: {source}")]
+    Io { file: String, #[source] source: std::io::Error },
+
+    /// A value in a file that isn't what the column says it should be. Rows count from 1 with the header as row 1.
+    #[error("{file} row {row} column {column}: {message}")]
+    Parse { file: String, row: usize, column: String, message: String },
+
+    /// A file laid out differently than we expect, missing or unknown headers, ragged rows, no sheet.
+    #[error("{file}: {message}")]
+    Schema { file: String, message: String },
+
+    #[error("database: {0}")]
+    Db(#[from] tokio_postgres::Error),
+
+    /// No connection to be had from the pool, all out past the wait timeout or the database wouldn't take another.
+    #[error("connection pool: {0}")]
+    Pool(#[from] deadpool_postgres::PoolError),
+
+    #[error("sqlite: {0}")]
+    Sqlite(#[from] rusqlite::Error),
+
+    /// Data that reads fine but can't be let in, unmapped tx types and the like.
+    #[error("{0}")]
+    Validation(String),
+}
+
+impl Error {
+    /// What the CLI exits with, so whatever runs it can tell a bad file from a down database.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Error::Io { .. } => 2,
+            Error::Parse { .. } => 3,
+            Error::Schema { .. } => 4,
+            Error::Db(_) | Error::Pool(_) | Error::Sqlite(_) => 5,
+            Error::Validation(_) => 6,
+        }
+    }
+
+    pub fn io(file: &str, source: std::io::Error) -> Self {
+        Error::Io { file: file.to_string(), source }
+    }
+
+    pub fn schema(file: &str, message: impl Display) -> Self {
+        Error::Schema { file: file.to_string(), message: message.to_string() }
+    }
+
+    /// csv hands back io trouble and bad records through the same error, this splits them back out.
+    pub fn csv(file: &str, e: csv::Error) -> Self {
+        let row = e.position().map(|p| p.line() as usize).unwrap_or(0);
+        match e.into_kind() {
+            csv::ErrorKind::Io(source) => Error::io(file, source),
+            other => Error::Parse { file: file.to_string(), row, column: "".to_string(), message: format!("{:?}", other) }
+        }
+    }
+
+    /// calamine wraps io errors too, anything else means it isn't a workbook we can read.
+    pub fn xlsx(file: &str, e: calamine::XlsxError) -> Self {
+        match e {
+            calamine::XlsxError::Io(source) => Error::io(file, source),
+            other => Error::schema(file, other)
+        }
+    }
+}
+
+/// Parses one field of a file or says exactly where it went wrong.
+pub fn parse_field<T>(file: &str, row: usize, column: &str, value: &str) -> Result<T, Error> where T: FromStr, T::Err: Display {
+    value.trim().parse().map_err(|e: T::Err| Error::Parse {
+        file: file.to_string(),
+        row,
+        column: column.to_string(),
+        message: format!("{:?} {}", value, e)
+    })
+}
+
+/// The same but a blank field is the default, anything else has to parse.
+pub fn parse_field_or<T>(file: &str, row: usize, column: &str, value: &str, default: T) -> Result<T, Error> where T: FromStr, T::Err: Display {
+    if value.trim().is_empty() {
+        return Ok(default)
+    }
+    parse_field(file, row, column, value)
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn parse_errors_say_where() {
+        let e = parse_field::<f64>("/tmp/fx_rates.csv", 4, "rate", "1.1x").unwrap_err();
+        assert_eq!(e.exit_code(), 3);
+        assert!(e.to_string().starts_with("/tmp/fx_rates.csv row 4 column rate:"));
+        assert_eq!(parse_field_or("/tmp/corporate_actions.csv", 2, "ratio", " ", 1.).unwrap(), 1.);
+        assert!(parse_field_or("/tmp/corporate_actions.csv", 2, "ratio", "2:1", 1.).is_err());
+    }
+}
@@ -0,0 +1,457 @@
+use crate::error::Error;
+use crate::fx::{self, FxRate};
+use crate::prices::{self, Price, PriceType};
+use crate::trades::{self, Trade};
+use crate::tx_types::Direction;
+use crate::utils;
+use chrono::NaiveDate;
+use serde::{Serialize,Deserialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+use tokio_postgres::{Row, Transaction};
+use tracing::{info, warn};
+
+/// how many standard deviations off the average slippage of the rest of its security an execution has to be to get flagged
+pub const OUTLIER_Z: f64 = 2.0;
+/// fewer executions than this in a security and nothing in it gets a z-score, each one needs two others to score against
+pub const MIN_SAMPLES: usize = 3;
+
+/// One execution against the day's benchmark. Slippage and cost are signed so positive is always worse for us,
+/// paying up on a buy or selling under. effective_cost is the slippage in money plus commission and fee, taken into
+/// the fund's base currency at fx_rate. price and benchmark stay in the trade's own currency.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Execution {
+    pub trade_id: Option<i32>,
+    pub handle: String,
+    pub account_name: String,
+    pub security_ticker: String,
+    pub broker: String,
+    pub trader: String,
+    pub direction: String,
+    pub trade_date: NaiveDate,
+    pub quantity: f64,
+    pub price: f64,
+    pub benchmark: f64,
+    /// VWAP, or the price type of the same day mark when there's no VWAP
+    pub benchmark_type: String,
+    pub slippage_bps: f64,
+    pub currency: String,
+    pub fx_rate: f64,
+    pub effective_cost: f64,
+    pub z_score: Option<f64>,
+    pub outlier: bool
+}
+
+impl Execution {
+    /// in the base currency like effective_cost
+    pub fn notional(&self) -> f64 {
+        self.quantity.abs() * self.benchmark * self.fx_rate
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum GroupBy {
+    Broker,
+    Trader
+}
+
+impl fmt::Display for GroupBy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            GroupBy::Broker => write!(f, "BROKER"),
+            GroupBy::Trader => write!(f, "TRADER"),
+        }
+    }
+}
+
+impl FromStr for GroupBy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_uppercase().as_ref() {
+            "BROKER" => Ok(GroupBy::Broker),
+            "TRADER" => Ok(GroupBy::Trader),
+            other => Err(format!("{} is not something executions are grouped by", other))
+        }
+    }
+}
+
+/// Executions for one broker or trader, slippage weighted by notional and cost in bps of notional.
+/// Money is in the fund's base currency.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ExecutionSummary {
+    pub handle: String,
+    pub group_by: GroupBy,
+    pub name: String,
+    pub executions: i32,
+    pub currency: String,
+    pub notional: f64,
+    pub slippage_bps: f64,
+    pub effective_cost: f64,
+    pub effective_cost_bps: f64,
+    pub outliers: i32
+}
+
+impl TryFrom<Row> for ExecutionSummary {
+    type Error = Error;
+
+    fn try_from(row: tokio_postgres::Row) -> Result<Self, Self::Error> {
+        let group_by: String = row.get("group_by");
+        Ok(Self {
+            handle: row.get("handle"),
+            group_by: group_by.parse().map_err(|e| Error::Validation(format!("in execution_summaries: {}", e)))?,
+            name: row.get("name"),
+            executions: row.get("executions"),
+            currency: row.get("currency"),
+            notional: row.get("notional"),
+            slippage_bps: row.get("slippage_bps"),
+            effective_cost: row.get("effective_cost"),
+            effective_cost_bps: row.get("effective_cost_bps"),
+            outliers: row.get("outliers"),
+        })
+    }
+}
+
+pub async fn build_execution_tables(client: &tokio_postgres::Client) -> Result<(), Error> {
+
+    client.query("CREATE TABLE execution_analysis (id SERIAL PRIMARY KEY,
+        trade_id INT,
+        handle VARCHAR NOT NULL,
+        account_name VARCHAR NOT NULL,
+        security_ticker VARCHAR NOT NULL,
+        broker VARCHAR NOT NULL,
+        trader VARCHAR NOT NULL,
+        direction VARCHAR NOT NULL,
+        trade_date DATE NOT NULL,
+        quantity FLOAT8 NOT NULL,
+        price FLOAT8 NOT NULL,
+        benchmark FLOAT8 NOT NULL,
+        benchmark_type VARCHAR NOT NULL,
+        slippage_bps FLOAT8 NOT NULL,
+        currency VARCHAR NOT NULL,
+        fx_rate FLOAT8 NOT NULL,
+        effective_cost FLOAT8 NOT NULL,
+        z_score FLOAT8,
+        outlier BOOLEAN NOT NULL
+        )", &[]).await?;
+
+    client.query("CREATE TABLE execution_summaries (id SERIAL PRIMARY KEY,
+        handle VARCHAR NOT NULL,
+        group_by VARCHAR NOT NULL,
+        name VARCHAR NOT NULL,
+        executions INT NOT NULL,
+        currency VARCHAR NOT NULL,
+        notional FLOAT8 NOT NULL,
+        slippage_bps FLOAT8 NOT NULL,
+        effective_cost FLOAT8 NOT NULL,
+        effective_cost_bps FLOAT8 NOT NULL,
+        outliers INT NOT NULL
+        )", &[]).await?;
+
+    Ok(())
+}
+
+pub async fn drop_execution_tables(client: &tokio_postgres::Client) -> Result<(), Error> {
+
+    client.query("drop TABLE execution_analysis", &[]).await?;
+    client.query("drop TABLE execution_summaries", &[]).await?;
+
+    Ok(())
+}
+
+async fn clean_execution(client: &Transaction<'_>, handle: &str) -> Result<(), Error> {
+
+    let statement = client.prepare("delete from execution_analysis where handle = $1").await?;
+    client.execute(&statement,&[&handle]).await?;
+    let statement = client.prepare("delete from execution_summaries where handle = $1").await?;
+    client.execute(&statement,&[&handle]).await?;
+
+    Ok(())
+}
+
+async fn insert_execution(client: &Transaction<'_>, e: &Execution) -> Result<(), Error> {
+
+    let statement = client.prepare("INSERT INTO execution_analysis (
+        trade_id,
+        handle,
+        account_name,
+        security_ticker,
+        broker,
+        trader,
+        direction,
+        trade_date,
+        quantity,
+        price,
+        benchmark,
+        benchmark_type,
+        slippage_bps,
+        currency,
+        fx_rate,
+        effective_cost,
+        z_score,
+        outlier
+        ) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16, $17, $18)").await?;
+
+    client.execute(&statement,&[
+        &e.trade_id,
+        &e.handle,
+        &e.account_name,
+        &e.security_ticker,
+        &e.broker,
+        &e.trader,
+        &e.direction,
+        &e.trade_date,
+        &e.quantity,
+        &e.price,
+        &e.benchmark,
+        &e.benchmark_type,
+        &e.slippage_bps,
+        &e.currency,
+        &e.fx_rate,
+        &e.effective_cost,
+        &e.z_score,
+        &e.outlier
+        ]).await?;
+    Ok(())
+}
+
+async fn insert_execution_summary(client: &Transaction<'_>, s: &ExecutionSummary) -> Result<(), Error> {
+
+    let statement = client.prepare("INSERT INTO execution_summaries (
+        handle,
+        group_by,
+        name,
+        executions,
+        currency,
+        notional,
+        slippage_bps,
+        effective_cost,
+        effective_cost_bps,
+        outliers
+        ) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)").await?;
+
+    client.execute(&statement,&[
+        &s.handle,
+        &s.group_by.to_string(),
+        &s.name,
+        &s.executions,
+        &s.currency,
+        &s.notional,
+        &s.slippage_bps,
+        &s.effective_cost,
+        &s.effective_cost_bps,
+        &s.outliers
+        ]).await?;
+    Ok(())
+}
+
+pub async fn get_execution_summaries(client: &tokio_postgres::Client, handle: &str) -> Result<Vec<ExecutionSummary>, Error> {
+
+    let rows = client.query("SELECT * FROM execution_summaries WHERE handle = $1 ORDER BY group_by, name", &[&handle]).await?;
+    rows.into_iter().map(ExecutionSummary::try_from).collect()
+}
+
+/// The day's VWAP from a price file if there is one, otherwise a mark, as long as it's from the same day.
+/// A stale mark says nothing about how well a trade was done.
+pub fn benchmark(prices: &[Price], security_ticker: &str, trade_date: NaiveDate) -> Option<(f64, String)> {
+
+    let mut vwaps: Vec<&Price> = prices.iter()
+        .filter(|p| p.price_type == PriceType::Vwap && p.price_date == trade_date && p.security_id.eq_ignore_ascii_case(security_ticker))
+        .collect();
+    vwaps.sort_by(|a, b| a.source.cmp(&b.source));
+    if let Some(p) = vwaps.first() {
+        return Some((p.price, PriceType::Vwap.to_string()));
+    }
+
+    prices::best_mark(prices, security_ticker, trade_date, &[])
+        .filter(|m| m.stale_days == 0)
+        .map(|m| (m.price, m.price_type))
+}
+
+/// Every buy and sell that has a benchmark for its day, z-scored against the others in its security.
+/// Money goes into base_currency at the trade date rate.
+pub fn analyze(trades: &[Trade], prices: &[Price], rates: &[FxRate], base_currency: &str) -> Vec<Execution> {
+
+    let mut v: Vec<Execution> = Vec::new();
+    for t in trades.iter().filter(|t| t.tx().is_trade() && t.quantity != 0.) {
+        let trade_date = utils::to_date(t.trade_date);
+        let Some((bench, benchmark_type)) = benchmark(prices, &t.security_ticker, trade_date).filter(|b| b.0 != 0.) else {
+            continue
+        };
+        let direction = t.direction();
+        let side = if direction == Direction::Buy { 1. } else { -1. };
+        let fx_rate = match fx::convert(rates, 1., &t.currency, base_currency, trade_date) {
+            Some(c) => c.rate,
+            None => {
+                warn!("no {}/{} rate for trade {:?}, leaving it unconverted", t.currency, base_currency, t.id);
+                1.
+            }
+        };
+        v.push(Execution {
+            trade_id: t.id,
+            handle: t.handle.clone(),
+            account_name: t.account_name.to_uppercase(),
+            security_ticker: t.security_ticker.to_uppercase(),
+            broker: t.broker.clone(),
+            trader: t.trader.clone(),
+            direction: direction.to_string(),
+            trade_date,
+            quantity: t.quantity,
+            price: t.price,
+            benchmark: bench,
+            benchmark_type,
+            slippage_bps: side * (t.price - bench) / bench * 10000.,
+            currency: base_currency.to_string(),
+            fx_rate,
+            effective_cost: (side * (t.price - bench) * t.quantity.abs() + t.commission + t.fee) * fx_rate,
+            z_score: None,
+            outlier: false
+        });
+    }
+    info!("{} of {} trades had a same day benchmark", v.len(), trades.iter().filter(|t| t.tx().is_trade()).count());
+
+    let mut by_security: BTreeMap<String, Vec<usize>> = BTreeMap::new();
+    for (i, e) in v.iter().enumerate() {
+        by_security.entry(e.security_ticker.clone()).or_default().push(i);
+    }
+    // each one against the rest, since with itself in the mix a handful of fills could never get past OUTLIER_Z
+    for idx in by_security.values().filter(|x| x.len() >= MIN_SAMPLES) {
+        for i in idx {
+            let rest: Vec<f64> = idx.iter().filter(|j| *j != i).map(|j| v[*j].slippage_bps).collect();
+            let n = rest.len() as f64;
+            let mean = rest.iter().sum::<f64>() / n;
+            let sd = (rest.iter().map(|x| (x - mean).powi(2)).sum::<f64>() / (n - 1.)).sqrt();
+            if sd == 0. {
+                continue
+            }
+            let z = (v[*i].slippage_bps - mean) / sd;
+            v[*i].z_score = Some(z);
+            v[*i].outlier = z.abs() > OUTLIER_Z;
+        }
+    }
+
+    v
+}
+
+pub fn summarize_by(executions: &[Execution], group_by: GroupBy) -> Vec<ExecutionSummary> {
+
+    let mut g: BTreeMap<(String, String), ExecutionSummary> = BTreeMap::new();
+    for e in executions {
+        let name = match group_by {
+            GroupBy::Broker => e.broker.trim().to_uppercase(),
+            GroupBy::Trader => e.trader.trim().to_uppercase()
+        };
+        let s = g.entry((e.handle.clone(), name.clone())).or_insert(ExecutionSummary {
+            handle: e.handle.clone(),
+            group_by,
+            name,
+            executions: 0,
+            currency: e.currency.clone(),
+            notional: 0.,
+            slippage_bps: 0.,
+            effective_cost: 0.,
+            effective_cost_bps: 0.,
+            outliers: 0
+        });
+        s.executions += 1;
+        s.notional += e.notional();
+        // weighted sum for now, divided through once the notional's all in
+        s.slippage_bps += e.slippage_bps * e.notional();
+        s.effective_cost += e.effective_cost;
+        s.outliers += e.outlier as i32;
+    }
+
+    g.into_values().map(|mut s| {
+        if s.notional != 0. {
+            s.slippage_bps /= s.notional;
+            s.effective_cost_bps = s.effective_cost / s.notional * 10000.;
+        }
+        s
+    }).collect()
+}
+
+/// Reruns the analysis over the handle's trades against the prices table and replaces what was there in one transaction.
+pub async fn best_execution(client: &mut tokio_postgres::Client, handle: &str) -> Result<(), Error> {
+
+    let all_trades = trades::get_all_trades(client, handle).await?;
+    let (Some(from), Some(to)) = (all_trades.iter().map(|t| t.trade_date).min(), all_trades.iter().map(|t| t.trade_date).max()) else {
+        info!("no trades for {:?}", handle);
+        return Ok(())
+    };
+    let prices = prices::get_all_prices(client, utils::to_date(from), utils::to_date(to)).await?;
+    let base_currency = fx::get_base_currency(client, handle).await?;
+    let rates = fx::get_fx_rates(client).await?;
+
+    let tx = client.transaction().await?;
+    info!("first I'll clean up the execution analysis for {:?}", handle);
+    clean_execution(&tx, handle).await?;
+
+    let executions = analyze(&all_trades, &prices, &rates, &base_currency);
+    for e in &executions {
+        if e.outlier {
+            info!("outlier {:?}", e);
+        }
+        insert_execution(&tx, e).await?;
+    }
+    for group_by in [GroupBy::Broker, GroupBy::Trader] {
+        for s in summarize_by(&executions, group_by) {
+            info!("{:?}", s);
+            insert_execution_summary(&tx, &s).await?;
+        }
+    }
+    tx.commit().await?;
+
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn trade(id: i32, tx_type: &str, broker: &str, quantity: f64, price: f64) -> Trade {
+        Trade { commission: 1., broker: broker.to_string(), trader: "JS".to_string(), ..Trade::test(id, tx_type, 4, quantity, price) }
+    }
+
+    #[test]
+    fn slippage_against_vwap_and_outliers() {
+        let d = NaiveDate::from_ymd_opt(2019, 11, 4).unwrap();
+        let prices = vec![
+            Price { security_id: "OPP".to_string(), price_date: d, source: "admin".to_string(), price_type: PriceType::Close, price: 9. },
+            Price { security_id: "OPP".to_string(), price_date: d, source: "tape".to_string(), price_type: PriceType::Vwap, price: 10. },
+        ];
+        let mut trades = vec![trade(1, "BUY", "GS", 100., 10.01), trade(2, "BUY", "GS", 100., 10.02), trade(3, "BUY", "GS", 100., 10.)];
+        trades.push(trade(4, "SELL", "MS", -100., 9.5));
+        let rates = vec![FxRate {
+            base_currency: "EUR".to_string(),
+            quote_currency: "USD".to_string(),
+            rate_date: d,
+            rate: 1.25,
+            source: "wm".to_string()
+        }];
+
+        let executions = analyze(&trades, &prices, &rates, "EUR");
+        assert_eq!(executions.len(), 4);
+        assert_eq!(executions[0].benchmark_type, "VWAP");
+        assert!((executions[0].slippage_bps - 10.).abs() < 1e-6);
+        assert!((executions[3].slippage_bps - 500.).abs() < 1e-6);
+        assert!((executions[3].effective_cost - 40.8).abs() < 1e-6);
+        assert_eq!(executions.iter().filter(|e| e.outlier).map(|e| e.trade_id.unwrap()).collect::<Vec<_>>(), vec![4]);
+
+        let brokers = summarize_by(&executions, GroupBy::Broker);
+        assert_eq!(brokers.iter().map(|s| (s.name.as_str(), s.executions, s.outliers)).collect::<Vec<_>>(), vec![("GS", 3, 0), ("MS", 1, 1)]);
+        assert!((brokers[1].notional - 800.).abs() < 1e-6);
+        assert!((brokers[1].effective_cost_bps - 510.).abs() < 1e-6);
+
+        assert!(benchmark(&prices, "OPP", d.succ_opt().unwrap()).is_none());
+    }
+
+    #[test]
+    fn group_bys_read_back_or_fail() {
+        assert_eq!(GroupBy::Trader.to_string().parse(), Ok(GroupBy::Trader));
+        assert_eq!(GroupBy::Broker.to_string().parse(), Ok(GroupBy::Broker));
+        assert!("DESK".parse::<GroupBy>().is_err());
+    }
+}
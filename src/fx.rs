@@ -0,0 +1,233 @@
+use crate::error::{self, Error};
+use crate::utils;
+use chrono::{NaiveDate, Duration as ChronoDuration};
+use serde::{Serialize,Deserialize};
+use std::time::SystemTime;
+use tokio_postgres::Row;
+use tracing::info;
+
+/// what a trade or fund is in when nothing says otherwise
+pub const DEFAULT_CURRENCY: &str = "USD";
+/// how far back a rate can be and still get used
+pub const MAX_STALE_DAYS: i64 = 5;
+
+/// One unit of base_currency buys rate units of quote_currency.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct FxRate {
+    pub base_currency: String,
+    pub quote_currency: String,
+    pub rate_date: NaiveDate,
+    pub rate: f64,
+    pub source: String
+}
+
+impl From<Row> for FxRate {
+    fn from(row: tokio_postgres::Row) -> Self {
+        Self {
+            base_currency: row.get("base_currency"),
+            quote_currency: row.get("quote_currency"),
+            rate_date: row.get("rate_date"),
+            rate: row.get("rate"),
+            source: row.get("source"),
+        }
+    }
+}
+
+/// An amount taken into another currency and the rate that did it, which is what goes in fx_audit.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Conversion {
+    pub from_currency: String,
+    pub to_currency: String,
+    pub as_of_date: NaiveDate,
+    pub rate_date: NaiveDate,
+    pub rate: f64,
+    pub source: String,
+    pub amount: f64,
+    pub converted: f64
+}
+
+pub async fn build_fx_tables(client: &tokio_postgres::Client) -> Result<(), Error> {
+
+    client.query("CREATE TABLE fx_rates (id SERIAL PRIMARY KEY,
+        base_currency VARCHAR NOT NULL,
+        quote_currency VARCHAR NOT NULL,
+        rate_date DATE NOT NULL,
+        rate FLOAT8 NOT NULL,
+        source VARCHAR NOT NULL,
+        UNIQUE (base_currency, quote_currency, rate_date, source)
+        )", &[]).await?;
+
+    client.query("CREATE TABLE fx_audit (id SERIAL PRIMARY KEY,
+        handle VARCHAR NOT NULL,
+        context VARCHAR NOT NULL,
+        reference VARCHAR NOT NULL,
+        from_currency VARCHAR NOT NULL,
+        to_currency VARCHAR NOT NULL,
+        as_of_date DATE NOT NULL,
+        rate_date DATE NOT NULL,
+        rate FLOAT8 NOT NULL,
+        source VARCHAR NOT NULL,
+        amount FLOAT8 NOT NULL,
+        converted FLOAT8 NOT NULL,
+        inserted_at TIMESTAMP NOT NULL
+        )", &[]).await?;
+
+    Ok(())
+}
+
+pub async fn drop_fx_tables(client: &tokio_postgres::Client) -> Result<(), Error> {
+
+    client.query("drop TABLE fx_rates", &[]).await?;
+    client.query("drop TABLE fx_audit", &[]).await?;
+
+    Ok(())
+}
+
+pub async fn insert_fx_rate(client: &tokio_postgres::Client, r: &FxRate) -> Result<(), Error> {
+
+    let statement = client.prepare("INSERT INTO fx_rates (
+        base_currency,
+        quote_currency,
+        rate_date,
+        rate,
+        source
+        ) VALUES ($1, $2, $3, $4, $5)
+        ON CONFLICT (base_currency, quote_currency, rate_date, source) DO UPDATE SET rate = EXCLUDED.rate").await?;
+
+    client.execute(&statement,&[
+        &r.base_currency,
+        &r.quote_currency,
+        &r.rate_date,
+        &r.rate,
+        &r.source
+        ]).await?;
+    Ok(())
+}
+
+pub async fn get_fx_rates(client: &tokio_postgres::Client) -> Result<Vec<FxRate>, Error> {
+
+    let rows = client.query("SELECT * FROM fx_rates ORDER BY rate_date", &[]).await?;
+    Ok(rows.into_iter().map(FxRate::from).collect())
+}
+
+/// The currency a fund reports in, dollars unless the fund's been set up in the hierarchy with something else.
+pub async fn get_base_currency(client: &tokio_postgres::Client, handle: &str) -> Result<String, Error> {
+
+    let rows = client.query("SELECT base_currency FROM funds WHERE handle = $1", &[&handle]).await?;
+    Ok(rows.first().map(|r| r.get("base_currency")).unwrap_or(DEFAULT_CURRENCY.to_string()))
+}
+
+/// context is what asked for the conversion (summary, nav...), reference is the trade id or position it was for.
+pub async fn insert_fx_audit(client: &tokio_postgres::Client, handle: &str, context: &str, reference: &str, c: &Conversion) -> Result<(), Error> {
+
+    let statement = client.prepare("INSERT INTO fx_audit (
+        handle,
+        context,
+        reference,
+        from_currency,
+        to_currency,
+        as_of_date,
+        rate_date,
+        rate,
+        source,
+        amount,
+        converted,
+        inserted_at
+        ) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12)").await?;
+
+    client.execute(&statement,&[
+        &handle,
+        &context,
+        &reference,
+        &c.from_currency,
+        &c.to_currency,
+        &c.as_of_date,
+        &c.rate_date,
+        &c.rate,
+        &c.source,
+        &c.amount,
+        &c.converted,
+        &SystemTime::now()
+        ]).await?;
+    Ok(())
+}
+
+/// Expects base_currency, quote_currency, rate_date (yyyy-mm-dd), rate and source columns.
+pub async fn load(client: &tokio_postgres::Client) -> Result<(), Error> {
+    let ifile = "/tmp/fx_rates.csv";
+
+    for (row, r) in utils::read_csv_records(ifile, 5)? {
+        let rate = FxRate {
+            base_currency: r[0].trim().to_uppercase(),
+            quote_currency: r[1].trim().to_uppercase(),
+            rate_date: error::parse_field(ifile, row, "rate_date", &r[2])?,
+            rate: error::parse_field(ifile, row, "rate", &r[3])?,
+            source: r[4].trim().to_string()
+        };
+        info!("{:?}", rate);
+        insert_fx_rate(client, &rate).await?;
+    }
+
+    Ok(())
+}
+
+/// Takes an amount from one currency to another with the latest rate within MAX_STALE_DAYS,
+/// quoted either way round. Same currency goes through at 1 so callers don't need to check.
+pub fn convert(rates: &[FxRate], amount: f64, from: &str, to: &str, as_of_date: NaiveDate) -> Option<Conversion> {
+
+    let conversion = |rate_date: NaiveDate, rate: f64, source: &str| Conversion {
+        from_currency: from.to_uppercase(),
+        to_currency: to.to_uppercase(),
+        as_of_date,
+        rate_date,
+        rate,
+        source: source.to_string(),
+        amount,
+        converted: amount * rate
+    };
+
+    if from.eq_ignore_ascii_case(to) {
+        return Some(conversion(as_of_date, 1., "IDENTITY"));
+    }
+
+    let oldest = as_of_date - ChronoDuration::days(MAX_STALE_DAYS);
+    rates.iter()
+        .filter(|r| r.rate_date <= as_of_date && r.rate_date >= oldest && r.rate != 0.)
+        .filter_map(|r| {
+            if r.base_currency.eq_ignore_ascii_case(from) && r.quote_currency.eq_ignore_ascii_case(to) {
+                Some((r, r.rate))
+            } else if r.base_currency.eq_ignore_ascii_case(to) && r.quote_currency.eq_ignore_ascii_case(from) {
+                Some((r, 1. / r.rate))
+            } else {
+                None
+            }
+        })
+        .max_by(|a, b| a.0.rate_date.cmp(&b.0.rate_date).then(b.0.source.cmp(&a.0.source)))
+        .map(|(r, rate)| conversion(r.rate_date, rate, &r.source))
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn convert_uses_the_latest_rate_either_way_round() {
+        let d = |day| NaiveDate::from_ymd_opt(2019, 11, day).unwrap();
+        let rates = vec![
+            FxRate { base_currency: "EUR".to_string(), quote_currency: "USD".to_string(), rate_date: d(1), rate: 1.10, source: "wm".to_string() },
+            FxRate { base_currency: "EUR".to_string(), quote_currency: "USD".to_string(), rate_date: d(4), rate: 1.25, source: "wm".to_string() },
+        ];
+
+        let c = convert(&rates, 100., "EUR", "USD", d(5)).unwrap();
+        assert_eq!((c.converted, c.rate_date), (125., d(4)));
+
+        let c = convert(&rates, 125., "usd", "eur", d(5)).unwrap();
+        assert_eq!(c.converted, 100.);
+
+        assert_eq!(convert(&rates, 10., "USD", "USD", d(5)).unwrap().converted, 10.);
+        assert!(convert(&rates, 10., "GBP", "USD", d(5)).is_none());
+        assert!(convert(&rates, 10., "EUR", "USD", d(20)).is_none());
+    }
+}
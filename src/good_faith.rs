@@ -0,0 +1,372 @@
+use crate::cash::{self, CashEntry};
+use crate::corporate_actions;
+use crate::error::Error;
+use crate::hierarchy::{self, Hierarchy};
+use crate::trades::{self, Trade};
+use crate::tx_types::Direction;
+use crate::utils;
+use chrono::{Months, NaiveDate};
+use serde::{Serialize,Deserialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+use tokio_postgres::{Row, Transaction};
+use tracing::{info, warn};
+
+/// the account_type, in the hierarchy or failing that on the trade, that gets checked
+pub const CASH_ACCOUNT_TYPE: &str = "CASH";
+/// good-faith violations in 12 months before the account gets restricted to settled cash
+pub const GOOD_FAITH_LIMIT: i64 = 3;
+/// free-riding restricts the account the first time
+pub const FREE_RIDING_LIMIT: i64 = 1;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ViolationType {
+    /// bought with money from a sale that hadn't settled, then sold before that money did
+    GoodFaith,
+    /// bought with nothing, settled or not, to pay for it and sold before the buy settled so the sale paid
+    FreeRiding
+}
+
+impl fmt::Display for ViolationType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ViolationType::GoodFaith => write!(f, "GOOD_FAITH"),
+            ViolationType::FreeRiding => write!(f, "FREE_RIDING"),
+        }
+    }
+}
+
+impl FromStr for ViolationType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_uppercase().replace([' ', '-'], "_").as_ref() {
+            "GOOD_FAITH" => Ok(ViolationType::GoodFaith),
+            "FREE_RIDING" => Ok(ViolationType::FreeRiding),
+            other => Err(format!("{} is not a cash account violation", other))
+        }
+    }
+}
+
+/// trade_date is the sale that made it a violation, funds_settle when the money the buy needed would have settled.
+/// rolling_12m counts this and the account's other violations of the same type in the 12 months up to trade_date.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Violation {
+    pub handle: String,
+    pub account_name: String,
+    pub currency: String,
+    pub violation_type: ViolationType,
+    pub security_ticker: String,
+    pub buy_trade_id: Option<i32>,
+    pub sell_trade_id: Option<i32>,
+    pub trade_date: NaiveDate,
+    pub funds_settle: NaiveDate,
+    pub shortfall: f64,
+    pub rolling_12m: i64
+}
+
+impl TryFrom<Row> for Violation {
+    type Error = Error;
+
+    fn try_from(row: tokio_postgres::Row) -> Result<Self, Self::Error> {
+        let violation_type: String = row.get("violation_type");
+        Ok(Self {
+            handle: row.get("handle"),
+            account_name: row.get("account_name"),
+            currency: row.get("currency"),
+            violation_type: violation_type.parse().map_err(|e| Error::Validation(format!("in cash_account_violations: {}", e)))?,
+            security_ticker: row.get("security_ticker"),
+            buy_trade_id: row.get("buy_trade_id"),
+            sell_trade_id: row.get("sell_trade_id"),
+            trade_date: row.get("trade_date"),
+            funds_settle: row.get("funds_settle"),
+            shortfall: row.get("shortfall"),
+            rolling_12m: row.get("rolling_12m"),
+        })
+    }
+}
+
+/// Where an account stands as of a date, restricted once either count reaches its limit.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct AccountStanding {
+    pub handle: String,
+    pub account_name: String,
+    pub as_of_date: NaiveDate,
+    pub good_faith_12m: i64,
+    pub free_riding_12m: i64,
+    pub restricted: bool
+}
+
+pub async fn build_violations_table(client: &tokio_postgres::Client) -> Result<(), Error> {
+
+    client.query("CREATE TABLE cash_account_violations (id SERIAL PRIMARY KEY,
+        handle VARCHAR NOT NULL,
+        account_name VARCHAR NOT NULL,
+        currency VARCHAR NOT NULL,
+        violation_type VARCHAR NOT NULL,
+        security_ticker VARCHAR NOT NULL,
+        buy_trade_id INT,
+        sell_trade_id INT,
+        trade_date DATE NOT NULL,
+        funds_settle DATE NOT NULL,
+        shortfall FLOAT8 NOT NULL,
+        rolling_12m BIGINT NOT NULL
+        )", &[]).await?;
+
+    Ok(())
+}
+
+pub async fn drop_violations_table(client: &tokio_postgres::Client) -> Result<(), Error> {
+
+    client.query("drop TABLE cash_account_violations", &[]).await?;
+
+    Ok(())
+}
+
+async fn clean_violations(client: &Transaction<'_>, handle: &str) -> Result<(), Error> {
+
+    let statement = client.prepare("delete from cash_account_violations where handle = $1").await?;
+    client.execute(&statement,&[&handle]).await?;
+
+    Ok(())
+}
+
+async fn insert_violation(client: &Transaction<'_>, v: &Violation) -> Result<(), Error> {
+
+    let statement = client.prepare("INSERT INTO cash_account_violations (
+        handle,
+        account_name,
+        currency,
+        violation_type,
+        security_ticker,
+        buy_trade_id,
+        sell_trade_id,
+        trade_date,
+        funds_settle,
+        shortfall,
+        rolling_12m
+        ) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11)").await?;
+
+    client.execute(&statement,&[
+        &v.handle,
+        &v.account_name,
+        &v.currency,
+        &v.violation_type.to_string(),
+        &v.security_ticker,
+        &v.buy_trade_id,
+        &v.sell_trade_id,
+        &v.trade_date,
+        &v.funds_settle,
+        &v.shortfall,
+        &v.rolling_12m
+        ]).await?;
+    Ok(())
+}
+
+pub async fn get_violations(client: &tokio_postgres::Client, handle: &str) -> Result<Vec<Violation>, Error> {
+
+    let rows = client.query("SELECT * FROM cash_account_violations WHERE handle = $1 ORDER BY account_name, trade_date, id", &[&handle]).await?;
+    rows.into_iter().map(Violation::try_from).collect()
+}
+
+pub fn is_cash_account(hierarchy: &Hierarchy, trade: &Trade) -> bool {
+    hierarchy.account_for(trade).map(|a| a.account_type.as_str()).unwrap_or(&trade.account_type).trim().eq_ignore_ascii_case(CASH_ACCOUNT_TYPE)
+}
+
+/// Ledger entries for the account and currency that were on the books before the buy, a same day entry counting
+/// when its trade came in first.
+fn before<'a>(ledger: &'a [CashEntry], b: &'a Trade) -> impl Iterator<Item = &'a CashEntry> {
+    let d = utils::to_date(b.trade_date);
+    let account = b.account_name.to_uppercase();
+    ledger.iter().filter(move |e| {
+        e.account_name == account && e.currency == b.currency && e.trade_id != b.id &&
+        (e.trade_date < d || (e.trade_date == d && e.trade_id.unwrap_or(0) < b.id.unwrap_or(0)))
+    })
+}
+
+/// Every buy in a cash account is checked against the settled cash there was when it was made, less buys before it
+/// still waiting to settle, then against sale proceeds still settling, soonest first. Proceeds aren't shared out
+/// between buys, so two buys on the same unsettled sale both count it.
+pub fn detect(trades: &[Trade], ledger: &[CashEntry], hierarchy: &Hierarchy) -> Vec<Violation> {
+
+    let cash_trades: Vec<&Trade> = trades.iter().filter(|t| t.tx().is_trade() && is_cash_account(hierarchy, t)).collect();
+    let mut found: Vec<Violation> = Vec::new();
+
+    for b in cash_trades.iter().filter(|t| t.direction() == Direction::Buy) {
+        let d = utils::to_date(b.trade_date);
+        let cost = ledger.iter().find(|e| b.id.is_some() && e.trade_id == b.id).map(|e| e.amount.abs()).unwrap_or(b.net_amount.abs());
+        let settled: f64 = before(ledger, b)
+            .filter(|e| e.settlement_date <= d || e.amount < 0.)
+            .map(|e| e.amount)
+            .sum();
+        let shortfall = cost - settled.max(0.);
+        if shortfall <= 0. {
+            continue
+        }
+
+        let mut proceeds: Vec<&CashEntry> = before(ledger, b).filter(|e| e.amount > 0. && e.settlement_date > d).collect();
+        proceeds.sort_by_key(|e| e.settlement_date);
+        let mut covered = 0.;
+        let mut funds_settle = None;
+        for e in proceeds {
+            covered += e.amount;
+            if covered >= shortfall {
+                funds_settle = Some(e.settlement_date);
+                break
+            }
+        }
+
+        // good faith if unsettled proceeds paid and the name went before they settled, free riding if nothing paid
+        let (violation_type, funds_settle) = match funds_settle {
+            Some(f) => (ViolationType::GoodFaith, f),
+            None => (ViolationType::FreeRiding, utils::to_date(b.settlement_date))
+        };
+        let sale = cash_trades.iter().find(|s| {
+            s.direction() == Direction::Sell &&
+            s.account_name.eq_ignore_ascii_case(&b.account_name) &&
+            s.security_ticker.eq_ignore_ascii_case(&b.security_ticker) &&
+            (s.trade_date > b.trade_date || (s.trade_date == b.trade_date && s.id > b.id)) &&
+            utils::to_date(s.trade_date) < funds_settle
+        });
+        if let Some(s) = sale {
+            found.push(Violation {
+                handle: b.handle.clone(),
+                account_name: b.account_name.to_uppercase(),
+                currency: b.currency.clone(),
+                violation_type,
+                security_ticker: b.security_ticker.to_uppercase(),
+                buy_trade_id: b.id,
+                sell_trade_id: s.id,
+                trade_date: utils::to_date(s.trade_date),
+                funds_settle,
+                shortfall,
+                rolling_12m: 0
+            });
+        }
+    }
+
+    found.sort_by(|a, b| (&a.account_name, a.trade_date).cmp(&(&b.account_name, b.trade_date)));
+    let counted: Vec<i64> = found.iter().map(|v| rolling_count(&found, &v.account_name, v.violation_type, v.trade_date)).collect();
+    for (v, n) in found.iter_mut().zip(counted) {
+        v.rolling_12m = n;
+    }
+    found
+}
+
+/// Violations of the type in the account in the 12 months up to and including as_of_date.
+pub fn rolling_count(violations: &[Violation], account_name: &str, violation_type: ViolationType, as_of_date: NaiveDate) -> i64 {
+    let from = as_of_date.checked_sub_months(Months::new(12)).unwrap_or(NaiveDate::MIN);
+    violations.iter()
+        .filter(|v| v.account_name == account_name && v.violation_type == violation_type && v.trade_date > from && v.trade_date <= as_of_date)
+        .count() as i64
+}
+
+pub fn standings(violations: &[Violation], as_of_date: NaiveDate) -> Vec<AccountStanding> {
+
+    let mut g: BTreeMap<String, &Violation> = BTreeMap::new();
+    for v in violations {
+        g.entry(v.account_name.clone()).or_insert(v);
+    }
+
+    g.into_iter().map(|(account_name, v)| {
+        let good_faith_12m = rolling_count(violations, &account_name, ViolationType::GoodFaith, as_of_date);
+        let free_riding_12m = rolling_count(violations, &account_name, ViolationType::FreeRiding, as_of_date);
+        AccountStanding {
+            handle: v.handle.clone(),
+            account_name,
+            as_of_date,
+            good_faith_12m,
+            free_riding_12m,
+            restricted: good_faith_12m >= GOOD_FAITH_LIMIT || free_riding_12m >= FREE_RIDING_LIMIT
+        }
+    }).collect()
+}
+
+/// Rechecks the handle's cash accounts off the posted cash ledger, replacing its violations in one transaction,
+/// and logs where each stands as of its last trade.
+pub async fn check(client: &mut tokio_postgres::Client, handle: &str) -> Result<Vec<AccountStanding>, Error> {
+
+    let all_trades = trades::get_all_trades(client, handle).await?;
+    let hierarchy = hierarchy::get_hierarchy(client).await?;
+    let mut ledger = cash::get_ledger(client, handle).await?;
+    if ledger.is_empty() {
+        warn!("no cash ledger posted for {:?}, working off the trades", handle);
+        ledger = cash::ledger(&all_trades, &corporate_actions::get_corporate_actions(client).await?);
+    }
+
+    let tx = client.transaction().await?;
+    info!("first I'll clean up cash account violations for {:?}", handle);
+    clean_violations(&tx, handle).await?;
+
+    let violations = detect(&all_trades, &ledger, &hierarchy);
+    for v in &violations {
+        info!("{:?}", v);
+        insert_violation(&tx, v).await?;
+    }
+    tx.commit().await?;
+
+    let as_of_date = all_trades.iter().map(|t| utils::to_date(t.trade_date)).max().unwrap_or_default();
+    let standing = standings(&violations, as_of_date);
+    for s in &standing {
+        info!("{:?}", s);
+    }
+
+    Ok(standing)
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn trade(id: i32, tx_type: &str, ticker: &str, day: u32, settle_days: u32, quantity: f64, net_amount: f64) -> Trade {
+        let t = Trade::test(id, tx_type, day, quantity, 10.);
+        Trade {
+            account_name: "RN9".to_string(),
+            account_number: "RN9".to_string(),
+            account_type: "Cash".to_string(),
+            security_ticker: ticker.to_string(),
+            principal: net_amount,
+            net_amount,
+            settlement_date: t.trade_date + settle_days as i64 * 86400,
+            ..t
+        }
+    }
+
+    #[test]
+    fn good_faith_and_free_riding() {
+        let trades = vec![
+            trade(1, "SUBSCRIPTION", "", 1, 0, 0., 1000.),
+            trade(2, "BUY", "AAA", 2, 2, 100., -1000.),
+            trade(3, "SELL", "AAA", 5, 2, -100., 1000.),
+            // paid for with trade 3's proceeds and gone before they settle on the 7th
+            trade(4, "BUY", "BBB", 5, 2, 100., -1000.),
+            trade(5, "SELL", "BBB", 6, 2, -100., 1000.),
+            // nothing to pay for this one at all
+            trade(6, "BUY", "CCC", 10, 2, 500., -5000.),
+            trade(7, "SELL", "CCC", 11, 2, -500., 5000.),
+        ];
+        let ledger: Vec<CashEntry> = trades.iter().map(CashEntry::from).collect();
+
+        let found = detect(&trades, &ledger, &Hierarchy::default());
+        let summary: Vec<(ViolationType, Option<i32>, Option<i32>)> = found.iter().map(|v| (v.violation_type, v.buy_trade_id, v.sell_trade_id)).collect();
+        assert_eq!(summary, vec![(ViolationType::GoodFaith, Some(4), Some(5)), (ViolationType::FreeRiding, Some(6), Some(7))]);
+        assert_eq!(found[0].funds_settle, NaiveDate::from_ymd_opt(2019, 11, 7).unwrap());
+
+        let standing = standings(&found, NaiveDate::from_ymd_opt(2019, 12, 31).unwrap());
+        assert_eq!((standing[0].good_faith_12m, standing[0].free_riding_12m, standing[0].restricted), (1, 1, true));
+        assert!(standings(&found, NaiveDate::from_ymd_opt(2020, 11, 30).unwrap())[0].good_faith_12m == 0);
+
+        let margin: Vec<Trade> = trades.iter().map(|t| Trade { account_type: "MARGIN".to_string(), ..t.clone() }).collect();
+        assert!(detect(&margin, &ledger, &Hierarchy::default()).is_empty());
+    }
+
+    #[test]
+    fn violation_types_read_back_or_fail() {
+        assert_eq!(ViolationType::FreeRiding.to_string().parse(), Ok(ViolationType::FreeRiding));
+        assert_eq!(ViolationType::GoodFaith.to_string().parse(), Ok(ViolationType::GoodFaith));
+        assert!("PATTERN_DAY_TRADE".parse::<ViolationType>().is_err());
+    }
+}
@@ -0,0 +1,294 @@
+use crate::error::Error;
+use crate::trades::Trade;
+use crate::utils;
+use serde::{Serialize,Deserialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+use tracing::{info, warn};
+
+/// what rollups call trades whose account nobody has put in the hierarchy yet
+pub const UNMAPPED: &str = "UNMAPPED";
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Firm {
+    pub id: i32,
+    pub name: String
+}
+
+/// A fund is what everything else calls a handle.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Fund {
+    pub id: i32,
+    pub firm_id: i32,
+    pub handle: String,
+    pub name: String,
+    pub base_currency: String
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Portfolio {
+    pub id: i32,
+    pub fund_id: i32,
+    pub name: String
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Account {
+    pub id: i32,
+    pub portfolio_id: i32,
+    pub account_number: String,
+    pub name: String,
+    pub account_type: String
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Level {
+    Firm,
+    Fund,
+    Portfolio,
+    Account
+}
+
+impl Level {
+    pub const ALL: [Level; 4] = [Level::Firm, Level::Fund, Level::Portfolio, Level::Account];
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Level::Firm => write!(f, "FIRM"),
+            Level::Fund => write!(f, "FUND"),
+            Level::Portfolio => write!(f, "PORTFOLIO"),
+            Level::Account => write!(f, "ACCOUNT"),
+        }
+    }
+}
+
+impl FromStr for Level {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_uppercase().as_ref() {
+            "FIRM" => Ok(Level::Firm),
+            "FUND" | "HANDLE" => Ok(Level::Fund),
+            "PORTFOLIO" => Ok(Level::Portfolio),
+            "ACCOUNT" => Ok(Level::Account),
+            other => Err(format!("{} is not a hierarchy level", other))
+        }
+    }
+}
+
+/// The whole firm / fund / portfolio / account tree loaded up, with every alias a source uses for an account.
+#[derive(Clone, Debug, Default)]
+pub struct Hierarchy {
+    firms: HashMap<i32, Firm>,
+    funds: HashMap<i32, Fund>,
+    portfolios: HashMap<i32, Portfolio>,
+    accounts: HashMap<i32, Account>,
+    aliases: HashMap<(String,String), i32>
+}
+
+impl Hierarchy {
+    pub fn new(firms: Vec<Firm>, funds: Vec<Fund>, portfolios: Vec<Portfolio>, accounts: Vec<Account>, aliases: Vec<(String, String, i32)>) -> Self {
+        Hierarchy {
+            firms: firms.into_iter().map(|x| (x.id, x)).collect(),
+            funds: funds.into_iter().map(|x| (x.id, x)).collect(),
+            portfolios: portfolios.into_iter().map(|x| (x.id, x)).collect(),
+            accounts: accounts.into_iter().map(|x| (x.id, x)).collect(),
+            aliases: aliases.into_iter().map(|(source, alias, id)| ((source.to_lowercase(), alias.trim().to_uppercase()), id)).collect()
+        }
+    }
+
+    /// An alias for the source first, then the account number itself, which any source can use.
+    pub fn resolve(&self, source: &str, raw: &str) -> Option<&Account> {
+        let raw = raw.trim().to_uppercase();
+        self.aliases.get(&(source.to_lowercase(), raw.clone()))
+            .and_then(|id| self.accounts.get(id))
+            .or_else(|| self.accounts.values().find(|a| a.account_number.to_uppercase() == raw))
+    }
+
+    pub fn fund_of(&self, account: &Account) -> Option<&Fund> {
+        self.portfolios.get(&account.portfolio_id).and_then(|p| self.funds.get(&p.fund_id))
+    }
+
+    /// Sets account_id and the real account number off whatever the file called the account, the handle is the source.
+    pub fn enrich(&self, trade: &mut Trade) {
+        match self.resolve(&trade.handle, &trade.account_name) {
+            Some(a) => {
+                if self.fund_of(a).map(|f| f.handle != trade.handle).unwrap_or(false) {
+                    warn!("{:?} row {:?} account {:?} belongs to another fund", trade.filename, trade.row, trade.account_name);
+                }
+                trade.account_id = Some(a.id);
+                trade.account_number = a.account_number.clone();
+            },
+            None => warn!("{:?} row {:?} account {:?} isn't in the hierarchy", trade.filename, trade.row, trade.account_name)
+        }
+    }
+
+    /// The account a trade's in, off its account_id or resolved on the fly for trades from before the hierarchy existed.
+    pub fn account_for(&self, trade: &Trade) -> Option<&Account> {
+        trade.account_id.and_then(|id| self.accounts.get(&id))
+            .or_else(|| self.resolve(&trade.handle, &trade.account_name))
+    }
+
+    /// The name of the node a trade rolls up to at a level. Trades from before the hierarchy existed get resolved on the fly,
+    /// and if that fails still land under their own handle and account name.
+    pub fn node(&self, trade: &Trade, level: Level) -> String {
+        let account = self.account_for(trade);
+        let portfolio = account.and_then(|a| self.portfolios.get(&a.portfolio_id));
+        let fund = portfolio.and_then(|p| self.funds.get(&p.fund_id));
+        let firm = fund.and_then(|f| self.firms.get(&f.firm_id));
+        match level {
+            Level::Firm => firm.map(|x| x.name.clone()).unwrap_or(UNMAPPED.to_string()),
+            Level::Fund => fund.map(|x| x.handle.clone()).unwrap_or(trade.handle.clone()),
+            Level::Portfolio => portfolio.map(|x| x.name.clone()).unwrap_or(UNMAPPED.to_string()),
+            Level::Account => account.map(|x| x.account_number.clone()).unwrap_or(trade.account_name.to_uppercase())
+        }
+    }
+}
+
+pub async fn build_hierarchy_tables(client: &tokio_postgres::Client) -> Result<(), Error> {
+
+    client.query("CREATE TABLE firms (id SERIAL PRIMARY KEY,
+        name VARCHAR NOT NULL UNIQUE
+        )", &[]).await?;
+
+    client.query("CREATE TABLE funds (id SERIAL PRIMARY KEY,
+        firm_id INT NOT NULL REFERENCES firms (id),
+        handle VARCHAR NOT NULL UNIQUE,
+        name VARCHAR NOT NULL,
+        base_currency VARCHAR NOT NULL
+        )", &[]).await?;
+
+    client.query("CREATE TABLE portfolios (id SERIAL PRIMARY KEY,
+        fund_id INT NOT NULL REFERENCES funds (id),
+        name VARCHAR NOT NULL,
+        UNIQUE (fund_id, name)
+        )", &[]).await?;
+
+    client.query("CREATE TABLE accounts (id SERIAL PRIMARY KEY,
+        portfolio_id INT NOT NULL REFERENCES portfolios (id),
+        account_number VARCHAR NOT NULL UNIQUE,
+        name VARCHAR NOT NULL,
+        account_type VARCHAR NOT NULL
+        )", &[]).await?;
+
+    client.query("CREATE TABLE account_aliases (id SERIAL PRIMARY KEY,
+        account_id INT NOT NULL REFERENCES accounts (id),
+        source VARCHAR NOT NULL,
+        alias VARCHAR NOT NULL,
+        UNIQUE (source, alias)
+        )", &[]).await?;
+
+    Ok(())
+}
+
+pub async fn drop_hierarchy_tables(client: &tokio_postgres::Client) -> Result<(), Error> {
+
+    client.query("drop TABLE account_aliases", &[]).await?;
+    client.query("drop TABLE accounts", &[]).await?;
+    client.query("drop TABLE portfolios", &[]).await?;
+    client.query("drop TABLE funds", &[]).await?;
+    client.query("drop TABLE firms", &[]).await?;
+
+    Ok(())
+}
+
+pub async fn get_hierarchy(client: &tokio_postgres::Client) -> Result<Hierarchy, Error> {
+
+    let firms = client.query("SELECT * FROM firms", &[]).await?.into_iter().map(|r| Firm {
+        id: r.get("id"),
+        name: r.get("name")
+    }).collect();
+    let funds = client.query("SELECT * FROM funds", &[]).await?.into_iter().map(|r| Fund {
+        id: r.get("id"),
+        firm_id: r.get("firm_id"),
+        handle: r.get("handle"),
+        name: r.get("name"),
+        base_currency: r.get("base_currency")
+    }).collect();
+    let portfolios = client.query("SELECT * FROM portfolios", &[]).await?.into_iter().map(|r| Portfolio {
+        id: r.get("id"),
+        fund_id: r.get("fund_id"),
+        name: r.get("name")
+    }).collect();
+    let accounts = client.query("SELECT * FROM accounts", &[]).await?.into_iter().map(|r| Account {
+        id: r.get("id"),
+        portfolio_id: r.get("portfolio_id"),
+        account_number: r.get("account_number"),
+        name: r.get("name"),
+        account_type: r.get("account_type")
+    }).collect();
+    let aliases = client.query("SELECT * FROM account_aliases", &[]).await?.into_iter().map(|r| (
+        r.get("source"),
+        r.get("alias"),
+        r.get("account_id")
+    )).collect();
+
+    Ok(Hierarchy::new(firms, funds, portfolios, accounts, aliases))
+}
+
+/// Each level gets upserted on its natural key and hands back its id for the next one down.
+async fn upsert_id(client: &tokio_postgres::Client, sql: &str, params: &[&(dyn tokio_postgres::types::ToSql + Sync)]) -> Result<i32, Error> {
+    let row = client.query_one(sql, params).await?;
+    Ok(row.get("id"))
+}
+
+/// One row per account, or per alias if an account goes by several names.
+/// Expects firm, handle, fund_name, base_currency, portfolio, account_number, account_name, account_type, source, alias columns,
+/// source and alias can be blank.
+pub async fn load(client: &tokio_postgres::Client) -> Result<(), Error> {
+    let ifile = "/tmp/hierarchy.csv";
+
+    for (_, r) in utils::read_csv_records(ifile, 10)? {
+        info!("{:?}", r);
+
+        let firm_id = upsert_id(client, "INSERT INTO firms (name) VALUES ($1)
+            ON CONFLICT (name) DO UPDATE SET name = EXCLUDED.name RETURNING id",
+            &[&r[0].trim()]).await?;
+
+        let fund_id = upsert_id(client, "INSERT INTO funds (firm_id, handle, name, base_currency) VALUES ($1, $2, $3, $4)
+            ON CONFLICT (handle) DO UPDATE SET firm_id = EXCLUDED.firm_id, name = EXCLUDED.name, base_currency = EXCLUDED.base_currency RETURNING id",
+            &[&firm_id, &r[1].trim().to_lowercase(), &r[2].trim(), &r[3].trim().to_uppercase()]).await?;
+
+        let portfolio_id = upsert_id(client, "INSERT INTO portfolios (fund_id, name) VALUES ($1, $2)
+            ON CONFLICT (fund_id, name) DO UPDATE SET name = EXCLUDED.name RETURNING id",
+            &[&fund_id, &r[4].trim()]).await?;
+
+        let account_id = upsert_id(client, "INSERT INTO accounts (portfolio_id, account_number, name, account_type) VALUES ($1, $2, $3, $4)
+            ON CONFLICT (account_number) DO UPDATE SET portfolio_id = EXCLUDED.portfolio_id, name = EXCLUDED.name, account_type = EXCLUDED.account_type RETURNING id",
+            &[&portfolio_id, &r[5].trim().to_uppercase(), &r[6].trim(), &r[7].trim()]).await?;
+
+        if !r[8].trim().is_empty() && !r[9].trim().is_empty() {
+            client.execute("INSERT INTO account_aliases (account_id, source, alias) VALUES ($1, $2, $3)
+                ON CONFLICT (source, alias) DO UPDATE SET account_id = EXCLUDED.account_id",
+                &[&account_id, &r[8].trim().to_lowercase(), &r[9].trim().to_uppercase()]).await?;
+        }
+    }
+
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn aliases_resolve_per_source() {
+        let h = Hierarchy::new(
+            vec![Firm { id: 1, name: "River North".to_string() }],
+            vec![Fund { id: 1, firm_id: 1, handle: "rivernorth".to_string(), name: "RN Opportunities".to_string(), base_currency: "USD".to_string() }],
+            vec![Portfolio { id: 1, fund_id: 1, name: "Core".to_string() }],
+            vec![Account { id: 7, portfolio_id: 1, account_number: "RN1".to_string(), name: "Main".to_string(), account_type: "Margin".to_string() }],
+            vec![("rivernorth".to_string(), "rn-main".to_string(), 7)]
+        );
+
+        assert_eq!(h.resolve("rivernorth", " RN-MAIN ").map(|a| a.id), Some(7));
+        assert_eq!(h.resolve("otheradmin", "rn1").map(|a| a.id), Some(7));
+        assert!(h.resolve("otheradmin", "rn-main").is_none());
+        assert_eq!(h.resolve("rivernorth", "rn-main").and_then(|a| h.fund_of(a)).map(|f| f.handle.as_str()), Some("rivernorth"));
+    }
+}
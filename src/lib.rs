@@ -0,0 +1,60 @@
+//! Trade ingest, summaries and chain detection for the funds we administer, usable in-process.
+//!
+//! The usual run is parse a source's files into `trades` ([`rivernorth::parse`]), then build what reporting
+//! needs off that table: summaries and trade chains for altpilot ([`trades::summarize`], [`trades::chain`]),
+//! the cash ledger ([`cash::post`]), holdings recon ([`recon::recon`]), nav history ([`nav::compute`]),
+//! surveillance alerts ([`surveillance::surveil`]), cash account checks ([`good_faith::check`]), best execution
+//! ([`execution::best_execution`]) and trading activity stats ([`stats::compute`]).
+//! Everything takes a `tokio_postgres::Client` for the tradellama database, the altpilot writers take a second one.
+//! Parse, summarize and chain go through the [`store`] traits instead, on Postgres that's a [`pool`] so the writes that
+//! need a transaction get a connection of their own, [`store::MemoryStore`] can stand in for both and
+//! [`sqlite::SqliteStore`] can run them on a laptop.
+//!
+//! [`trades::Trade`] is the model everything works off, signs follow the convention in [`tx_types`].
+
+/// Cash ledger posted off settlement dates, with settled and projected balances.
+pub mod cash;
+/// The command line, parsing the arguments and running the named function against postgres or sqlite.
+pub mod cli;
+/// Commission totals, cents per share and bps by broker, trader, account and month, against broker targets.
+pub mod commissions;
+/// Splits, renames, mergers and spin-offs, and applying them to trade history.
+pub mod corporate_actions;
+/// The crate wide error type and the exit codes the CLI uses for each kind.
+pub mod error;
+/// Best execution: fills against the same day VWAP or mark, outliers, and cost by broker and trader.
+pub mod execution;
+/// FX rates, conversion into a fund's base currency and the audit of every rate used.
+pub mod fx;
+/// Good-faith and free-riding violations in cash accounts, with rolling 12 month counts.
+pub mod good_faith;
+/// Firm, fund, portfolio and account, with the aliases each source uses for an account.
+pub mod hierarchy;
+/// Daily nav, nav per share and fee accruals.
+pub mod nav;
+/// Positions rolled up from trades as of a date.
+pub mod positions;
+/// Pooled Postgres connections, which work as a store for parse, summarize and chain.
+pub mod pool;
+/// Marks from price files and picking the best one for a day.
+pub mod prices;
+/// Administrator holdings against positions derived from trades.
+pub mod recon;
+/// River North's activity and holdings files.
+pub mod rivernorth;
+/// Security master, identifier checks and the asset class taxonomy.
+pub mod securities;
+/// The SQLite backend, for running without a Postgres server.
+pub mod sqlite;
+/// Turnover, trade counts and sizes, buy/sell ratio and names traded per handle by month, quarter and year.
+pub mod stats;
+/// Storage traits summaries and chains are written against, Postgres and in-memory backends.
+pub mod store;
+/// Compliance rules run over trades, writing alerts with the trades behind them.
+pub mod surveillance;
+/// The trade model, the trades table, summaries and chain detection.
+pub mod trades;
+/// Canonical tx types, directions and how each source spells them.
+pub mod tx_types;
+/// Hashing and date helpers.
+pub mod utils;
@@ -0,0 +1,348 @@
+use crate::cash;
+use crate::corporate_actions;
+use crate::error::Error;
+use crate::fx::{self, Conversion, FxRate};
+use crate::positions::{self, Position};
+use crate::prices::{self, Price};
+use crate::recon::QUANTITY_TOLERANCE;
+use crate::trades;
+use crate::utils;
+use chrono::{Datelike, NaiveDate, Weekday, Duration as ChronoDuration};
+use serde::{Serialize,Deserialize};
+use tokio_postgres::Row;
+use tracing::{info, warn};
+
+/// day over day moves in nav per share bigger than this get flagged for a look
+pub const NAV_MOVE_THRESHOLD: f64 = 0.05;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct NavRecord {
+    pub handle: String,
+    pub nav_date: NaiveDate,
+    pub base_currency: String,
+    pub market_value: f64,
+    pub cash: f64,
+    pub accrued_fees: f64,
+    pub nav: f64,
+    pub shares_outstanding: f64,
+    pub nav_per_share: f64,
+    pub daily_return: f64,
+    pub unpriced: i32,
+    pub flagged: bool
+}
+
+impl From<Row> for NavRecord {
+    fn from(row: tokio_postgres::Row) -> Self {
+        Self {
+            handle: row.get("handle"),
+            nav_date: row.get("nav_date"),
+            base_currency: row.get("base_currency"),
+            market_value: row.get("market_value"),
+            cash: row.get("cash"),
+            accrued_fees: row.get("accrued_fees"),
+            nav: row.get("nav"),
+            shares_outstanding: row.get("shares_outstanding"),
+            nav_per_share: row.get("nav_per_share"),
+            daily_return: row.get("daily_return"),
+            unpriced: row.get("unpriced"),
+            flagged: row.get("flagged"),
+        }
+    }
+}
+
+/// Shares outstanding as of a date, the latest one on or before the nav date applies.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct FundShares {
+    pub handle: String,
+    pub as_of_date: NaiveDate,
+    pub shares_outstanding: f64
+}
+
+/// An annual fee accrued daily on gross assets, e.g. management at 0.01.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct FeeSchedule {
+    pub handle: String,
+    pub fee_name: String,
+    pub annual_rate: f64
+}
+
+pub async fn build_nav_tables(client: &tokio_postgres::Client) -> Result<(), Error> {
+
+    client.query("CREATE TABLE nav_history (id SERIAL PRIMARY KEY,
+        handle VARCHAR NOT NULL,
+        nav_date DATE NOT NULL,
+        base_currency VARCHAR NOT NULL,
+        market_value FLOAT8 NOT NULL,
+        cash FLOAT8 NOT NULL,
+        accrued_fees FLOAT8 NOT NULL,
+        nav FLOAT8 NOT NULL,
+        shares_outstanding FLOAT8 NOT NULL,
+        nav_per_share FLOAT8 NOT NULL,
+        daily_return FLOAT8 NOT NULL,
+        unpriced INT NOT NULL,
+        flagged BOOLEAN NOT NULL,
+        UNIQUE (handle, nav_date)
+        )", &[]).await?;
+
+    client.query("CREATE TABLE fund_shares (id SERIAL PRIMARY KEY,
+        handle VARCHAR NOT NULL,
+        as_of_date DATE NOT NULL,
+        shares_outstanding FLOAT8 NOT NULL,
+        UNIQUE (handle, as_of_date)
+        )", &[]).await?;
+
+    client.query("CREATE TABLE fee_schedules (id SERIAL PRIMARY KEY,
+        handle VARCHAR NOT NULL,
+        fee_name VARCHAR NOT NULL,
+        annual_rate FLOAT8 NOT NULL
+        )", &[]).await?;
+
+    Ok(())
+}
+
+pub async fn drop_nav_tables(client: &tokio_postgres::Client) -> Result<(), Error> {
+
+    client.query("drop TABLE nav_history", &[]).await?;
+    client.query("drop TABLE fund_shares", &[]).await?;
+    client.query("drop TABLE fee_schedules", &[]).await?;
+
+    Ok(())
+}
+
+pub async fn get_fund_shares(client: &tokio_postgres::Client, handle: &str) -> Result<Vec<FundShares>, Error> {
+
+    let rows = client.query("SELECT * FROM fund_shares WHERE handle = $1 ORDER BY as_of_date", &[&handle]).await?;
+    Ok(rows.into_iter().map(|r| FundShares {
+        handle: r.get("handle"),
+        as_of_date: r.get("as_of_date"),
+        shares_outstanding: r.get("shares_outstanding")
+    }).collect())
+}
+
+pub async fn get_fee_schedules(client: &tokio_postgres::Client, handle: &str) -> Result<Vec<FeeSchedule>, Error> {
+
+    let rows = client.query("SELECT * FROM fee_schedules WHERE handle = $1", &[&handle]).await?;
+    Ok(rows.into_iter().map(|r| FeeSchedule {
+        handle: r.get("handle"),
+        fee_name: r.get("fee_name"),
+        annual_rate: r.get("annual_rate")
+    }).collect())
+}
+
+async fn insert_nav_record(client: &tokio_postgres::Client, n: &NavRecord) -> Result<(), Error> {
+
+    let statement = client.prepare("INSERT INTO nav_history (
+        handle,
+        nav_date,
+        base_currency,
+        market_value,
+        cash,
+        accrued_fees,
+        nav,
+        shares_outstanding,
+        nav_per_share,
+        daily_return,
+        unpriced,
+        flagged
+        ) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12)
+        ON CONFLICT (handle, nav_date) DO UPDATE SET
+        base_currency = EXCLUDED.base_currency,
+        market_value = EXCLUDED.market_value,
+        cash = EXCLUDED.cash,
+        accrued_fees = EXCLUDED.accrued_fees,
+        nav = EXCLUDED.nav,
+        shares_outstanding = EXCLUDED.shares_outstanding,
+        nav_per_share = EXCLUDED.nav_per_share,
+        daily_return = EXCLUDED.daily_return,
+        unpriced = EXCLUDED.unpriced,
+        flagged = EXCLUDED.flagged").await?;
+
+    client.execute(&statement,&[
+        &n.handle,
+        &n.nav_date,
+        &n.base_currency,
+        &n.market_value,
+        &n.cash,
+        &n.accrued_fees,
+        &n.nav,
+        &n.shares_outstanding,
+        &n.nav_per_share,
+        &n.daily_return,
+        &n.unpriced,
+        &n.flagged
+        ]).await?;
+    Ok(())
+}
+
+/// Values positions at the best mark for the day in the fund's base currency, falling back to the last execution price.
+/// A position with no mark or no fx rate counts as unpriced, missing rates go through at 1. Flat positions are left out,
+/// they're worth nothing and a name sold out of long ago would otherwise count as unpriced every day after.
+/// Hands back the conversions so they can go in the audit.
+pub fn market_value(positions: &[Position], prices: &[Price], rates: &[FxRate], base_currency: &str, nav_date: NaiveDate) -> (f64, i32, Vec<(String, Conversion)>) {
+    let mut unpriced = 0;
+    let mut conversions: Vec<(String, Conversion)> = Vec::new();
+    let mv = positions.iter().filter(|p| p.quantity.abs() > QUANTITY_TOLERANCE).map(|p| {
+        let price = match prices::best_mark(prices, &p.security_ticker, nav_date, &[]) {
+            Some(m) => m.price,
+            None => {
+                unpriced += 1;
+                p.last_price
+            }
+        };
+        let local = p.quantity * price;
+        match fx::convert(rates, local, &p.currency, base_currency, nav_date) {
+            Some(c) => {
+                let converted = c.converted;
+                conversions.push((format!("{}/{}", p.account_name, p.security_ticker), c));
+                converted
+            },
+            None => {
+                warn!("no {}/{} rate for {:?} on {:?}", p.currency, base_currency, p.security_ticker, nav_date);
+                unpriced += 1;
+                local
+            }
+        }
+    }).sum();
+    (mv, unpriced, conversions)
+}
+
+/// Puts a day together and compares it against the prior day.
+pub fn nav_for_day(handle: &str, nav_date: NaiveDate, market_value: f64, cash: f64, accrued_fees: f64, shares_outstanding: f64, prior: Option<&NavRecord>) -> NavRecord {
+
+    let nav = market_value + cash - accrued_fees;
+    let nav_per_share = if shares_outstanding > 0. { nav / shares_outstanding } else { 0. };
+
+    // without shares we can still say how the fund moved
+    let (today, yesterday) = match prior {
+        Some(p) if shares_outstanding > 0. && p.shares_outstanding > 0. => (nav_per_share, p.nav_per_share),
+        Some(p) => (nav, p.nav),
+        None => (0., 0.)
+    };
+    let daily_return = if yesterday != 0. { today / yesterday - 1. } else { 0. };
+
+    NavRecord {
+        handle: handle.to_string(),
+        nav_date,
+        base_currency: fx::DEFAULT_CURRENCY.to_string(),
+        market_value,
+        cash,
+        accrued_fees,
+        nav,
+        shares_outstanding,
+        nav_per_share,
+        daily_return,
+        unpriced: 0,
+        flagged: daily_return.abs() > NAV_MOVE_THRESHOLD
+    }
+}
+
+/// Strikes a nav for every weekday from the first trade through the last trade or the last price we have for a name
+/// the handle traded. Fees accrue on gross assets for every calendar day since the prior nav, so a Monday carries the
+/// weekend, and start over each month, when they get paid.
+pub async fn compute(client: &tokio_postgres::Client, handle: &str) -> Result<(), Error> {
+
+    let all_trades = trades::get_all_trades(client, handle).await?;
+    let (Some(first), Some(last)) = (all_trades.iter().map(|t| t.trade_date).min(), all_trades.iter().map(|t| t.trade_date).max()) else {
+        info!("no trades for {:?}, no nav", handle);
+        return Ok(())
+    };
+    let first = utils::to_date(first);
+    let all_prices = prices::get_all_prices(client, first - ChronoDuration::days(prices::MAX_STALE_DAYS), NaiveDate::MAX).await?;
+    // prices loaded for other funds don't stretch this one's run
+    let last = all_prices.iter()
+        .filter(|p| all_trades.iter().any(|t| t.security_ticker.eq_ignore_ascii_case(&p.security_id)))
+        .map(|p| p.price_date)
+        .max().unwrap_or(NaiveDate::MIN).max(utils::to_date(last));
+
+    let base_currency = fx::get_base_currency(client, handle).await?;
+    let rates = fx::get_fx_rates(client).await?;
+    let ledger = cash::get_ledger(client, handle).await?;
+    let actions = corporate_actions::get_corporate_actions(client).await?;
+    let shares = get_fund_shares(client, handle).await?;
+    let fees = get_fee_schedules(client, handle).await?;
+    let annual_fee_rate: f64 = fees.iter().map(|f| f.annual_rate).sum();
+
+    let mut prior: Option<NavRecord> = None;
+    let mut accrued_fees = 0.;
+
+    for nav_date in first.iter_days().take_while(|d| *d <= last) {
+        if matches!(nav_date.weekday(), Weekday::Sat | Weekday::Sun) {
+            continue
+        }
+        if prior.as_ref().map(|p| p.nav_date.month() != nav_date.month()).unwrap_or(false) {
+            accrued_fees = 0.;
+        }
+
+        let close = nav_date.and_hms_opt(23, 59, 59).unwrap().timestamp();
+        let held = positions::reconstruct(&all_trades, &actions, close);
+        let (mv, mut unpriced, mut conversions) = market_value(&held, &all_prices, &rates, &base_currency, nav_date);
+        // trade date accounting, so unsettled trades count at their projected cash
+        let mut cash = 0.;
+        for b in cash::balances(&ledger, nav_date) {
+            match fx::convert(&rates, b.projected, &b.currency, &base_currency, nav_date) {
+                Some(c) => {
+                    cash += c.converted;
+                    conversions.push((format!("{}/cash", b.account_name), c));
+                },
+                None => {
+                    warn!("no {}/{} rate for {:?} cash on {:?}", b.currency, base_currency, b.account_name, nav_date);
+                    unpriced += 1;
+                    cash += b.projected;
+                }
+            }
+        }
+        let days = prior.as_ref().map(|p| (nav_date - p.nav_date).num_days()).unwrap_or(1);
+        accrued_fees += (mv + cash) * annual_fee_rate / 365. * days as f64;
+        let shares_outstanding = shares.iter().rev().find(|s| s.as_of_date <= nav_date).map(|s| s.shares_outstanding).unwrap_or(0.);
+
+        let mut n = nav_for_day(handle, nav_date, mv, cash, accrued_fees, shares_outstanding, prior.as_ref());
+        n.base_currency = base_currency.clone();
+        n.unpriced = unpriced;
+        if n.flagged {
+            warn!("{:?} nav moved {:.2}% on {:?}", handle, n.daily_return * 100., nav_date);
+        }
+        info!("{:?}", n);
+        insert_nav_record(client, &n).await?;
+        for (reference, c) in conversions.iter().filter(|(_, c)| c.from_currency != c.to_currency) {
+            fx::insert_fx_audit(client, handle, "nav", reference, c).await?;
+        }
+        prior = Some(n);
+    }
+
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn nav_for_day_flags_big_moves() {
+        let d = NaiveDate::from_ymd_opt(2019, 11, 4).unwrap();
+        let first = nav_for_day("rivernorth", d, 1000., 100., 10., 100., None);
+        assert_eq!((first.nav, first.nav_per_share, first.daily_return, first.flagged), (1090., 10.9, 0., false));
+
+        let second = nav_for_day("rivernorth", d.succ_opt().unwrap(), 1100., 100., 10., 100., Some(&first));
+        assert!((second.daily_return - 1190. / 1090. + 1.).abs() < 1e-12);
+        assert!(second.flagged);
+    }
+
+    #[test]
+    fn flat_positions_are_not_unpriced() {
+        let d = NaiveDate::from_ymd_opt(2019, 11, 4).unwrap();
+        let position = |security_ticker: &str, quantity: f64| Position {
+            handle: "rivernorth".to_string(),
+            account_name: "RN1".to_string(),
+            security_ticker: security_ticker.to_string(),
+            cusip: "".to_string(),
+            currency: "USD".to_string(),
+            quantity,
+            last_price: 10.,
+            as_of_date: 0
+        };
+        let (mv, unpriced, _) = market_value(&[position("OPP", 100.), position("GONE", 0.)], &[], &[], "USD", d);
+        assert_eq!((mv, unpriced), (1000., 1));
+    }
+}
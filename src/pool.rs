@@ -0,0 +1,176 @@
+use crate::commissions::{self, CommissionSummary, CommissionTarget};
+use crate::corporate_actions::{self, CorporateAction};
+use crate::error::Error;
+use crate::fx::{self, Conversion, FxRate};
+use crate::hierarchy::{self, Hierarchy};
+use crate::rivernorth;
+use crate::securities::{self, SecurityMaster};
+use crate::store::{SummaryStore, TradeStore};
+use crate::trades::{self, AccountSummary, ChainRun, FileSummary, HierarchySummary, SecuritySummary, Trade, TradeChain};
+use crate::tx_types::{self, TxTypeMap};
+use async_trait::async_trait;
+use deadpool_postgres::{Manager, ManagerConfig, Pool, RecyclingMethod, Runtime};
+use std::time::Duration;
+use tokio_postgres::NoTls;
+
+/// How many connections a pool keeps and how long to wait on each step of getting one.
+#[derive(Clone, Debug)]
+pub struct PoolConfig {
+    pub size: usize,
+    /// waiting for a connection to come free when they're all out
+    pub wait_timeout: Duration,
+    /// opening a new connection
+    pub connect_timeout: Duration,
+    /// checking a returned connection still works
+    pub recycle_timeout: Duration
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        PoolConfig {
+            size: 8,
+            wait_timeout: Duration::from_secs(30),
+            connect_timeout: Duration::from_secs(10),
+            recycle_timeout: Duration::from_secs(5)
+        }
+    }
+}
+
+/// Nothing connects until the first `get`, so a bad host shows up there rather than here.
+pub fn connect(conn: &str, config: &PoolConfig) -> Result<Pool, Error> {
+    let pg_config: tokio_postgres::Config = conn.parse()?;
+    let manager = Manager::from_config(pg_config, NoTls, ManagerConfig { recycling_method: RecyclingMethod::Fast });
+    Pool::builder(manager)
+        .max_size(config.size)
+        .wait_timeout(Some(config.wait_timeout))
+        .create_timeout(Some(config.connect_timeout))
+        .recycle_timeout(Some(config.recycle_timeout))
+        .runtime(Runtime::Tokio1)
+        .build()
+        .map_err(|e| Error::Validation(format!("couldn't set up the connection pool: {}", e)))
+}
+
+#[async_trait]
+impl TradeStore for Pool {
+    async fn insert_trade(&self, trade: &Trade) -> Result<(), Error> {
+        let client = self.get().await?;
+        rivernorth::insert_trade(&client, trade).await
+    }
+
+    async fn insert_trades(&self, trades: &[Trade]) -> Result<(), Error> {
+        let mut client = self.get().await?;
+        rivernorth::insert_trades(&mut client, trades).await
+    }
+
+    async fn get_all_trades(&self, handle: &str) -> Result<Vec<Trade>, Error> {
+        let client = self.get().await?;
+        trades::get_all_trades(&client, handle).await
+    }
+
+    async fn get_security_master(&self) -> Result<SecurityMaster, Error> {
+        let client = self.get().await?;
+        securities::get_security_master(&client).await
+    }
+
+    async fn get_tx_type_map(&self, source: &str) -> Result<TxTypeMap, Error> {
+        let client = self.get().await?;
+        tx_types::get_tx_type_map(&client, source).await
+    }
+
+    async fn get_hierarchy(&self) -> Result<Hierarchy, Error> {
+        let client = self.get().await?;
+        hierarchy::get_hierarchy(&client).await
+    }
+
+    async fn get_corporate_actions(&self) -> Result<Vec<CorporateAction>, Error> {
+        let client = self.get().await?;
+        corporate_actions::get_corporate_actions(&client).await
+    }
+
+    async fn get_fx_rates(&self) -> Result<Vec<FxRate>, Error> {
+        let client = self.get().await?;
+        fx::get_fx_rates(&client).await
+    }
+
+    async fn get_base_currency(&self, handle: &str) -> Result<String, Error> {
+        let client = self.get().await?;
+        fx::get_base_currency(&client, handle).await
+    }
+
+    async fn insert_fx_audit(&self, handle: &str, context: &str, reference: &str, c: &Conversion) -> Result<(), Error> {
+        let client = self.get().await?;
+        fx::insert_fx_audit(&client, handle, context, reference, c).await
+    }
+
+    async fn get_commission_targets(&self, handle: &str) -> Result<Vec<CommissionTarget>, Error> {
+        let client = self.get().await?;
+        commissions::get_commission_targets(&client, handle).await
+    }
+}
+
+#[async_trait]
+impl SummaryStore for Pool {
+    async fn insert_file_summary(&self, s: &FileSummary) -> Result<(), Error> {
+        let client = self.get().await?;
+        trades::insert_file_summary(&client, s).await
+    }
+
+    async fn insert_account_summary(&self, s: &AccountSummary) -> Result<(), Error> {
+        let client = self.get().await?;
+        trades::insert_account_summary(&client, s).await
+    }
+
+    async fn insert_security_summary(&self, s: &SecuritySummary) -> Result<(), Error> {
+        let client = self.get().await?;
+        trades::insert_security_summary(&client, s).await
+    }
+
+    async fn insert_hierarchy_summary(&self, s: &HierarchySummary) -> Result<(), Error> {
+        let client = self.get().await?;
+        trades::insert_hierarchy_summary(&client, s).await
+    }
+
+    async fn insert_commission_summary(&self, s: &CommissionSummary) -> Result<(), Error> {
+        let client = self.get().await?;
+        commissions::insert_commission_summary(&client, s).await
+    }
+
+    /// The whole swap runs on the one connection so it's all the one transaction.
+    async fn replace_chains(&self, handle: &str, chains: &[TradeChain], run: &ChainRun) -> Result<i32, Error> {
+        let mut client = self.get().await?;
+        trades::write_chains(&mut client, handle, chains, None, run).await
+    }
+
+    async fn update_chains(&self, handle: &str, chains: &[TradeChain], removed: &[String], run: &ChainRun) -> Result<i32, Error> {
+        let mut client = self.get().await?;
+        trades::write_chains(&mut client, handle, chains, Some(removed), run).await
+    }
+
+    async fn get_chains(&self, handle: &str) -> Result<Vec<TradeChain>, Error> {
+        let client = self.get().await?;
+        trades::get_chains(&client, handle).await
+    }
+
+    async fn get_last_chain_run(&self, handle: &str) -> Result<Option<ChainRun>, Error> {
+        let client = self.get().await?;
+        trades::get_last_chain_run(&client, handle).await
+    }
+
+    async fn publish_chains(&self, handle: &str, chains: &[TradeChain]) -> Result<(), Error> {
+        let mut client = self.get().await?;
+        trades::publish_chains(&mut client, handle, chains).await
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn bad_connection_strings_fail_up_front() {
+        assert!(connect("host=localhost user=tradellama", &PoolConfig::default()).is_ok());
+        assert_eq!(connect("host=localhost port=notaport", &PoolConfig::default()).unwrap_err().exit_code(), 5);
+    }
+}
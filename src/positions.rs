@@ -0,0 +1,60 @@
+use crate::corporate_actions::{self, CorporateAction};
+use crate::trades::Trade;
+use crate::utils;
+use serde::{Serialize,Deserialize};
+use std::collections::BTreeMap;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Position {
+    pub handle: String,
+    pub account_name: String,
+    pub security_ticker: String,
+    pub cusip: String,
+    pub currency: String,
+    pub quantity: f64,
+    pub last_price: f64,
+    pub as_of_date: i64
+}
+
+/// Files don't agree on whether sells come through negative, so the tx type decides, transfers keep the file's sign.
+pub fn signed_quantity(trade: &Trade) -> f64 {
+    match trade.tx().quantity_sign() {
+        Some(sign) => sign * trade.quantity.abs(),
+        None => trade.quantity
+    }
+}
+
+/// Rolls trades up into account / ticker positions as of a point in time, trades after as_of_date are ignored.
+/// Dividends, fees and the like ride along in the same files but don't move the position.
+pub fn derive_positions(trades: &[Trade], as_of_date: i64) -> Vec<Position> {
+
+    let mut g: BTreeMap<(String,String), Position> = BTreeMap::new();
+
+    for t in trades.iter().filter(|x| x.trade_date <= as_of_date && x.tx().moves_position()) {
+        let p = g.entry((t.account_name.to_uppercase(), t.security_ticker.to_uppercase())).or_insert(Position {
+            handle: t.handle.clone(),
+            account_name: t.account_name.to_uppercase(),
+            security_ticker: t.security_ticker.to_uppercase(),
+            cusip: t.cusip.clone(),
+            currency: t.currency.clone(),
+            quantity: 0.,
+            last_price: 0.,
+            as_of_date
+        });
+        p.quantity += signed_quantity(t);
+        p.last_price = t.price.abs();
+    }
+
+    g.into_values().collect()
+}
+
+/// Positions as of a date after the corporate actions effective by then, which is what a statement for that date shows.
+pub fn reconstruct(trades: &[Trade], actions: &[CorporateAction], as_of_date: i64) -> Vec<Position> {
+
+    let effective: Vec<CorporateAction> = actions.iter().filter(|a| a.effective_date <= utils::to_date(as_of_date)).cloned().collect();
+    let mut adjusted = corporate_actions::apply(trades, &effective);
+    let events = corporate_actions::position_events(&adjusted, &effective);
+    adjusted.extend(events);
+
+    derive_positions(&adjusted, as_of_date)
+}
@@ -0,0 +1,391 @@
+use crate::error::{self, Error};
+use crate::rivernorth;
+use crate::utils;
+use chrono::{NaiveDate, Duration as ChronoDuration};
+use calamine::{Reader, open_workbook, Xlsx, DataType};
+use serde::{Serialize,Deserialize};
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+use tokio_postgres::Row;
+use tracing::info;
+
+/// how far back best_mark will go for a stale price before giving up
+pub const MAX_STALE_DAYS: i64 = 5;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PriceType {
+    Close,
+    Bid,
+    Ask,
+    Nav,
+    /// the day's volume weighted average, a benchmark for executions and never picked as a mark
+    Vwap
+}
+
+impl fmt::Display for PriceType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PriceType::Close => write!(f, "CLOSE"),
+            PriceType::Bid => write!(f, "BID"),
+            PriceType::Ask => write!(f, "ASK"),
+            PriceType::Nav => write!(f, "NAV"),
+            PriceType::Vwap => write!(f, "VWAP"),
+        }
+    }
+}
+
+impl FromStr for PriceType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_uppercase().as_ref() {
+            "CLOSE" | "LAST" => Ok(PriceType::Close),
+            "BID" => Ok(PriceType::Bid),
+            "ASK" | "OFFER" => Ok(PriceType::Ask),
+            "NAV" => Ok(PriceType::Nav),
+            "VWAP" => Ok(PriceType::Vwap),
+            other => Err(format!("unknown price type {}", other))
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Price {
+    pub security_id: String,
+    pub price_date: NaiveDate,
+    pub source: String,
+    pub price_type: PriceType,
+    pub price: f64
+}
+
+impl From<Row> for Price {
+    fn from(row: tokio_postgres::Row) -> Self {
+        let price_type: String = row.get("price_type");
+        Self {
+            security_id: row.get("security_id"),
+            price_date: row.get("price_date"),
+            source: row.get("source"),
+            price_type: price_type.parse().unwrap_or(PriceType::Close),
+            price: row.get("price"),
+        }
+    }
+}
+
+/// The price picked for a security on a date, and how it was picked.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Mark {
+    pub security_id: String,
+    pub as_of_date: NaiveDate,
+    pub price_date: NaiveDate,
+    pub source: String,
+    pub price_type: String,
+    pub price: f64,
+    pub stale_days: i64
+}
+
+pub async fn build_prices_table(client: &tokio_postgres::Client) -> Result<(), Error> {
+
+    client.query("CREATE TABLE prices (id SERIAL PRIMARY KEY,
+        security_id VARCHAR NOT NULL,
+        price_date DATE NOT NULL,
+        source VARCHAR NOT NULL,
+        price_type VARCHAR NOT NULL,
+        price FLOAT8 NOT NULL,
+        UNIQUE (security_id, price_date, source, price_type)
+        )", &[]).await?;
+
+    Ok(())
+}
+
+pub async fn drop_prices_table(client: &tokio_postgres::Client) -> Result<(), Error> {
+
+    client.query("drop TABLE prices", &[]).await?;
+
+    Ok(())
+}
+
+/// Reloading a file replaces whatever that source said before for the same day.
+pub async fn insert_price(client: &tokio_postgres::Client, price: &Price) -> Result<(), Error> {
+
+    let statement = client.prepare("INSERT INTO prices (
+        security_id,
+        price_date,
+        source,
+        price_type,
+        price
+        ) VALUES ($1, $2, $3, $4, $5)
+        ON CONFLICT (security_id, price_date, source, price_type) DO UPDATE SET price = EXCLUDED.price").await?;
+
+    client.execute(&statement,&[
+        &price.security_id,
+        &price.price_date,
+        &price.source,
+        &price.price_type.to_string(),
+        &price.price
+        ]).await?;
+    Ok(())
+}
+
+pub async fn get_prices(client: &tokio_postgres::Client, security_id: &str, from: NaiveDate, to: NaiveDate) -> Result<Vec<Price>, Error> {
+
+    let rows = client.query("SELECT * FROM prices WHERE upper(security_id) = upper($1) AND price_date BETWEEN $2 AND $3 ORDER BY price_date",
+        &[&security_id, &from, &to]).await?;
+    Ok(rows.into_iter().map(Price::from).collect())
+}
+
+pub async fn get_all_prices(client: &tokio_postgres::Client, from: NaiveDate, to: NaiveDate) -> Result<Vec<Price>, Error> {
+
+    let rows = client.query("SELECT * FROM prices WHERE price_date BETWEEN $1 AND $2 ORDER BY price_date",
+        &[&from, &to]).await?;
+    Ok(rows.into_iter().map(Price::from).collect())
+}
+
+pub fn get_price_header(h: Option<&DataType>) -> String {
+
+    match h {
+        Some(dt) => header_name(&dt.to_string()),
+        _ => "NoBueno".to_string()
+    }
+}
+
+fn header_name(h: &str) -> String {
+    match h.trim().to_lowercase().replace([' ', '_'], "").as_ref() {
+        "securityid" | "symbol" | "ticker" | "securitysymbol" | "cusip" => "security_id".to_string(),
+        "date" | "pricedate" | "asofdate" => "price_date".to_string(),
+        "source" => "source".to_string(),
+        "pricetype" | "type" => "price_type".to_string(),
+        "price" | "mark" => "price".to_string(),
+        _ => "nomatch".to_string()
+    }
+}
+
+fn parse_date(s: &str) -> Option<NaiveDate> {
+    NaiveDate::parse_from_str(s.trim(), "%Y-%m-%d").ok()
+        .or_else(|| NaiveDate::parse_from_str(s.trim(), "%m/%d/%Y").ok())
+        .or_else(|| NaiveDate::parse_from_str(s.trim(), "%Y%m%d").ok())
+}
+
+fn date_at(ifile: &str, row: usize, s: &str) -> Result<NaiveDate, Error> {
+    parse_date(s).ok_or_else(|| Error::Parse {
+        file: ifile.to_string(),
+        row,
+        column: "price_date".to_string(),
+        message: format!("{:?} isn't a date", s)
+    })
+}
+
+fn required(ifile: &str, row: usize, column: &str, value: String) -> Result<String, Error> {
+    if value.is_empty() {
+        return Err(Error::Parse { file: ifile.to_string(), row, column: column.to_string(), message: "blank".to_string() });
+    }
+    Ok(value)
+}
+
+/// No price type column or a blank in it means a close, anything else has to be one we know.
+fn price_type_at(ifile: &str, row: usize, value: Option<&str>) -> Result<PriceType, Error> {
+    match value.map(|x| x.trim()) {
+        None | Some("") => Ok(PriceType::Close),
+        Some(x) => error::parse_field(ifile, row, "price_type", x)
+    }
+}
+
+/// Files without a source column are credited to the file they came from.
+fn default_source(ifile: &str) -> String {
+    Path::new(ifile).file_stem().and_then(|x| x.to_str()).unwrap_or(ifile).to_string()
+}
+
+pub fn read_csv(ifile: &str) -> Result<Vec<Price>, Error> {
+
+    let mut rdr = csv::Reader::from_path(ifile).map_err(|e| Error::csv(ifile, e))?;
+    let mapped_headers: Vec<String> = rdr.headers().map_err(|e| Error::csv(ifile, e))?.iter().map(header_name).collect();
+    let position = |name: &str| mapped_headers.iter().position(|x| x == name);
+
+    let (Some(idp), Some(dtp), Some(pp)) = (position("security_id"), position("price_date"), position("price")) else {
+        return Err(Error::schema(ifile, "missing one of security id, date or price"));
+    };
+    let source_position = position("source");
+    let price_type_position = position("price_type");
+
+    let mut v: Vec<Price> = Vec::new();
+    for record in rdr.records() {
+        let r = record.map_err(|e| Error::csv(ifile, e))?;
+        let row = r.position().map(|p| p.line() as usize).unwrap_or(0);
+        v.push(Price {
+            security_id: required(ifile, row, "security_id", r[idp].trim().to_uppercase())?,
+            price_date: date_at(ifile, row, &r[dtp])?,
+            source: source_position.map(|x| r[x].trim().to_string()).unwrap_or_else(|| default_source(ifile)),
+            price_type: price_type_at(ifile, row, price_type_position.map(|x| &r[x]))?,
+            price: error::parse_field(ifile, row, "price", &r[pp])?
+        });
+    }
+
+    Ok(v)
+}
+
+pub fn read_xlsx(ifile: &str) -> Result<Vec<Price>, Error> {
+
+    let mut workbook: Xlsx<_> = open_workbook(ifile).map_err(|e| Error::xlsx(ifile, e))?;
+    let mut v: Vec<Price> = Vec::new();
+
+    if let Some(Ok(range)) = workbook.worksheet_range("Sheet1") {
+        let idx_cap = range.get_size().1 as u32;
+        let mapped_headers: Vec<String> = rivernorth::get_mapped_headers(&range, idx_cap, get_price_header);
+        let position = |name: &str| mapped_headers.iter().position(|x| x == name);
+
+        let (Some(idp), Some(dtp), Some(pp)) = (position("security_id"), position("price_date"), position("price")) else {
+            return Err(Error::schema(ifile, "missing one of security id, date or price"));
+        };
+        let source_position = position("source");
+        let price_type_position = position("price_type");
+
+        for (i, r) in range.rows().enumerate().skip(1) {
+            let price_date = match &r[dtp] {
+                DataType::String(s) => date_at(ifile, i + 1, s)?,
+                other => rivernorth::excel_date(other).map(|x| x.date()).ok_or_else(|| Error::Parse {
+                    file: ifile.to_string(),
+                    row: i + 1,
+                    column: "price_date".to_string(),
+                    message: format!("{:?} isn't a date", other)
+                })?
+            };
+            let price_type = price_type_position.map(|x| rivernorth::excel_string_at(ifile, i, "price_type", &r[x], false)).transpose()?;
+            v.push(Price {
+                security_id: rivernorth::excel_string_at(ifile, i, "security_id", &r[idp], true)?.to_uppercase(),
+                price_date,
+                source: source_position.map(|x| rivernorth::excel_string_at(ifile, i, "source", &r[x], false)).transpose()?
+                    .filter(|x| !x.is_empty()).unwrap_or_else(|| default_source(ifile)),
+                price_type: price_type_at(ifile, i + 1, price_type.as_deref())?,
+                price: rivernorth::excel_float_at(ifile, i, "price", &r[pp], true)?
+            });
+        }
+    }
+
+    Ok(v)
+}
+
+pub async fn load(client: &tokio_postgres::Client) -> Result<(), Error> {
+    let ifiles = vec!["/tmp/prices.csv","/tmp/prices.xlsx"];
+
+    for ifile in ifiles {
+        let prices = if ifile.ends_with(".csv") { read_csv(ifile)? } else { read_xlsx(ifile)? };
+        info!("{:?} has {:?} prices, hash {:?}", ifile, prices.len(), utils::sha_fmt(ifile).unwrap_or("failedhash".to_string()));
+        for p in &prices {
+            insert_price(client, p).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Fallback rules for a mark, first hit wins:
+/// on the latest date within MAX_STALE_DAYS that has anything,
+/// a close, then a NAV, then the bid/ask midpoint, then whichever of bid or ask there is.
+/// When sources disagree the preferred sources win in the order given, then anything else alphabetically.
+pub fn best_mark(prices: &[Price], security_id: &str, as_of_date: NaiveDate, preferred_sources: &[&str]) -> Option<Mark> {
+
+    let oldest = as_of_date - ChronoDuration::days(MAX_STALE_DAYS);
+    let candidates: Vec<&Price> = prices.iter()
+        .filter(|p| p.security_id.eq_ignore_ascii_case(security_id) && p.price_date <= as_of_date && p.price_date >= oldest && p.price_type != PriceType::Vwap)
+        .collect();
+
+    let price_date = candidates.iter().map(|p| p.price_date).max()?;
+
+    let source_rank = |s: &str| preferred_sources.iter().position(|x| x.eq_ignore_ascii_case(s)).unwrap_or(preferred_sources.len());
+    let mut sources: Vec<&str> = candidates.iter().filter(|p| p.price_date == price_date).map(|p| p.source.as_str()).collect();
+    sources.sort_by(|a, b| source_rank(a).cmp(&source_rank(b)).then(a.cmp(b)));
+    sources.dedup();
+
+    let find = |source: &str, price_type: PriceType| candidates.iter()
+        .find(|p| p.price_date == price_date && p.source == source && p.price_type == price_type)
+        .map(|p| p.price);
+    let mark = |source: &str, price_type: &str, price: f64| Mark {
+        security_id: security_id.to_uppercase(),
+        as_of_date,
+        price_date,
+        source: source.to_string(),
+        price_type: price_type.to_string(),
+        price,
+        stale_days: (as_of_date - price_date).num_days()
+    };
+
+    for price_type in [PriceType::Close, PriceType::Nav] {
+        for source in &sources {
+            if let Some(price) = find(source, price_type) {
+                return Some(mark(source, &price_type.to_string(), price));
+            }
+        }
+    }
+    for source in &sources {
+        if let (Some(bid), Some(ask)) = (find(source, PriceType::Bid), find(source, PriceType::Ask)) {
+            return Some(mark(source, "MID", (bid + ask) / 2.));
+        }
+    }
+    for source in &sources {
+        for price_type in [PriceType::Bid, PriceType::Ask] {
+            if let Some(price) = find(source, price_type) {
+                return Some(mark(source, &price_type.to_string(), price));
+            }
+        }
+    }
+
+    None
+}
+
+pub async fn get_best_mark(client: &tokio_postgres::Client, security_id: &str, as_of_date: NaiveDate, preferred_sources: &[&str]) -> Result<Option<Mark>, Error> {
+
+    let prices = get_prices(client, security_id, as_of_date - ChronoDuration::days(MAX_STALE_DAYS), as_of_date).await?;
+    Ok(best_mark(&prices, security_id, as_of_date, preferred_sources))
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn price(day: u32, source: &str, price_type: PriceType, price: f64) -> Price {
+        Price {
+            security_id: "ABC".to_string(),
+            price_date: NaiveDate::from_ymd_opt(2019, 11, day).unwrap(),
+            source: source.to_string(),
+            price_type,
+            price
+        }
+    }
+
+    #[test]
+    fn best_mark_falls_back() {
+        let prices = vec![
+            price(1, "admin", PriceType::Close, 10.),
+            price(4, "broker", PriceType::Bid, 11.),
+            price(4, "broker", PriceType::Ask, 12.),
+            price(4, "admin", PriceType::Nav, 11.8),
+        ];
+
+        let m = best_mark(&prices, "abc", NaiveDate::from_ymd_opt(2019, 11, 5).unwrap(), &[]).unwrap();
+        assert_eq!((m.price, m.price_type.as_str(), m.stale_days), (11.8, "NAV", 1));
+
+        let m = best_mark(&prices[..3], "abc", NaiveDate::from_ymd_opt(2019, 11, 4).unwrap(), &[]).unwrap();
+        assert_eq!((m.price, m.price_type.as_str()), (11.5, "MID"));
+
+        let m = best_mark(&prices, "abc", NaiveDate::from_ymd_opt(2019, 11, 3).unwrap(), &[]).unwrap();
+        assert_eq!((m.price, m.stale_days), (10., 2));
+
+        assert!(best_mark(&prices, "abc", NaiveDate::from_ymd_opt(2019, 11, 20).unwrap(), &[]).is_none());
+    }
+
+    #[test]
+    fn unreadable_cells_say_where() {
+        let ifile = std::env::temp_dir().join("nav_unreadable_prices.csv");
+        std::fs::write(&ifile, "symbol,date,price\nABC,2019-11-01,10\nABC,2019-11-04,ten\n").unwrap();
+        let e = read_csv(ifile.to_str().unwrap()).unwrap_err();
+        assert!(matches!(e, Error::Parse { row: 3, ref column, .. } if column == "price"), "{}", e);
+
+        let e = rivernorth::excel_float_at("/tmp/2019-11.xlsx", 4, "quantity", &DataType::String("1O0".to_string()), false).unwrap_err();
+        assert!(e.to_string().starts_with("/tmp/2019-11.xlsx row 5 column quantity:"));
+        assert_eq!(rivernorth::excel_float_at("/tmp/2019-11.xlsx", 4, "fee", &DataType::Empty, false).unwrap(), 0.);
+        assert!(rivernorth::excel_string_at("/tmp/2019-11.xlsx", 4, "tx_type", &DataType::Empty, true).is_err());
+        assert_eq!(rivernorth::excel_string_at("/tmp/2019-11.xlsx", 4, "cusip", &DataType::Float(123456.), false).unwrap(), "123456");
+    }
+}
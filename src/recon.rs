@@ -0,0 +1,390 @@
+use crate::corporate_actions;
+use crate::error::Error;
+use crate::positions::{self, Position};
+use crate::prices;
+use crate::trades;
+use crate::utils;
+use serde::{Serialize,Deserialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use tokio_postgres::Row;
+use tracing::info;
+
+/// quantities within this of each other are the same position
+pub const QUANTITY_TOLERANCE: f64 = 0.0001;
+/// prices are compared relative to the statement, half a percent
+pub const PRICE_TOLERANCE: f64 = 0.005;
+
+/// A position line from an administrator holdings file.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Holding {
+    pub id: Option<i32>,
+    pub handle: String,
+    pub filename: String,
+    pub filehash: String,
+    pub row: i32,
+    pub account_name: String,
+    pub security_ticker: String,
+    pub cusip: String,
+    pub security_description: String,
+    pub quantity: f64,
+    pub price: f64,
+    pub market_value: f64,
+    pub as_of_date: i64
+}
+
+impl From<Row> for Holding {
+    fn from(row: tokio_postgres::Row) -> Self {
+        Self {
+            id: Some(row.get("id")),
+            handle: row.get("handle"),
+            filename: row.get("filename"),
+            filehash: row.get("filehash"),
+            row: row.get("row"),
+            account_name: row.get("account_name"),
+            security_ticker: row.get("security_ticker"),
+            cusip: row.get("cusip"),
+            security_description: row.get("security_description"),
+            quantity: row.get("quantity"),
+            price: row.get("price"),
+            market_value: row.get("market_value"),
+            as_of_date: row.get("as_of_date"),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum BreakType {
+    QuantityMismatch,
+    MissingSecurity,
+    PriceMismatch
+}
+
+impl fmt::Display for BreakType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BreakType::QuantityMismatch => write!(f, "QUANTITY_MISMATCH"),
+            BreakType::MissingSecurity => write!(f, "MISSING_SECURITY"),
+            BreakType::PriceMismatch => write!(f, "PRICE_MISMATCH"),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ReconBreak {
+    pub handle: String,
+    pub account_name: String,
+    pub security_ticker: String,
+    pub break_type: BreakType,
+    pub statement_value: f64,
+    pub derived_value: f64,
+    pub difference: f64,
+    pub as_of_date: i64,
+    pub first_seen: i64,
+    pub age_days: i64
+}
+
+impl ReconBreak {
+    fn new(handle: &str, key: &(String,String), break_type: BreakType, statement_value: f64, derived_value: f64, as_of_date: i64) -> Self {
+        Self {
+            handle: handle.to_string(),
+            account_name: key.0.clone(),
+            security_ticker: key.1.clone(),
+            break_type,
+            statement_value,
+            derived_value,
+            difference: statement_value - derived_value,
+            as_of_date,
+            first_seen: as_of_date,
+            age_days: 0
+        }
+    }
+}
+
+pub async fn build_holdings_table(client: &tokio_postgres::Client) -> Result<(), Error> {
+
+    client.query("CREATE TABLE holdings (id SERIAL PRIMARY KEY,
+        handle VARCHAR NOT NULL,
+        filename VARCHAR NOT NULL,
+        filehash VARCHAR NOT NULL,
+        row INT NOT NULL,
+        account_name VARCHAR NOT NULL,
+        security_ticker VARCHAR NOT NULL,
+        cusip VARCHAR NOT NULL,
+        security_description VARCHAR NOT NULL,
+        quantity FLOAT8 NOT NULL,
+        price FLOAT8 NOT NULL,
+        market_value FLOAT8 NOT NULL,
+        as_of_date BIGINT NOT NULL
+        )", &[]).await?;
+
+    Ok(())
+}
+
+pub async fn drop_holdings_table(client: &tokio_postgres::Client) -> Result<(), Error> {
+
+    client.query("drop TABLE holdings", &[]).await?;
+
+    Ok(())
+}
+
+pub async fn build_recon_breaks_table(client: &tokio_postgres::Client) -> Result<(), Error> {
+
+    client.query("CREATE TABLE recon_breaks (id SERIAL PRIMARY KEY,
+        handle VARCHAR NOT NULL,
+        account_name VARCHAR NOT NULL,
+        security_ticker VARCHAR NOT NULL,
+        break_type VARCHAR NOT NULL,
+        statement_value FLOAT8 NOT NULL,
+        derived_value FLOAT8 NOT NULL,
+        difference FLOAT8 NOT NULL,
+        as_of_date BIGINT NOT NULL,
+        first_seen BIGINT NOT NULL,
+        age_days BIGINT NOT NULL
+        )", &[]).await?;
+
+    Ok(())
+}
+
+pub async fn drop_recon_breaks_table(client: &tokio_postgres::Client) -> Result<(), Error> {
+
+    client.query("drop TABLE recon_breaks", &[]).await?;
+
+    Ok(())
+}
+
+pub async fn insert_holding(client: &tokio_postgres::Client, holding: &Holding) -> Result<(), Error> {
+
+    let statement = client.prepare("INSERT INTO holdings (
+        handle,
+        filename,
+        filehash,
+        row,
+        account_name,
+        security_ticker,
+        cusip,
+        security_description,
+        quantity,
+        price,
+        market_value,
+        as_of_date
+        ) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12)").await?;
+
+    client.execute(&statement,&[
+        &holding.handle,
+        &holding.filename,
+        &holding.filehash,
+        &holding.row,
+        &holding.account_name,
+        &holding.security_ticker,
+        &holding.cusip,
+        &holding.security_description,
+        &holding.quantity,
+        &holding.price,
+        &holding.market_value,
+        &holding.as_of_date
+        ]).await?;
+    Ok(())
+}
+
+pub async fn get_all_holdings(client: &tokio_postgres::Client, handle: &str) -> Result<Vec<Holding>, Error> {
+
+    let rows = client.query("SELECT * FROM holdings WHERE handle = $1 ORDER BY as_of_date, id", &[&handle]).await?;
+    Ok(rows.into_iter().map(Holding::from).collect())
+}
+
+async fn insert_recon_break(client: &tokio_postgres::Client, b: &ReconBreak) -> Result<(), Error> {
+
+    let statement = client.prepare("INSERT INTO recon_breaks (
+        handle,
+        account_name,
+        security_ticker,
+        break_type,
+        statement_value,
+        derived_value,
+        difference,
+        as_of_date,
+        first_seen,
+        age_days
+        ) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)").await?;
+
+    client.execute(&statement,&[
+        &b.handle,
+        &b.account_name,
+        &b.security_ticker,
+        &b.break_type.to_string(),
+        &b.statement_value,
+        &b.derived_value,
+        &b.difference,
+        &b.as_of_date,
+        &b.first_seen,
+        &b.age_days
+        ]).await?;
+    Ok(())
+}
+
+async fn clean_recon_breaks(client: &tokio_postgres::Client, handle: &str, as_of_date: i64) -> Result<(), Error> {
+
+    let statement = client.prepare("delete from recon_breaks where handle = $1 and as_of_date = $2").await?;
+    client.execute(&statement,&[&handle, &as_of_date]).await?;
+
+    Ok(())
+}
+
+/// The break is aged from the first statement it showed up on, as long as it was still there on the statement before this one.
+/// previous is that statement's date, a break that cleared on it starts over.
+async fn get_first_seen(client: &tokio_postgres::Client, b: &ReconBreak, previous: Option<i64>) -> Result<Option<i64>, Error> {
+
+    let Some(previous) = previous else {
+        return Ok(None)
+    };
+    let rows = client.query("SELECT first_seen FROM recon_breaks WHERE handle = $1 AND account_name = $2 AND security_ticker = $3 AND break_type = $4
+        AND as_of_date = $5",
+        &[&b.handle, &b.account_name, &b.security_ticker, &b.break_type.to_string(), &previous]).await?;
+
+    Ok(rows.first().map(|r| r.get("first_seen")))
+}
+
+/// Compares a statement against positions derived from trades for the same date.
+/// A statement can split one position over several lots, those get added up and priced at their market value over quantity.
+pub fn reconcile(handle: &str, holdings: &[Holding], positions: &[Position], as_of_date: i64) -> Vec<ReconBreak> {
+
+    let mut statement: BTreeMap<(String,String), Holding> = BTreeMap::new();
+    for h in holdings {
+        statement.entry((h.account_name.to_uppercase(), h.security_ticker.to_uppercase()))
+            .and_modify(|x| {
+                x.quantity += h.quantity;
+                x.market_value += h.market_value;
+                if x.quantity.abs() > QUANTITY_TOLERANCE {
+                    x.price = x.market_value / x.quantity;
+                }
+            })
+            .or_insert_with(|| h.clone());
+    }
+    let derived: BTreeMap<(String,String), &Position> = positions.iter()
+        .filter(|p| p.quantity.abs() > QUANTITY_TOLERANCE)
+        .map(|p| ((p.account_name.to_uppercase(), p.security_ticker.to_uppercase()), p))
+        .collect();
+
+    let keys: BTreeSet<&(String,String)> = statement.keys().chain(derived.keys()).collect();
+    let mut breaks: Vec<ReconBreak> = Vec::new();
+
+    for k in keys {
+        match (statement.get(k), derived.get(k)) {
+            (Some(h), Some(p)) => {
+                if (h.quantity - p.quantity).abs() > QUANTITY_TOLERANCE {
+                    breaks.push(ReconBreak::new(handle, k, BreakType::QuantityMismatch, h.quantity, p.quantity, as_of_date));
+                }
+                if h.price != 0. && ((h.price - p.last_price) / h.price).abs() > PRICE_TOLERANCE {
+                    breaks.push(ReconBreak::new(handle, k, BreakType::PriceMismatch, h.price, p.last_price, as_of_date));
+                }
+            },
+            (Some(h), None) => breaks.push(ReconBreak::new(handle, k, BreakType::MissingSecurity, h.quantity, 0., as_of_date)),
+            (None, Some(p)) => breaks.push(ReconBreak::new(handle, k, BreakType::MissingSecurity, 0., p.quantity, as_of_date)),
+            (None, None) => {}
+        }
+    }
+
+    breaks
+}
+
+pub async fn recon(client: &tokio_postgres::Client, handle: &str) -> Result<(), Error> {
+
+    let all_trades = trades::get_all_trades(client, handle).await?;
+    let all_holdings = get_all_holdings(client, handle).await?;
+    let actions = corporate_actions::get_corporate_actions(client).await?;
+
+    let as_of_dates: BTreeSet<i64> = all_holdings.iter().map(|x| x.as_of_date).collect();
+    let mut previous: Option<i64> = None;
+
+    for as_of_date in as_of_dates {
+        info!("reconciling {:?} as of {:?}", handle, as_of_date);
+        clean_recon_breaks(client, handle, as_of_date).await?;
+
+        let statement: Vec<Holding> = all_holdings.iter().filter(|x| x.as_of_date == as_of_date).cloned().collect();
+        let mut derived = positions::reconstruct(&all_trades, &actions, as_of_date);
+        // price off the marks where we have them, the last execution is only a fallback
+        for p in derived.iter_mut() {
+            if let Some(m) = prices::get_best_mark(client, &p.security_ticker, utils::to_date(as_of_date), &[]).await? {
+                p.last_price = m.price;
+            }
+        }
+
+        for mut b in reconcile(handle, &statement, &derived, as_of_date) {
+            if let Some(first_seen) = get_first_seen(client, &b, previous).await? {
+                b.first_seen = first_seen;
+            }
+            b.age_days = (b.as_of_date - b.first_seen) / 86400;
+            info!("{:?}", b);
+            insert_recon_break(client, &b).await?;
+        }
+        previous = Some(as_of_date);
+    }
+
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn holding(ticker: &str, quantity: f64, price: f64) -> Holding {
+        Holding {
+            id: None,
+            handle: "rivernorth".to_string(),
+            filename: "holdings.xlsx".to_string(),
+            filehash: "hash".to_string(),
+            row: 1,
+            account_name: "RN1".to_string(),
+            security_ticker: ticker.to_string(),
+            cusip: "".to_string(),
+            security_description: "".to_string(),
+            quantity,
+            price,
+            market_value: quantity * price,
+            as_of_date: 0
+        }
+    }
+
+    fn position(ticker: &str, quantity: f64, last_price: f64) -> Position {
+        Position {
+            handle: "rivernorth".to_string(),
+            account_name: "RN1".to_string(),
+            security_ticker: ticker.to_string(),
+            cusip: "".to_string(),
+            currency: "USD".to_string(),
+            quantity,
+            last_price,
+            as_of_date: 0
+        }
+    }
+
+    #[test]
+    fn reconcile_finds_each_break_type() {
+        let holdings = vec![holding("ABC", 100., 10.), holding("DEF", 50., 20.), holding("GHI", 10., 5.)];
+        let positions = vec![position("ABC", 100., 10.01), position("DEF", 40., 25.), position("JKL", 7., 1.)];
+
+        let breaks = reconcile("rivernorth", &holdings, &positions, 0);
+        let found: Vec<(&str, BreakType)> = breaks.iter().map(|b| (b.security_ticker.as_str(), b.break_type)).collect();
+
+        assert_eq!(found, vec![
+            ("DEF", BreakType::QuantityMismatch),
+            ("DEF", BreakType::PriceMismatch),
+            ("GHI", BreakType::MissingSecurity),
+            ("JKL", BreakType::MissingSecurity),
+        ]);
+        assert_eq!(breaks[0].difference, 10.);
+    }
+
+    #[test]
+    fn reconcile_adds_up_lots() {
+        let holdings = vec![holding("ABC", 60., 10.), holding("ABC", 40., 10.)];
+        let positions = vec![position("ABC", 100., 10.)];
+        assert!(reconcile("rivernorth", &holdings, &positions, 0).is_empty());
+
+        let breaks = reconcile("rivernorth", &holdings, &[position("ABC", 60., 10.)], 0);
+        assert_eq!(breaks.iter().map(|b| (b.break_type, b.statement_value)).collect::<Vec<_>>(), vec![(BreakType::QuantityMismatch, 100.)]);
+    }
+}
@@ -1,19 +1,33 @@
-use crate::*;
-use std::error::Error;
+use crate::error::{self, Error};
+use crate::hierarchy::Hierarchy;
+use crate::securities::SecurityMaster;
+use crate::store::TradeStore;
+use crate::tx_types::TxTypeMap;
+use crate::{recon, trades, utils};
 
 use itertools::Itertools;
-use calamine::{Reader, open_workbook, Xlsx, DataType};
+use calamine::{Reader, open_workbook, Xlsx, DataType, Range};
 use chrono::{NaiveDateTime, NaiveDate, Duration as ChronoDuration};
+use futures::future::try_join_all;
+use std::sync::Arc;
+use tokio_postgres::{GenericClient, Statement};
+use tracing::info;
 
 
 
+/// What every activity file has to have, currency is the only optional one.
+const TRADE_COLUMNS: [&str; 17] = ["account_name", "account_type", "tx_type", "security_ticker", "cusip", "security_description", "trade_date", "quantity",
+    "price", "principal", "commission", "fee", "net_amount", "settlement_date", "security_type", "broker", "trader"];
+
+const HOLDING_COLUMNS: [&str; 8] = ["account_name", "security_ticker", "cusip", "security_description", "quantity", "price", "market_value", "as_of_date"];
+
 pub fn get_header(h: Option<&DataType>) -> String{
 
 	match h {
 		Some(dt) => {
-			match dt.get_string().expect("NEILISDUMB").to_lowercase().as_ref() {
+			match dt.to_string().to_lowercase().as_ref() {
 				"portfolioaccountnumber" => "account_name".to_string(),
-				"portfolioaccounttype" => "account_number".to_string(),
+				"portfolioaccounttype" => "account_type".to_string(),
 				"activity" => "tx_type".to_string(),
 				"securitysymbol" => "security_ticker".to_string(),
 				"cusip" => "cusip".to_string(),
@@ -29,6 +43,8 @@ pub fn get_header(h: Option<&DataType>) -> String{
 				"securitytype" => "security_type".to_string(),
 				"broker" => "broker".to_string(),
 				"trader" => "trader".to_string(),
+				"currency" => "currency".to_string(),
+				"settlementcurrency" => "currency".to_string(),
 				_ => "nomatch".to_string()
 			}
 		},
@@ -39,20 +55,138 @@ pub fn get_header(h: Option<&DataType>) -> String{
 
 }
 
-pub async fn insert_trade(client: &tokio_postgres::Client, trade: &trades::Trade) -> Result<(), Box<dyn Error>> {
-    info!("{:?}, {:?}", client, trade);
-    let statement = client.prepare("INSERT INTO trades (
+pub fn get_holding_header(h: Option<&DataType>) -> String{
+
+	match h {
+		Some(dt) => {
+			match dt.to_string().to_lowercase().as_ref() {
+				"portfolioaccountnumber" => "account_name".to_string(),
+				"securitysymbol" => "security_ticker".to_string(),
+				"cusip" => "cusip".to_string(),
+				"securitydescription" => "security_description".to_string(),
+				"quantity" => "quantity".to_string(),
+				"price" => "price".to_string(),
+				"marketvalue" => "market_value".to_string(),
+				"asofdate" => "as_of_date".to_string(),
+				_ => "nomatch".to_string()
+			}
+		},
+		_ => {
+			"NoBueno".to_string()
+		}
+	}
+
+}
+
+/// Maps the first row of a sheet through one of the header functions above, so trade and holdings files share the same column lookup.
+pub fn get_mapped_headers(range: &Range<DataType>, idx_cap: u32, mapper: fn(Option<&DataType>) -> String) -> Vec<String> {
+    (0..idx_cap).map(|x| mapper(range.get_value((0,x)))).collect()
+}
+
+/// Opens Sheet1 and checks it's something we can read by column: every row as wide as the header,
+/// every header one the mapper knows, and none of the required ones missing.
+pub fn open_sheet(ifile: &str, mapper: fn(Option<&DataType>) -> String, required: &[&str]) -> Result<(Range<DataType>, Vec<String>), Error> {
+
+    let mut workbook: Xlsx<_> = open_workbook(ifile).map_err(|e| Error::xlsx(ifile, e))?;
+    let range = match workbook.worksheet_range("Sheet1") {
+        Some(Ok(range)) => range,
+        Some(Err(e)) => return Err(Error::xlsx(ifile, e)),
+        None => return Err(Error::schema(ifile, "no Sheet1"))
+    };
+
+    let ulens: Vec<usize> = range.rows().map(|x| x.len()).unique().collect();
+    if ulens.len() != 1 {
+        return Err(Error::schema(ifile, format!("rows aren't all the same width: {:?}", ulens)));
+    }
+    let idx_cap = ulens[0] as u32;
+
+    let mapped_headers: Vec<String> = get_mapped_headers(&range, idx_cap, mapper);
+    let unknown: Vec<(usize, String)> = mapped_headers.iter().enumerate()
+        .filter(|(_, x)| *x == "nomatch" || *x == "NoBueno")
+        .map(|(i, _)| (i, range.get_value((0, i as u32)).map(|x| x.to_string()).unwrap_or_default()))
+        .collect();
+    if !unknown.is_empty() {
+        return Err(Error::schema(ifile, format!("headers we don't know (column, header): {:?}", unknown)));
+    }
+    let missing: Vec<&&str> = required.iter().filter(|x| !mapped_headers.iter().any(|h| h == *x)).collect();
+    if !missing.is_empty() {
+        return Err(Error::schema(ifile, format!("missing columns {:?}", missing)));
+    }
+
+    Ok((range, mapped_headers))
+}
+
+/// Excel stores dates as days since Jan 1 1900.
+/// Caution! Excel dates after 28th February 1900 are actually one day out. Excel behaves as though the date 29th February 1900 existed, which it didn't.
+/// river north gives dates, not times, so setting to market close (closed end funds)
+pub fn excel_date(cell: &DataType) -> Option<NaiveDateTime> {
+    let days = cell.to_string().parse::<i64>().ok()?;
+    let excel_bullshit: NaiveDateTime = NaiveDate::from_ymd_opt(1899, 12, 30)?.and_hms_opt(16, 0, 0)?;
+    Some(excel_bullshit + ChronoDuration::days(days))
+}
+
+/// A date cell or exactly where it wasn't one, rows counted the way Excel shows them.
+fn excel_date_at(ifile: &str, i: usize, column: &str, cell: &DataType) -> Result<NaiveDateTime, Error> {
+    excel_date(cell).ok_or_else(|| Error::Parse {
+        file: ifile.to_string(),
+        row: i + 1,
+        column: column.to_string(),
+        message: format!("{:?} isn't a date", cell)
+    })
+}
+
+/// A number cell or exactly where it wasn't one. A blank is 0 unless the column has to have something,
+/// the files leave commission, fee and the like empty when there isn't any.
+pub(crate) fn excel_float_at(ifile: &str, i: usize, column: &str, cell: &DataType, required: bool) -> Result<f64, Error> {
+    match cell {
+        DataType::Float(x) => Ok(*x),
+        DataType::Int(x) => Ok(*x as f64),
+        DataType::String(s) if !s.trim().is_empty() => error::parse_field(ifile, i + 1, column, s),
+        DataType::Empty | DataType::String(_) if !required => Ok(0.),
+        other => Err(Error::Parse {
+            file: ifile.to_string(),
+            row: i + 1,
+            column: column.to_string(),
+            message: format!("{:?} isn't a number", other)
+        })
+    }
+}
+
+/// A text cell or exactly where it wasn't one. Numbers come through as text since cusips and account numbers
+/// can be all digits, a blank is only a problem when the column has to have something.
+pub(crate) fn excel_string_at(ifile: &str, i: usize, column: &str, cell: &DataType, required: bool) -> Result<String, Error> {
+    let value = match cell {
+        DataType::String(s) => s.trim().to_string(),
+        DataType::Float(_) | DataType::Int(_) => cell.to_string(),
+        DataType::Empty => "".to_string(),
+        other => return Err(Error::Parse {
+            file: ifile.to_string(),
+            row: i + 1,
+            column: column.to_string(),
+            message: format!("{:?} isn't text", other)
+        })
+    };
+    if required && value.is_empty() {
+        return Err(Error::Parse { file: ifile.to_string(), row: i + 1, column: column.to_string(), message: "blank".to_string() });
+    }
+    Ok(value)
+}
+
+const INSERT_TRADE: &str = "INSERT INTO trades (
     	handle,
     	filename,
     	filehash,
     	row,
     	account_name,
     	account_number,
+    	account_type,
+    	account_id,
     	security_description,
     	security_ticker,
     	asset_class,
     	security_type,
     	tx_type,
+    	source_tx_type,
     	cusip,
     	price,
     	quantity,
@@ -60,24 +194,48 @@ pub async fn insert_trade(client: &tokio_postgres::Client, trade: &trades::Trade
     	fee,
     	principal,
     	net_amount,
+    	currency,
     	trade_date,
     	settlement_date,
     	broker,
     	trader
-    	) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16, $17, $18, $19, $20, $21, $22)").await?;
+    	) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16, $17, $18, $19, $20, $21, $22, $23, $24, $25, $26)
+    	ON CONFLICT (handle, filehash, row) DO NOTHING";
+
+pub async fn insert_trade(client: &tokio_postgres::Client, trade: &trades::Trade) -> Result<(), Error> {
+    info!("{:?}, {:?}", client, trade);
+    let statement = client.prepare(INSERT_TRADE).await?;
+    execute_insert_trade(client, &statement, trade).await
+}
+
+/// The lot in one transaction, none of them go in if one fails.
+pub async fn insert_trades(client: &mut tokio_postgres::Client, trades: &[trades::Trade]) -> Result<(), Error> {
+    let tx = client.transaction().await?;
+    let statement = tx.prepare(INSERT_TRADE).await?;
+    for trade in trades {
+        info!("{:?}", trade);
+        execute_insert_trade(&tx, &statement, trade).await?;
+    }
+    tx.commit().await?;
+	Ok(())
+}
 
-    client.execute(&statement,&[
+async fn execute_insert_trade<C: GenericClient>(client: &C, statement: &Statement, trade: &trades::Trade) -> Result<(), Error> {
+    client.execute(statement,&[
     	&trade.handle,
     	&trade.filename, 
     	&trade.filehash,
     	&trade.row,
     	&trade.account_name,
     	&trade.account_number,
+    	&trade.account_type,
+    	&trade.account_id,
     	&trade.security_description,
     	&trade.security_ticker,
     	&trade.asset_class,
     	&trade.security_type,
     	&trade.tx_type,
+    	&trade.source_tx_type,
     	&trade.cusip,
     	&trade.price,
     	&trade.quantity,
@@ -85,6 +243,7 @@ pub async fn insert_trade(client: &tokio_postgres::Client, trade: &trades::Trade
     	&trade.fee,
     	&trade.principal,
     	&trade.net_amount,
+    	&trade.currency,
     	&trade.trade_date,
     	&trade.settlement_date,
     	&trade.broker,
@@ -93,138 +252,229 @@ pub async fn insert_trade(client: &tokio_postgres::Client, trade: &trades::Trade
 	Ok(())
 }
 
-pub async fn parse(client: &tokio_postgres::Client) -> Result<(), Box<dyn Error>> {
-     let ifiles = vec!["/tmp/2019-09.xlsx","/tmp/2019-10.xlsx","/tmp/2019-11.xlsx"];
+pub async fn parse(store: &dyn TradeStore) -> Result<(), Error> {
+     parse_files(store, &["/tmp/2019-09.xlsx","/tmp/2019-10.xlsx","/tmp/2019-11.xlsx"]).await
+}
+
+/// Each file goes in whole or not at all, one insert_trades per file, the store decides where that is. Files are read alongside each other
+/// but go into the store in the order given, so trade ids still follow file then row.
+pub async fn parse_files(store: &dyn TradeStore, ifiles: &[&str]) -> Result<(), Error> {
+     let (master, tx_map, tree) = futures::try_join!(
+         store.get_security_master(),
+         store.get_tx_type_map("rivernorth"),
+         store.get_hierarchy()
+     )?;
+     let reference = Arc::new((master, tx_map, tree));
+
+     let read = try_join_all(ifiles.iter().map(|ifile| {
+         let ifile = ifile.to_string();
+         let reference = reference.clone();
+         tokio::task::spawn_blocking(move || read_trades(&ifile, &reference.0, &reference.1, &reference.2))
+     })).await.map_err(|e| Error::Validation(format!("a file reader died: {}", e)))?;
+
+     for file_trades in read {
+        store.insert_trades(&file_trades?).await?;
+     }
+
+	Ok(())
+}
+
+/// Everything in one activity file as trades, enriched and signed, or why the file can't go in.
+fn read_trades(ifile: &str, master: &SecurityMaster, tx_map: &TxTypeMap, tree: &Hierarchy) -> Result<Vec<trades::Trade>, Error> {
+
+    let (range, mapped_headers) = open_sheet(ifile, get_header, &TRADE_COLUMNS)?;
+    let filehash = utils::sha_fmt(ifile)?;
+    let total_cells = range.get_size().0 * range.get_size().1;
+    let non_empty_cells: usize = range.used_cells().count();
+    info!("Found {} cells in 'Sheet1', including {} non empty cells",
+             total_cells, non_empty_cells);
+
+    let cusip_position = mapped_headers.iter().position(|x| x == "cusip");
+    let account_name_position = mapped_headers.iter().position(|x| x == "account_name");
+    let account_type_position = mapped_headers.iter().position(|x| x == "account_type");
+    let security_description_position = mapped_headers.iter().position(|x| x == "security_description");
+    let security_ticker_position = mapped_headers.iter().position(|x| x == "security_ticker");
+    let security_type_position = mapped_headers.iter().position(|x| x == "security_type");
+    let tx_type_position = mapped_headers.iter().position(|x| x == "tx_type");
+    let price_position = mapped_headers.iter().position(|x| x == "price");
+    let quantity_position = mapped_headers.iter().position(|x| x == "quantity");
+    let commission_position = mapped_headers.iter().position(|x| x == "commission");
+    let fee_position = mapped_headers.iter().position(|x| x == "fee");
+    let principal_position = mapped_headers.iter().position(|x| x == "principal");
+    let net_amount_position = mapped_headers.iter().position(|x| x == "net_amount");
+    let trade_date_position = mapped_headers.iter().position(|x| x == "trade_date");
+    let settlement_date_position = mapped_headers.iter().position(|x| x == "settlement_date");
+    let broker_position = mapped_headers.iter().position(|x| x == "broker");
+    let trader_position = mapped_headers.iter().position(|x| x == "trader");
+    // older files don't have a currency column, the security master or dollars fill it in
+    let currency_position = mapped_headers.iter().position(|x| x == "currency");
+
+    // nothing from the file goes in until every row has a tx type we know
+    let mut file_trades: Vec<trades::Trade> = Vec::new();
+    let mut unmapped: Vec<(usize, String)> = Vec::new();
+
+    for (i,r) in range.rows().enumerate() {
+        if i == 0 {
+            continue
+        }
+
+        if let (
+            Some(cp), 
+            Some(anap), 
+            Some(antp), 
+            Some(sdp), 
+            Some(stp), 
+            Some(sectypepos), 
+            Some(txtp), 
+            Some(pp), 
+            Some(qtyp),
+            Some(cmmp),
+            Some(feep),
+            Some(princep),
+            Some(nap),
+            Some(trdp), 
+            Some(stdp), 
+            Some(brkp), 
+            Some(trap)) = (
+            cusip_position, 
+            account_name_position, 
+            account_type_position, 
+            security_description_position, 
+            security_ticker_position, 
+            security_type_position,
+            tx_type_position, 
+            price_position,
+            quantity_position,
+            commission_position,
+            fee_position,
+            principal_position,
+            net_amount_position, 
+            trade_date_position, 
+            settlement_date_position, 
+            broker_position, 
+            trader_position) {
+
+            let this_bullshit_trade_date = excel_date_at(ifile, i, "trade_date", &r[trdp])?;
+            let this_bullshit_settlment_date = excel_date_at(ifile, i, "settlement_date", &r[stdp])?;
+
+            let text = |column: &str, p: usize, required: bool| excel_string_at(ifile, i, column, &r[p], required);
+            let number = |column: &str, p: usize| excel_float_at(ifile, i, column, &r[p], false);
+
+            let source_tx_type = text("tx_type", txtp, true)?;
+            let Some(tx_type) = tx_map.map(&source_tx_type) else {
+                unmapped.push((i, source_tx_type));
+                continue
+            };
+
+            let mut trade = trades::Trade {
+            	id: None,
+            	handle: "rivernorth".to_string(),
+            	filename: ifile.to_string(),
+            	filehash: filehash.clone(),
+            	row: i as i32,
+                account_name: text("account_name", anap, true)?,
+                // the file only has the one account column, the hierarchy says which account it really is
+                account_number: text("account_name", anap, true)?,
+                account_type: text("account_type", antp, false)?,
+                account_id: None,
+                security_description: text("security_description", sdp, false)?,
+                security_ticker: text("security_ticker", stp, false)?,
+                security_type: text("security_type", sectypepos, false)?,
+                asset_class: text("security_type", sectypepos, false)?,
+                tx_type: tx_type.to_string(),
+                source_tx_type,
+                broker: text("broker", brkp, false)?,
+                trader: text("trader", trap, false)?,
+                cusip: text("cusip", cp, false)?,
+                price: number("price", pp)?,
+                quantity: number("quantity", qtyp)?,
+                commission: number("commission", cmmp)?,
+                fee: number("fee", feep)?,
+                principal: number("principal", princep)?,
+                net_amount: number("net_amount", nap)?,
+                currency: currency_position.map(|x| text("currency", x, false)).transpose()?.unwrap_or_default().to_uppercase(),
+                trade_date: this_bullshit_trade_date.timestamp(),
+                settlement_date: this_bullshit_settlment_date.timestamp(),
+            };
+
+            master.enrich(&mut trade);
+            tree.enrich(&mut trade);
+            trade.normalize_signs();
+            file_trades.push(trade);
+
+        }
+    }
+
+    if !unmapped.is_empty() {
+        return Err(Error::Validation(format!("{} has tx types with no mapping for {} (row, tx type): {:?}", ifile, tx_map.source, unmapped)));
+    }
+
+    Ok(file_trades)
+}
+
+
+pub async fn parse_holdings(client: &tokio_postgres::Client) -> Result<(), Error> {
+     let ifiles = vec!["/tmp/holdings-2019-09.xlsx","/tmp/holdings-2019-10.xlsx","/tmp/holdings-2019-11.xlsx"];
 
      for ifile in ifiles {
- 
-        let mut workbook: Xlsx<_> = open_workbook(ifile).expect("Cannot open file");
-        // Read whole worksheet data and provide some statistics
-        if let Some(Ok(range)) = workbook.worksheet_range("Sheet1") {
-            let total_cells = range.get_size().0 * range.get_size().1;
-            let non_empty_cells: usize = range.used_cells().count();
-            println!("Found {} cells in 'Sheet1', including {} non empty cells",
-                     total_cells, non_empty_cells);
-            // alternatively, we can manually filter rows
-            assert_eq!(non_empty_cells, range.rows()
-                .flat_map(|r| r.iter().filter(|&c| c != &DataType::Empty)).count());
-
-            let lens: Vec<usize> = range.rows().into_iter().map(|x| x.len()).rev().collect();
-            let ulens: Vec<_> = lens.into_iter().unique().collect();
-            assert_eq!(1,ulens.len());
-
-            let idx_cap = ulens[0] as u32;
-
-            let _original_headers: Vec<Option<&DataType>> = (0..idx_cap).collect::<Vec<u32>>().iter().map(|x| range.get_value((0,*x))).collect();        
-            let mapped_headers: Vec<String> = (0..idx_cap).collect::<Vec<u32>>().iter().map(|x| rivernorth::get_header(range.get_value((0,*x)))).collect();
-            let cusip_position = mapped_headers.iter().position(|x| x == "cusip");
-            let account_name_position = mapped_headers.iter().position(|x| x == "account_name");
-            let account_number_position = mapped_headers.iter().position(|x| x == "account_number");
-            let security_description_position = mapped_headers.iter().position(|x| x == "security_description");
-            let security_ticker_position = mapped_headers.iter().position(|x| x == "security_ticker");
-            let security_type_position = mapped_headers.iter().position(|x| x == "security_type");
-            let tx_type_position = mapped_headers.iter().position(|x| x == "tx_type");
-            let price_position = mapped_headers.iter().position(|x| x == "price");
-            let quantity_position = mapped_headers.iter().position(|x| x == "quantity");
-            let commission_position = mapped_headers.iter().position(|x| x == "commission");
-            let fee_position = mapped_headers.iter().position(|x| x == "fee");
-            let principal_position = mapped_headers.iter().position(|x| x == "principal");
-            let net_amount_position = mapped_headers.iter().position(|x| x == "net_amount");
-            let trade_date_position = mapped_headers.iter().position(|x| x == "trade_date");
-            let settlement_date_position = mapped_headers.iter().position(|x| x == "settlement_date");
-            let broker_position = mapped_headers.iter().position(|x| x == "broker");
-            let trader_position = mapped_headers.iter().position(|x| x == "trader");
-
-            assert_eq!(false, mapped_headers.contains(&"nomatch".to_string()));
-
-
-            for (i,r) in range.rows().enumerate() {
-                if i == 0 {
-                    continue
-                }
-
-                if let (
-                    Some(cp), 
-                    Some(anap), 
-                    Some(anmp), 
-                    Some(sdp), 
-                    Some(stp), 
-                    Some(sectypepos), 
-                    Some(txtp), 
-                    Some(pp), 
-                    Some(qtyp),
-                    Some(cmmp),
-                    Some(feep),
-                    Some(princep),
-                    Some(nap),
-                    Some(trdp), 
-                    Some(stdp), 
-                    Some(brkp), 
-                    Some(trap)) = (
-                    cusip_position, 
-                    account_name_position, 
-                    account_number_position, 
-                    security_description_position, 
-                    security_ticker_position, 
-                    security_type_position,
-                    tx_type_position, 
-                    price_position,
-                    quantity_position,
-                    commission_position,
-                    fee_position,
-                    principal_position,
-                    net_amount_position, 
-                    trade_date_position, 
-                    settlement_date_position, 
-                    broker_position, 
-                    trader_position) {
-
-                    let string_trade_date = r[trdp].to_string();
-                    // this is excel bullshit number of days since Jan 1 1900
-                    let istring_trade_date = string_trade_date.parse::<i64>().unwrap_or(1);
-                    // Caution! Excel dates after 28th February 1900 are actually one day out. Excel behaves as though the date 29th February 1900 existed, which it didn't.
-                    let string_settlement_date = r[stdp].to_string();
-                    // this is excel bullshit number of days since Jan 1 1900
-                    let istring_settlement_date = string_settlement_date.parse::<i64>().unwrap_or(1);
-                    // Caution! Excel dates after 28th February 1900 are actually one day out. Excel behaves as though the date 29th February 1900 existed, which it didn't.
-                    // river north gives dates, not times, so setting to market close (closed end funds)
-                    let excel_bullshit: NaiveDateTime = NaiveDate::from_ymd_opt(1899, 12, 30).unwrap().and_hms_opt(16, 0, 0).unwrap();
-                    let this_bullshit_trade_date = excel_bullshit + ChronoDuration::days(istring_trade_date);
-                    let this_bullshit_settlment_date = excel_bullshit + ChronoDuration::days(istring_settlement_date);
-
-                    let trade = trades::Trade {
-                    	id: None,
-                    	handle: "rivernorth".to_string(),
-                    	filename: ifile.to_string(),
-                    	filehash: utils::sha_fmt(ifile).unwrap_or("failedhash".to_string()),
-                    	row: i as i32,
-                        account_name: r[anap].get_string().unwrap_or("ALTP ERROR NO DATA PROVIDED").to_string(),
-                        account_number: r[anmp].get_string().unwrap_or("ALTP ERROR NO DATA PROVIDED").to_string(),
-                        security_description: r[sdp].get_string().unwrap_or("ALTP ERROR NO DATA PROVIDED").to_string(),
-                        security_ticker: r[stp].get_string().unwrap_or("ALTP ERROR NO DATA PROVIDED").to_string(),
-                        security_type: r[sectypepos].get_string().unwrap_or("ALTP ERROR NO DATA PROVIDED").to_string(),
-                        asset_class: r[sectypepos].get_string().unwrap_or("ALTP ERROR NO DATA PROVIDED").to_string(),
-                        tx_type: r[txtp].get_string().unwrap_or("ALTP ERROR NO DATA PROVIDED").to_string(),
-                        broker: r[brkp].get_string().unwrap_or("ALTP ERROR NO DATA PROVIDED").to_string(),
-                        trader: r[trap].get_string().unwrap_or("ALTP ERROR NO DATA PROVIDED").to_string(),
-                        cusip: r[cp].get_string().unwrap_or("ALTP ERROR NO DATA PROVIDED").to_string(),
-                        price: r[pp].get_float().unwrap_or(0.),
-                        quantity: r[qtyp].get_float().unwrap_or(0.),
-                        commission: r[cmmp].get_float().unwrap_or(0.),
-                        fee: r[feep].get_float().unwrap_or(0.),
-                        principal: r[princep].get_float().unwrap_or(0.),
-                        net_amount: r[nap].get_float().unwrap_or(0.),
-                        trade_date: this_bullshit_trade_date.timestamp(),
-                        settlement_date: this_bullshit_settlment_date.timestamp(),
-                    };
-
-                    info!("{:?}", trade);
-                    insert_trade(&client, &trade).await?;
-
-                }
+
+        let (range, mapped_headers) = open_sheet(ifile, get_holding_header, &HOLDING_COLUMNS)?;
+        let filehash = utils::sha_fmt(ifile)?;
+
+        let account_name_position = mapped_headers.iter().position(|x| x == "account_name");
+        let security_ticker_position = mapped_headers.iter().position(|x| x == "security_ticker");
+        let cusip_position = mapped_headers.iter().position(|x| x == "cusip");
+        let security_description_position = mapped_headers.iter().position(|x| x == "security_description");
+        let quantity_position = mapped_headers.iter().position(|x| x == "quantity");
+        let price_position = mapped_headers.iter().position(|x| x == "price");
+        let market_value_position = mapped_headers.iter().position(|x| x == "market_value");
+        let as_of_date_position = mapped_headers.iter().position(|x| x == "as_of_date");
+
+        for (i,r) in range.rows().enumerate() {
+            if i == 0 {
+                continue
+            }
+
+            if let (
+                Some(anap),
+                Some(stp),
+                Some(cp),
+                Some(sdp),
+                Some(qtyp),
+                Some(pp),
+                Some(mvp),
+                Some(aodp)) = (
+                account_name_position,
+                security_ticker_position,
+                cusip_position,
+                security_description_position,
+                quantity_position,
+                price_position,
+                market_value_position,
+                as_of_date_position) {
+
+                let holding = recon::Holding {
+                    id: None,
+                    handle: "rivernorth".to_string(),
+                    filename: ifile.to_string(),
+                    filehash: filehash.clone(),
+                    row: i as i32,
+                    account_name: excel_string_at(ifile, i, "account_name", &r[anap], true)?,
+                    security_ticker: excel_string_at(ifile, i, "security_ticker", &r[stp], true)?,
+                    cusip: excel_string_at(ifile, i, "cusip", &r[cp], false)?,
+                    security_description: excel_string_at(ifile, i, "security_description", &r[sdp], false)?,
+                    quantity: excel_float_at(ifile, i, "quantity", &r[qtyp], true)?,
+                    price: excel_float_at(ifile, i, "price", &r[pp], true)?,
+                    market_value: excel_float_at(ifile, i, "market_value", &r[mvp], true)?,
+                    as_of_date: excel_date_at(ifile, i, "as_of_date", &r[aodp])?.timestamp(),
+                };
+
+                info!("{:?}", holding);
+                recon::insert_holding(client, &holding).await?;
+
             }
         }
     }
 
 	Ok(())
-}
\ No newline at end of file
+}
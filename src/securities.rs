@@ -0,0 +1,346 @@
+use crate::error::{self, Error};
+use crate::fx;
+use crate::trades::Trade;
+use crate::utils;
+use serde::{Serialize,Deserialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+use tokio_postgres::Row;
+use tracing::{info, warn};
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum AssetClass {
+    Equity,
+    Fund,
+    FixedIncome,
+    Option,
+    Future,
+    Cash,
+    Other
+}
+
+impl fmt::Display for AssetClass {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AssetClass::Equity => write!(f, "EQUITY"),
+            AssetClass::Fund => write!(f, "FUND"),
+            AssetClass::FixedIncome => write!(f, "FIXED_INCOME"),
+            AssetClass::Option => write!(f, "OPTION"),
+            AssetClass::Future => write!(f, "FUTURE"),
+            AssetClass::Cash => write!(f, "CASH"),
+            AssetClass::Other => write!(f, "OTHER"),
+        }
+    }
+}
+
+impl FromStr for AssetClass {
+    type Err = String;
+
+    /// Takes our own names as well as whatever security type an administrator sends.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let t = s.trim().to_uppercase();
+        if t.is_empty() {
+            Err("no asset class".to_string())
+        } else if t.contains("OPTION") || t == "CALL" || t == "PUT" {
+            Ok(AssetClass::Option)
+        } else if t.contains("FUTURE") {
+            Ok(AssetClass::Future)
+        } else if t.contains("FUND") || t.contains("ETF") || t.contains("CLOSED END") || t.contains("CEF") || t.contains("TRUST") {
+            Ok(AssetClass::Fund)
+        } else if t.contains("BOND") || t.contains("NOTE") || t.contains("FIXED") || t.contains("DEBT") || t.contains("TREASURY") {
+            Ok(AssetClass::FixedIncome)
+        } else if t.contains("CASH") || t.contains("MONEY MARKET") {
+            Ok(AssetClass::Cash)
+        } else if t.contains("EQUITY") || t.contains("STOCK") || t.contains("COMMON") || t.contains("PREFERRED") || t.contains("ADR") {
+            Ok(AssetClass::Equity)
+        } else {
+            Ok(AssetClass::Other)
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Security {
+    pub id: Option<i32>,
+    pub cusip: String,
+    pub isin: String,
+    pub sedol: String,
+    pub ticker: String,
+    pub figi: String,
+    pub name: String,
+    pub asset_class: AssetClass,
+    pub currency: String,
+    pub multiplier: f64,
+    pub exchange: String
+}
+
+impl From<Row> for Security {
+    fn from(row: tokio_postgres::Row) -> Self {
+        let asset_class: String = row.get("asset_class");
+        Self {
+            id: Some(row.get("id")),
+            cusip: row.get("cusip"),
+            isin: row.get("isin"),
+            sedol: row.get("sedol"),
+            ticker: row.get("ticker"),
+            figi: row.get("figi"),
+            name: row.get("name"),
+            asset_class: asset_class.parse().unwrap_or(AssetClass::Other),
+            currency: row.get("currency"),
+            multiplier: row.get("multiplier"),
+            exchange: row.get("exchange"),
+        }
+    }
+}
+
+fn char_value(c: char) -> Option<u32> {
+    match c {
+        '0'..='9' => c.to_digit(10),
+        'A'..='Z' => Some(c as u32 - 'A' as u32 + 10),
+        '*' => Some(36),
+        '@' => Some(37),
+        '#' => Some(38),
+        _ => None
+    }
+}
+
+/// Nine characters, the last being the modulus 10 double-add-double check digit over the first eight.
+pub fn valid_cusip(cusip: &str) -> bool {
+    let c: Vec<char> = cusip.trim().to_uppercase().chars().collect();
+    if c.len() != 9 {
+        return false
+    }
+    let mut sum = 0;
+    for (i, ch) in c[..8].iter().enumerate() {
+        let Some(mut v) = char_value(*ch) else { return false };
+        if i % 2 == 1 {
+            v *= 2;
+        }
+        sum += v / 10 + v % 10;
+    }
+    c[8].to_digit(10) == Some((10 - sum % 10) % 10)
+}
+
+/// Twelve characters, a country code, the national id, then a Luhn check digit over the letters expanded to numbers.
+pub fn valid_isin(isin: &str) -> bool {
+    let c: Vec<char> = isin.trim().to_uppercase().chars().collect();
+    if c.len() != 12 || !c[..2].iter().all(|x| x.is_ascii_alphabetic()) || !c[11].is_ascii_digit() {
+        return false
+    }
+    let mut digits: Vec<u32> = Vec::new();
+    for ch in &c {
+        match char_value(*ch) {
+            Some(v) if v < 10 => digits.push(v),
+            Some(v) if v < 36 => {
+                digits.push(v / 10);
+                digits.push(v % 10);
+            },
+            _ => return false
+        }
+    }
+    let sum: u32 = digits.iter().rev().enumerate().map(|(i, d)| {
+        if i % 2 == 1 {
+            let dd = d * 2;
+            dd / 10 + dd % 10
+        } else {
+            *d
+        }
+    }).sum();
+    sum.is_multiple_of(10)
+}
+
+/// The security master loaded up and indexed the ways trades can point at it.
+#[derive(Clone, Debug, Default)]
+pub struct SecurityMaster {
+    securities: Vec<Security>,
+    by_cusip: HashMap<String, usize>,
+    by_isin: HashMap<String, usize>,
+    by_ticker: HashMap<String, usize>
+}
+
+impl SecurityMaster {
+    pub fn new(securities: Vec<Security>) -> Self {
+        let mut m = SecurityMaster { securities, ..Default::default() };
+        for (i, s) in m.securities.iter().enumerate() {
+            if !s.cusip.is_empty() {
+                m.by_cusip.insert(s.cusip.to_uppercase(), i);
+            }
+            if !s.isin.is_empty() {
+                m.by_isin.insert(s.isin.to_uppercase(), i);
+            }
+            if !s.ticker.is_empty() {
+                m.by_ticker.insert(s.ticker.to_uppercase(), i);
+            }
+        }
+        m
+    }
+
+    /// CUSIP first since it's the most specific thing the files carry, some administrators stuff an ISIN in that column, ticker last.
+    pub fn resolve(&self, trade: &Trade) -> Option<&Security> {
+        let id = trade.cusip.trim().to_uppercase();
+        let ticker = trade.security_ticker.trim().to_uppercase();
+        self.by_cusip.get(&id)
+            .or_else(|| self.by_isin.get(&id))
+            .or_else(|| self.by_ticker.get(&ticker))
+            .map(|i| &self.securities[*i])
+    }
+
+    /// Fills in what the master knows and settles asset_class on the taxonomy, falling back to the file's security type.
+    /// Trades with no currency of their own take the security's, then dollars.
+    pub fn enrich(&self, trade: &mut Trade) {
+        if !trade.cusip.is_empty() && !valid_cusip(&trade.cusip) && !valid_isin(&trade.cusip) {
+            warn!("{:?} row {:?} has a bad identifier {:?}", trade.filename, trade.row, trade.cusip);
+        }
+        match self.resolve(trade) {
+            Some(s) => {
+                if !valid_cusip(&trade.cusip) && !s.cusip.is_empty() {
+                    trade.cusip = s.cusip.clone();
+                }
+                if trade.security_description.is_empty() || trade.security_description.starts_with("ALTP ERROR") {
+                    trade.security_description = s.name.clone();
+                }
+                trade.asset_class = s.asset_class.to_string();
+                if trade.currency.is_empty() {
+                    trade.currency = s.currency.clone();
+                }
+            },
+            None => {
+                trade.asset_class = trade.security_type.parse().unwrap_or(AssetClass::Other).to_string();
+            }
+        }
+        if trade.currency.is_empty() {
+            trade.currency = fx::DEFAULT_CURRENCY.to_string();
+        }
+    }
+}
+
+pub async fn build_securities_table(client: &tokio_postgres::Client) -> Result<(), Error> {
+
+    client.query("CREATE TABLE securities (id SERIAL PRIMARY KEY,
+        cusip VARCHAR NOT NULL,
+        isin VARCHAR NOT NULL,
+        sedol VARCHAR NOT NULL,
+        ticker VARCHAR NOT NULL,
+        figi VARCHAR NOT NULL,
+        name VARCHAR NOT NULL,
+        asset_class VARCHAR NOT NULL,
+        currency VARCHAR NOT NULL,
+        multiplier FLOAT8 NOT NULL,
+        exchange VARCHAR NOT NULL
+        )", &[]).await?;
+
+    Ok(())
+}
+
+pub async fn drop_securities_table(client: &tokio_postgres::Client) -> Result<(), Error> {
+
+    client.query("drop TABLE securities", &[]).await?;
+
+    Ok(())
+}
+
+async fn clean_securities(client: &tokio_postgres::Client) -> Result<(), Error> {
+
+    client.query("delete from securities", &[]).await?;
+
+    Ok(())
+}
+
+pub async fn insert_security(client: &tokio_postgres::Client, s: &Security) -> Result<(), Error> {
+
+    let statement = client.prepare("INSERT INTO securities (
+        cusip,
+        isin,
+        sedol,
+        ticker,
+        figi,
+        name,
+        asset_class,
+        currency,
+        multiplier,
+        exchange
+        ) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)").await?;
+
+    client.execute(&statement,&[
+        &s.cusip,
+        &s.isin,
+        &s.sedol,
+        &s.ticker,
+        &s.figi,
+        &s.name,
+        &s.asset_class.to_string(),
+        &s.currency,
+        &s.multiplier,
+        &s.exchange
+        ]).await?;
+    Ok(())
+}
+
+pub async fn get_security_master(client: &tokio_postgres::Client) -> Result<SecurityMaster, Error> {
+
+    let rows = client.query("SELECT * FROM securities ORDER BY id", &[]).await?;
+    Ok(SecurityMaster::new(rows.into_iter().map(Security::from).collect()))
+}
+
+/// Replaces the master with the file, rows with identifiers that fail their check digit are skipped.
+/// Expects cusip, isin, sedol, ticker, figi, name, asset_class, currency, multiplier, exchange columns.
+/// A blank asset_class is Other and a blank multiplier 1, a multiplier that isn't a number fails the load.
+pub async fn load(client: &tokio_postgres::Client) -> Result<(), Error> {
+    let ifile = "/tmp/securities.csv";
+
+    let mut v: Vec<Security> = Vec::new();
+    for (row, r) in utils::read_csv_records(ifile, 10)? {
+        let s = Security {
+            id: None,
+            cusip: r[0].trim().to_uppercase(),
+            isin: r[1].trim().to_uppercase(),
+            sedol: r[2].trim().to_uppercase(),
+            ticker: r[3].trim().to_uppercase(),
+            figi: r[4].trim().to_uppercase(),
+            name: r[5].trim().to_string(),
+            asset_class: error::parse_field_or(ifile, row, "asset_class", &r[6], AssetClass::Other)?,
+            currency: r[7].trim().to_uppercase(),
+            multiplier: error::parse_field_or(ifile, row, "multiplier", &r[8], 1.)?,
+            exchange: r[9].trim().to_uppercase()
+        };
+        if (!s.cusip.is_empty() && !valid_cusip(&s.cusip)) || (!s.isin.is_empty() && !valid_isin(&s.isin)) {
+            warn!("skipping security with a bad check digit {:?}", s);
+            continue
+        }
+        v.push(s);
+    }
+
+    clean_securities(client).await?;
+    for s in &v {
+        info!("{:?}", s);
+        insert_security(client, s).await?;
+    }
+
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn check_digits() {
+        assert!(valid_cusip("037833100"));
+        assert!(valid_cusip("38259P508"));
+        assert!(!valid_cusip("037833101"));
+        assert!(!valid_cusip("ALTP ERROR NO DATA PROVIDED"));
+        assert!(valid_isin("US0378331005"));
+        assert!(valid_isin("GB0002634946"));
+        assert!(!valid_isin("US0378331006"));
+    }
+
+    #[test]
+    fn asset_class_from_security_type() {
+        assert_eq!("Closed End Fund".parse(), Ok(AssetClass::Fund));
+        assert_eq!("Common Stock".parse(), Ok(AssetClass::Equity));
+        assert_eq!("Corporate Bond".parse(), Ok(AssetClass::FixedIncome));
+    }
+}
@@ -0,0 +1,570 @@
+use crate::commissions::{CommissionSummary, CommissionTarget};
+use crate::corporate_actions::CorporateAction;
+use crate::error::Error;
+use crate::fx::{Conversion, FxRate};
+use crate::hierarchy::Hierarchy;
+use crate::securities::SecurityMaster;
+use crate::store::{ReferenceData, SummaryStore, TradeStore};
+use crate::trades::{self, AccountSummary, ChainMetrics, ChainParameters, ChainRun, FileSummary, HierarchySummary, SecuritySummary, Trade, TradeChain};
+use crate::tx_types::TxTypeMap;
+use async_trait::async_trait;
+use chrono::NaiveDateTime;
+use rusqlite::{params, Connection};
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use tracing::info;
+
+/// where the laptop database goes when nothing says otherwise
+pub const DEFAULT_PATH: &str = "/tmp/nav.sqlite";
+
+/// The trades, chains and summary tables in one SQLite file, laid out like their Postgres counterparts
+/// with dates as unix seconds. Reference data stays in tradellama, so it comes from `reference`.
+pub struct SqliteStore {
+    conn: Mutex<Connection>,
+    pub reference: ReferenceData
+}
+
+impl SqliteStore {
+    /// Opens or creates the file and builds whatever tables aren't there yet.
+    pub fn open(path: &str) -> Result<Self, Error> {
+        Self::with_connection(Connection::open(path)?)
+    }
+
+    /// Nothing on disk, for tests.
+    pub fn open_in_memory() -> Result<Self, Error> {
+        Self::with_connection(Connection::open_in_memory()?)
+    }
+
+    /// SQLite leaves foreign keys off unless each connection asks, and chain_members leans on them.
+    fn with_connection(conn: Connection) -> Result<Self, Error> {
+        conn.execute_batch("PRAGMA foreign_keys = ON;")?;
+        let store = SqliteStore { conn: Mutex::new(conn), reference: ReferenceData::default() };
+        store.build_tables()?;
+        Ok(store)
+    }
+
+    pub fn build_tables(&self) -> Result<(), Error> {
+        self.conn.lock().unwrap().execute_batch("
+            CREATE TABLE IF NOT EXISTS trades (id INTEGER PRIMARY KEY AUTOINCREMENT,
+                handle TEXT NOT NULL,
+                filename TEXT NOT NULL,
+                filehash TEXT NOT NULL,
+                row INTEGER NOT NULL,
+                account_name TEXT NOT NULL,
+                account_number TEXT NOT NULL,
+                account_type TEXT NOT NULL,
+                account_id INTEGER,
+                security_ticker TEXT NOT NULL,
+                security_description TEXT NOT NULL,
+                asset_class TEXT NOT NULL,
+                security_type TEXT NOT NULL,
+                tx_type TEXT NOT NULL,
+                source_tx_type TEXT NOT NULL,
+                cusip TEXT NOT NULL,
+                price REAL NOT NULL,
+                quantity REAL NOT NULL,
+                commission REAL NOT NULL,
+                fee REAL NOT NULL,
+                principal REAL NOT NULL,
+                net_amount REAL NOT NULL,
+                currency TEXT NOT NULL,
+                trade_date INTEGER NOT NULL,
+                settlement_date INTEGER NOT NULL,
+                broker TEXT NOT NULL,
+                trader TEXT NOT NULL,
+                UNIQUE (handle, filehash, row)
+            );
+            CREATE TABLE IF NOT EXISTS fx_audit (id INTEGER PRIMARY KEY AUTOINCREMENT,
+                handle TEXT NOT NULL,
+                context TEXT NOT NULL,
+                reference TEXT NOT NULL,
+                from_currency TEXT NOT NULL,
+                to_currency TEXT NOT NULL,
+                as_of_date TEXT NOT NULL,
+                rate_date TEXT NOT NULL,
+                rate REAL NOT NULL,
+                source TEXT NOT NULL,
+                amount REAL NOT NULL,
+                converted REAL NOT NULL,
+                inserted_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP
+            );
+            CREATE TABLE IF NOT EXISTS chains (id TEXT PRIMARY KEY,
+                handle TEXT NOT NULL,
+                rule TEXT NOT NULL,
+                members INTEGER NOT NULL,
+                holding_secs INTEGER NOT NULL,
+                net_quantity REAL NOT NULL,
+                gross_notional REAL NOT NULL,
+                realized_pnl REAL NOT NULL,
+                costs REAL NOT NULL,
+                flat BOOLEAN NOT NULL,
+                inserted_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP,
+                updated_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP
+            );
+            CREATE TABLE IF NOT EXISTS chain_members (id INTEGER PRIMARY KEY AUTOINCREMENT,
+                chain_id TEXT NOT NULL REFERENCES chains (id) ON DELETE CASCADE,
+                trade_id INTEGER NOT NULL REFERENCES trades (id),
+                role TEXT NOT NULL,
+                position INTEGER NOT NULL,
+                direction TEXT NOT NULL,
+                UNIQUE (chain_id, position)
+            );
+            CREATE INDEX IF NOT EXISTS chain_members_trade_id ON chain_members (trade_id);
+            CREATE VIEW IF NOT EXISTS chain_rows AS SELECT
+                m.id, c.handle, t.filename, t.filehash, t.row, c.id AS chain_id, m.role = 'head' AS head,
+                t.security_ticker, t.account_name, t.tx_type, m.direction, t.price, t.quantity, t.commission,
+                t.net_amount, t.currency, t.broker, t.trade_date, t.settlement_date, c.inserted_at, c.updated_at
+                FROM chain_members m
+                JOIN chains c ON c.id = m.chain_id
+                JOIN trades t ON t.id = m.trade_id;
+            CREATE TABLE IF NOT EXISTS chain_runs (run_id INTEGER PRIMARY KEY AUTOINCREMENT,
+                handle TEXT NOT NULL,
+                mode TEXT NOT NULL,
+                parameters TEXT NOT NULL,
+                trades INTEGER NOT NULL,
+                chains INTEGER NOT NULL,
+                members INTEGER NOT NULL,
+                removed INTEGER NOT NULL,
+                last_trade_id INTEGER NOT NULL,
+                started_at INTEGER NOT NULL,
+                duration_ms INTEGER NOT NULL,
+                inserted_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP,
+                updated_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP
+            );
+            CREATE TABLE IF NOT EXISTS chain_trades (trade_id INTEGER PRIMARY KEY REFERENCES trades (id) ON DELETE CASCADE,
+                handle TEXT NOT NULL,
+                hash TEXT NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS chain_trades_handle ON chain_trades (handle);
+            CREATE TABLE IF NOT EXISTS file_summaries (id INTEGER PRIMARY KEY AUTOINCREMENT,
+                handle TEXT NOT NULL,
+                filename TEXT NOT NULL,
+                filehash TEXT NOT NULL,
+                calc REAL NOT NULL,
+                inserted_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP,
+                updated_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP
+            );
+            CREATE TABLE IF NOT EXISTS account_summaries (id INTEGER PRIMARY KEY AUTOINCREMENT,
+                handle TEXT NOT NULL,
+                tx_type TEXT NOT NULL,
+                direction TEXT NOT NULL,
+                account_name TEXT NOT NULL,
+                currency TEXT NOT NULL,
+                calc REAL NOT NULL,
+                inserted_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP,
+                updated_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP
+            );
+            CREATE TABLE IF NOT EXISTS security_summaries (id INTEGER PRIMARY KEY AUTOINCREMENT,
+                handle TEXT NOT NULL,
+                tx_type TEXT NOT NULL,
+                direction TEXT NOT NULL,
+                security_ticker TEXT NOT NULL,
+                currency TEXT NOT NULL,
+                calc REAL NOT NULL,
+                inserted_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP,
+                updated_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP
+            );
+            CREATE TABLE IF NOT EXISTS hierarchy_summaries (id INTEGER PRIMARY KEY AUTOINCREMENT,
+                handle TEXT NOT NULL,
+                level TEXT NOT NULL,
+                node TEXT NOT NULL,
+                tx_type TEXT NOT NULL,
+                direction TEXT NOT NULL,
+                currency TEXT NOT NULL,
+                calc REAL NOT NULL,
+                inserted_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP,
+                updated_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP
+            );
+            CREATE TABLE IF NOT EXISTS commission_summaries (id INTEGER PRIMARY KEY AUTOINCREMENT,
+                handle TEXT NOT NULL,
+                dimension TEXT NOT NULL,
+                name TEXT NOT NULL,
+                period TEXT NOT NULL,
+                currency TEXT NOT NULL,
+                trades INTEGER NOT NULL,
+                shares REAL NOT NULL,
+                notional REAL NOT NULL,
+                commission REAL NOT NULL,
+                fee REAL NOT NULL,
+                cents_per_share REAL NOT NULL,
+                bps_of_notional REAL NOT NULL,
+                commission_share REAL NOT NULL,
+                target_share REAL,
+                inserted_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP,
+                updated_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP
+            );")?;
+
+        Ok(())
+    }
+
+    pub fn drop_tables(&self) -> Result<(), Error> {
+        self.conn.lock().unwrap().execute_batch("
+            DROP VIEW IF EXISTS chain_rows;
+            DROP TABLE IF EXISTS chain_members;
+            DROP TABLE IF EXISTS chain_trades;
+            DROP TABLE IF EXISTS chains;
+            DROP TABLE IF EXISTS trades;
+            DROP TABLE IF EXISTS fx_audit;
+            DROP TABLE IF EXISTS chain_runs;
+            DROP TABLE IF EXISTS file_summaries;
+            DROP TABLE IF EXISTS account_summaries;
+            DROP TABLE IF EXISTS security_summaries;
+            DROP TABLE IF EXISTS hierarchy_summaries;
+            DROP TABLE IF EXISTS commission_summaries;")?;
+
+        Ok(())
+    }
+
+    /// A chains row and the head and members under it for each chain, written with the run in one transaction.
+    /// removed None clears every chain the handle has first.
+    fn write_chains(&self, handle: &str, chains: &[TradeChain], removed: Option<&[String]>, run: &ChainRun) -> Result<i32, Error> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        match removed {
+            None => {
+                tx.execute("DELETE FROM chains WHERE handle = ?1", [handle])?;
+            },
+            Some(ids) => for id in ids {
+                tx.execute("DELETE FROM chains WHERE handle = ?1 AND id = ?2", [handle, id])?;
+            }
+        }
+        for chain in chains {
+            let m = &chain.metrics;
+            tx.execute("INSERT INTO chains (id, handle, rule, members, holding_secs, net_quantity, gross_notional, realized_pnl, costs, flat)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+                params![chain.chain_id, chain.head.handle, run.parameters.rule, chain.chain.len() as i32,
+                m.holding_secs, m.net_quantity, m.gross_notional, m.realized_pnl, m.costs, m.flat])?;
+            for (position, (role, t)) in chain.rows().enumerate() {
+                tx.execute("INSERT INTO chain_members (chain_id, trade_id, role, position, direction) VALUES (?1, ?2, ?3, ?4, ?5)",
+                    params![chain.chain_id, trades::member_id(t)?, role, position as i32, t.direction().to_string()])?;
+            }
+        }
+        tx.execute("INSERT INTO chain_runs (handle, mode, parameters, trades, chains, members, removed, last_trade_id, started_at, duration_ms)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+            params![
+            run.handle,
+            run.mode,
+            serde_json::to_string(&run.parameters).unwrap_or_default(),
+            run.trades,
+            run.chains,
+            run.members,
+            run.removed,
+            run.last_trade_id,
+            run.started_at.timestamp(),
+            run.duration_ms
+            ])?;
+        let run_id = tx.last_insert_rowid() as i32;
+        tx.execute("DELETE FROM chain_trades WHERE handle = ?1", [handle])?;
+        for (trade_id, hash) in &run.trade_hashes {
+            tx.execute("INSERT INTO chain_trades (trade_id, handle, hash) VALUES (?1, ?2, ?3)", params![trade_id, handle, hash])?;
+        }
+        tx.commit()?;
+        Ok(run_id)
+    }
+}
+
+fn trade_from_row(row: &rusqlite::Row) -> rusqlite::Result<Trade> {
+    Ok(Trade {
+        id: Some(row.get("id")?),
+        handle: row.get("handle")?,
+        filename: row.get("filename")?,
+        filehash: row.get("filehash")?,
+        row: row.get("row")?,
+        account_name: row.get("account_name")?,
+        account_number: row.get("account_number")?,
+        account_type: row.get("account_type")?,
+        account_id: row.get("account_id")?,
+        security_description: row.get("security_description")?,
+        security_ticker: row.get("security_ticker")?,
+        asset_class: row.get("asset_class")?,
+        security_type: row.get("security_type")?,
+        tx_type: row.get("tx_type")?,
+        source_tx_type: row.get("source_tx_type")?,
+        cusip: row.get("cusip")?,
+        price: row.get("price")?,
+        quantity: row.get("quantity")?,
+        commission: row.get("commission")?,
+        fee: row.get("fee")?,
+        principal: row.get("principal")?,
+        net_amount: row.get("net_amount")?,
+        currency: row.get("currency")?,
+        trade_date: row.get("trade_date")?,
+        settlement_date: row.get("settlement_date")?,
+        broker: row.get("broker")?,
+        trader: row.get("trader")?,
+    })
+}
+
+fn chain_metrics(row: &rusqlite::Row) -> rusqlite::Result<ChainMetrics> {
+    Ok(ChainMetrics {
+        holding_secs: row.get("holding_secs")?,
+        net_quantity: row.get("net_quantity")?,
+        gross_notional: row.get("gross_notional")?,
+        realized_pnl: row.get("realized_pnl")?,
+        costs: row.get("costs")?,
+        flat: row.get("flat")?,
+    })
+}
+
+fn insert_trade_row(conn: &Connection, trade: &Trade) -> Result<(), Error> {
+    info!("{:?}", trade);
+    conn.execute("INSERT INTO trades (
+        handle, filename, filehash, row, account_name, account_number, account_type, account_id,
+        security_ticker, security_description, asset_class, security_type, tx_type, source_tx_type, cusip,
+        price, quantity, commission, fee, principal, net_amount, currency,
+        trade_date, settlement_date, broker, trader
+        ) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17, ?18, ?19, ?20, ?21, ?22, ?23, ?24, ?25, ?26)
+        ON CONFLICT (handle, filehash, row) DO NOTHING",
+        params![
+        trade.handle,
+        trade.filename,
+        trade.filehash,
+        trade.row,
+        trade.account_name,
+        trade.account_number,
+        trade.account_type,
+        trade.account_id,
+        trade.security_ticker,
+        trade.security_description,
+        trade.asset_class,
+        trade.security_type,
+        trade.tx_type,
+        trade.source_tx_type,
+        trade.cusip,
+        trade.price,
+        trade.quantity,
+        trade.commission,
+        trade.fee,
+        trade.principal,
+        trade.net_amount,
+        trade.currency,
+        trade.trade_date,
+        trade.settlement_date,
+        trade.broker,
+        trade.trader
+        ])?;
+    Ok(())
+}
+
+#[async_trait]
+impl TradeStore for SqliteStore {
+    async fn insert_trade(&self, trade: &Trade) -> Result<(), Error> {
+        insert_trade_row(&self.conn.lock().unwrap(), trade)
+    }
+
+    async fn insert_trades(&self, trades: &[Trade]) -> Result<(), Error> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        for trade in trades {
+            insert_trade_row(&tx, trade)?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    async fn get_all_trades(&self, handle: &str) -> Result<Vec<Trade>, Error> {
+        let conn = self.conn.lock().unwrap();
+        let mut statement = conn.prepare("SELECT * FROM trades WHERE handle = ?1 ORDER BY id")?;
+        let trades = statement.query_map([handle], trade_from_row)?.collect::<Result<Vec<Trade>, _>>()?;
+        Ok(trades)
+    }
+
+    async fn get_security_master(&self) -> Result<SecurityMaster, Error> {
+        Ok(self.reference.master.clone())
+    }
+
+    async fn get_tx_type_map(&self, source: &str) -> Result<TxTypeMap, Error> {
+        Ok(self.reference.tx_type_map(source))
+    }
+
+    async fn get_hierarchy(&self) -> Result<Hierarchy, Error> {
+        Ok(self.reference.hierarchy.clone())
+    }
+
+    async fn get_corporate_actions(&self) -> Result<Vec<CorporateAction>, Error> {
+        Ok(self.reference.corporate_actions.clone())
+    }
+
+    async fn get_fx_rates(&self) -> Result<Vec<FxRate>, Error> {
+        Ok(self.reference.fx_rates.clone())
+    }
+
+    async fn get_base_currency(&self, handle: &str) -> Result<String, Error> {
+        Ok(self.reference.base_currency(handle))
+    }
+
+    async fn insert_fx_audit(&self, handle: &str, context: &str, reference: &str, c: &Conversion) -> Result<(), Error> {
+        self.conn.lock().unwrap().execute("INSERT INTO fx_audit (
+            handle, context, reference, from_currency, to_currency, as_of_date, rate_date, rate, source, amount, converted
+            ) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)",
+            params![
+            handle,
+            context,
+            reference,
+            c.from_currency,
+            c.to_currency,
+            c.as_of_date.to_string(),
+            c.rate_date.to_string(),
+            c.rate,
+            c.source,
+            c.amount,
+            c.converted
+            ])?;
+        Ok(())
+    }
+
+    async fn get_commission_targets(&self, handle: &str) -> Result<Vec<CommissionTarget>, Error> {
+        Ok(self.reference.commission_targets(handle))
+    }
+}
+
+#[async_trait]
+impl SummaryStore for SqliteStore {
+    async fn insert_file_summary(&self, s: &FileSummary) -> Result<(), Error> {
+        self.conn.lock().unwrap().execute("INSERT INTO file_summaries (handle, filename, filehash, calc) VALUES (?1, ?2, ?3, ?4)",
+            params![s.handle, s.filename, s.filehash, s.calc])?;
+        Ok(())
+    }
+
+    async fn insert_account_summary(&self, s: &AccountSummary) -> Result<(), Error> {
+        self.conn.lock().unwrap().execute("INSERT INTO account_summaries (handle, tx_type, direction, account_name, currency, calc) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![s.handle, s.tx_type, s.direction, s.account_name, s.currency, s.calc])?;
+        Ok(())
+    }
+
+    async fn insert_security_summary(&self, s: &SecuritySummary) -> Result<(), Error> {
+        self.conn.lock().unwrap().execute("INSERT INTO security_summaries (handle, tx_type, direction, security_ticker, currency, calc) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![s.handle, s.tx_type, s.direction, s.security_ticker, s.currency, s.calc])?;
+        Ok(())
+    }
+
+    async fn insert_hierarchy_summary(&self, s: &HierarchySummary) -> Result<(), Error> {
+        self.conn.lock().unwrap().execute("INSERT INTO hierarchy_summaries (handle, level, node, tx_type, direction, currency, calc) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![s.handle, s.level, s.node, s.tx_type, s.direction, s.currency, s.calc])?;
+        Ok(())
+    }
+
+    async fn insert_commission_summary(&self, s: &CommissionSummary) -> Result<(), Error> {
+        self.conn.lock().unwrap().execute("INSERT INTO commission_summaries (
+            handle, dimension, name, period, currency, trades, shares, notional, commission, fee, cents_per_share, bps_of_notional, commission_share, target_share
+            ) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14)",
+            params![s.handle, s.dimension, s.name, s.period, s.currency, s.trades, s.shares, s.notional, s.commission, s.fee,
+                s.cents_per_share, s.bps_of_notional, s.commission_share, s.target_share])?;
+        Ok(())
+    }
+
+    async fn replace_chains(&self, handle: &str, chains: &[TradeChain], run: &ChainRun) -> Result<i32, Error> {
+        self.write_chains(handle, chains, None, run)
+    }
+
+    async fn update_chains(&self, handle: &str, chains: &[TradeChain], removed: &[String], run: &ChainRun) -> Result<i32, Error> {
+        self.write_chains(handle, chains, Some(removed), run)
+    }
+
+    async fn get_chains(&self, handle: &str) -> Result<Vec<TradeChain>, Error> {
+        let conn = self.conn.lock().unwrap();
+        let mut statement = conn.prepare("SELECT m.chain_id, m.role, c.holding_secs, c.net_quantity, c.gross_notional, c.realized_pnl, c.costs, c.flat, t.*
+            FROM chain_members m
+            JOIN chains c ON c.id = m.chain_id
+            JOIN trades t ON t.id = m.trade_id
+            WHERE c.handle = ?1 ORDER BY m.chain_id, m.position")?;
+        let rows = statement.query_map([handle], |r| Ok((r.get::<_, String>("chain_id")?, r.get::<_, String>("role")?, chain_metrics(r)?, trade_from_row(r)?)))?;
+        let mut v: Vec<TradeChain> = Vec::new();
+        for r in rows {
+            let (chain_id, role, metrics, t) = r?;
+            if role == trades::HEAD_ROLE {
+                v.push(TradeChain { chain_id, head: t, chain: Vec::new(), metrics });
+            } else if let Some(c) = v.last_mut().filter(|c| c.chain_id == chain_id) {
+                c.chain.push(t);
+            }
+        }
+        Ok(v)
+    }
+
+    async fn get_last_chain_run(&self, handle: &str) -> Result<Option<ChainRun>, Error> {
+        let conn = self.conn.lock().unwrap();
+        let mut statement = conn.prepare("SELECT * FROM chain_runs WHERE handle = ?1 ORDER BY run_id DESC LIMIT 1")?;
+        let mut runs = statement.query_map([handle], |r| {
+            let parameters: String = r.get("parameters")?;
+            Ok(ChainRun {
+                run_id: Some(r.get("run_id")?),
+                handle: r.get("handle")?,
+                mode: r.get("mode")?,
+                parameters: serde_json::from_str(&parameters).unwrap_or(ChainParameters { rule: parameters, min_tx_types: 0, corporate_actions: 0 }),
+                trades: r.get("trades")?,
+                chains: r.get("chains")?,
+                members: r.get("members")?,
+                removed: r.get("removed")?,
+                last_trade_id: r.get("last_trade_id")?,
+                trade_hashes: BTreeMap::new(),
+                started_at: NaiveDateTime::from_timestamp_opt(r.get("started_at")?, 0).unwrap_or_default(),
+                duration_ms: r.get("duration_ms")?,
+            })
+        })?;
+        let Some(mut run) = runs.next().transpose()? else {
+            return Ok(None)
+        };
+        let mut statement = conn.prepare("SELECT trade_id, hash FROM chain_trades WHERE handle = ?1")?;
+        run.trade_hashes = statement.query_map([handle], |r| Ok((r.get(0)?, r.get(1)?)))?.collect::<Result<_, _>>()?;
+        Ok(Some(run))
+    }
+
+    /// altpilot reads the one file too, chain_rows already has them in its layout.
+    async fn publish_chains(&self, _handle: &str, _chains: &[TradeChain]) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn trade(tx_type: &str, day: u32, quantity: f64) -> Trade {
+        Trade { id: None, row: day as i32, account_id: Some(7), ..Trade::test(0, tx_type, day, quantity, 10.) }
+    }
+
+    #[tokio::test]
+    async fn trades_round_trip_and_chain() {
+        let store = SqliteStore::open_in_memory().unwrap();
+        store.insert_trade(&trade("BUY", 1, 100.)).await.unwrap();
+        store.insert_trade(&trade("SELL", 2, -100.)).await.unwrap();
+
+        let trades = store.get_all_trades("rivernorth").await.unwrap();
+        assert_eq!(trades.iter().map(|t| t.id.unwrap()).collect::<Vec<_>>(), vec![1, 2]);
+        assert_eq!((trades[1].account_id, trades[1].net_amount), (Some(7), 1000.));
+
+        trades::chain(&store, &store, &store, "rivernorth").await.unwrap();
+        let run = trades::chain(&store, &store, &store, "rivernorth").await.unwrap();
+        assert_eq!(run.run_id, Some(2));
+        // the second run's hashes replace the first's rather than piling up
+        let hashes: i64 = store.conn.lock().unwrap().query_row("SELECT count(*) FROM chain_trades", [], |r| r.get(0)).unwrap();
+        assert_eq!(hashes, 2);
+        assert_eq!(store.get_last_chain_run("rivernorth").await.unwrap().unwrap().trade_hashes, run.trade_hashes);
+        let rows: i64 = store.conn.lock().unwrap().query_row("SELECT count(*) FROM chain_rows", [], |r| r.get(0)).unwrap();
+        assert_eq!(rows, 3);
+        assert!(store.conn.lock().unwrap().execute("DELETE FROM trades WHERE id = 1", []).is_err());
+
+        let chains = store.get_chains("rivernorth").await.unwrap();
+        assert_eq!(chains[0].metrics, trades::ChainMetrics::of(&chains[0].head, &chains[0].chain));
+    }
+
+    #[tokio::test]
+    async fn a_file_of_trades_goes_in_whole_or_not_at_all_and_once() {
+        let store = SqliteStore::open_in_memory().unwrap();
+        store.conn.lock().unwrap().execute_batch("CREATE TRIGGER no_third BEFORE INSERT ON trades WHEN NEW.row = 3
+            BEGIN SELECT RAISE(ABORT, 'no third row'); END").unwrap();
+
+        assert!(store.insert_trades(&[trade("BUY", 1, 100.), trade("SELL", 2, -50.), trade("SELL", 3, -50.)]).await.is_err());
+        assert!(store.get_all_trades("rivernorth").await.unwrap().is_empty());
+
+        store.insert_trades(&[trade("BUY", 1, 100.), trade("SELL", 2, -50.)]).await.unwrap();
+        assert_eq!(store.get_all_trades("rivernorth").await.unwrap().len(), 2);
+
+        // the same file again adds nothing, so its chain heads don't turn up twice
+        store.insert_trades(&[trade("BUY", 1, 100.), trade("SELL", 2, -50.)]).await.unwrap();
+        assert_eq!(store.get_all_trades("rivernorth").await.unwrap().len(), 2);
+        trades::chain(&store, &store, &store, "rivernorth").await.unwrap();
+    }
+}
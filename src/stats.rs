@@ -0,0 +1,341 @@
+use crate::corporate_actions::{self, CorporateAction};
+use crate::error::Error;
+use crate::fx::{self, FxRate};
+use crate::nav;
+use crate::positions;
+use crate::prices::{self, Price};
+use crate::trades::{self, Trade};
+use crate::tx_types::Direction;
+use crate::utils;
+use chrono::{Datelike, Duration as ChronoDuration, Months, NaiveDate};
+use serde::{Serialize,Deserialize};
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+use std::str::FromStr;
+use tokio_postgres::{Row, Transaction};
+use tracing::{info, warn};
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Period {
+    Month,
+    Quarter,
+    Year
+}
+
+impl Period {
+    pub const ALL: [Period; 3] = [Period::Month, Period::Quarter, Period::Year];
+
+    /// First and last day of the period the date falls in.
+    pub fn bounds(&self, date: NaiveDate) -> (NaiveDate, NaiveDate) {
+        let (month, months) = match self {
+            Period::Month => (date.month(), 1),
+            Period::Quarter => ((date.month() - 1) / 3 * 3 + 1, 3),
+            Period::Year => (1, 12)
+        };
+        let start = NaiveDate::from_ymd_opt(date.year(), month, 1).unwrap();
+        (start, start + Months::new(months) - ChronoDuration::days(1))
+    }
+
+    /// 2019-11, 2019-Q4 or 2019
+    pub fn label(&self, date: NaiveDate) -> String {
+        match self {
+            Period::Month => date.format("%Y-%m").to_string(),
+            Period::Quarter => format!("{}-Q{}", date.year(), (date.month() - 1) / 3 + 1),
+            Period::Year => date.year().to_string()
+        }
+    }
+}
+
+impl fmt::Display for Period {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Period::Month => write!(f, "MONTH"),
+            Period::Quarter => write!(f, "QUARTER"),
+            Period::Year => write!(f, "YEAR"),
+        }
+    }
+}
+
+impl FromStr for Period {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_uppercase().as_str() {
+            "MONTH" => Ok(Period::Month),
+            "QUARTER" => Ok(Period::Quarter),
+            "YEAR" => Ok(Period::Year),
+            other => Err(format!("unknown period {}", other))
+        }
+    }
+}
+
+/// Trading activity for a handle over one period, money in the fund's base currency.
+/// turnover_ratio is the smaller of purchases and sales over the average of the opening and closing market value,
+/// buy_sell_ratio is purchases over sales and isn't there when nothing was sold.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct TradingStats {
+    pub handle: String,
+    pub period_type: String,
+    pub period: String,
+    pub period_start: NaiveDate,
+    pub period_end: NaiveDate,
+    pub currency: String,
+    pub trades: i32,
+    pub buys: i32,
+    pub sells: i32,
+    pub purchases: f64,
+    pub sales: f64,
+    pub average_trade_size: f64,
+    pub buy_sell_ratio: Option<f64>,
+    pub distinct_securities: i32,
+    pub opening_market_value: f64,
+    pub closing_market_value: f64,
+    pub turnover_ratio: f64
+}
+
+impl From<Row> for TradingStats {
+    fn from(row: tokio_postgres::Row) -> Self {
+        Self {
+            handle: row.get("handle"),
+            period_type: row.get("period_type"),
+            period: row.get("period"),
+            period_start: row.get("period_start"),
+            period_end: row.get("period_end"),
+            currency: row.get("currency"),
+            trades: row.get("trades"),
+            buys: row.get("buys"),
+            sells: row.get("sells"),
+            purchases: row.get("purchases"),
+            sales: row.get("sales"),
+            average_trade_size: row.get("average_trade_size"),
+            buy_sell_ratio: row.get("buy_sell_ratio"),
+            distinct_securities: row.get("distinct_securities"),
+            opening_market_value: row.get("opening_market_value"),
+            closing_market_value: row.get("closing_market_value"),
+            turnover_ratio: row.get("turnover_ratio"),
+        }
+    }
+}
+
+pub async fn build_stats_table(client: &tokio_postgres::Client) -> Result<(), Error> {
+
+    client.query("CREATE TABLE trading_stats (id SERIAL PRIMARY KEY,
+        handle VARCHAR NOT NULL,
+        period_type VARCHAR NOT NULL,
+        period VARCHAR NOT NULL,
+        period_start DATE NOT NULL,
+        period_end DATE NOT NULL,
+        currency VARCHAR NOT NULL,
+        trades INT NOT NULL,
+        buys INT NOT NULL,
+        sells INT NOT NULL,
+        purchases FLOAT8 NOT NULL,
+        sales FLOAT8 NOT NULL,
+        average_trade_size FLOAT8 NOT NULL,
+        buy_sell_ratio FLOAT8,
+        distinct_securities INT NOT NULL,
+        opening_market_value FLOAT8 NOT NULL,
+        closing_market_value FLOAT8 NOT NULL,
+        turnover_ratio FLOAT8 NOT NULL,
+        UNIQUE (handle, period_type, period)
+        )", &[]).await?;
+
+    Ok(())
+}
+
+pub async fn drop_stats_table(client: &tokio_postgres::Client) -> Result<(), Error> {
+
+    client.query("drop TABLE trading_stats", &[]).await?;
+
+    Ok(())
+}
+
+async fn clean_stats(client: &Transaction<'_>, handle: &str) -> Result<(), Error> {
+
+    let statement = client.prepare("delete from trading_stats where handle = $1").await?;
+    client.execute(&statement,&[&handle]).await?;
+
+    Ok(())
+}
+
+async fn insert_stats(client: &Transaction<'_>, s: &TradingStats) -> Result<(), Error> {
+
+    let statement = client.prepare("INSERT INTO trading_stats (
+        handle,
+        period_type,
+        period,
+        period_start,
+        period_end,
+        currency,
+        trades,
+        buys,
+        sells,
+        purchases,
+        sales,
+        average_trade_size,
+        buy_sell_ratio,
+        distinct_securities,
+        opening_market_value,
+        closing_market_value,
+        turnover_ratio
+        ) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16, $17)").await?;
+
+    client.execute(&statement,&[
+        &s.handle,
+        &s.period_type,
+        &s.period,
+        &s.period_start,
+        &s.period_end,
+        &s.currency,
+        &s.trades,
+        &s.buys,
+        &s.sells,
+        &s.purchases,
+        &s.sales,
+        &s.average_trade_size,
+        &s.buy_sell_ratio,
+        &s.distinct_securities,
+        &s.opening_market_value,
+        &s.closing_market_value,
+        &s.turnover_ratio
+        ]).await?;
+    Ok(())
+}
+
+pub async fn get_stats(client: &tokio_postgres::Client, handle: &str) -> Result<Vec<TradingStats>, Error> {
+
+    let rows = client.query("SELECT * FROM trading_stats WHERE handle = $1 ORDER BY period_type, period_start", &[&handle]).await?;
+    Ok(rows.into_iter().map(TradingStats::from).collect())
+}
+
+/// Every period of the given kind with a buy or sell in it. Positions for the opening and closing values come
+/// from the whole trade history, valued the way the nav does it.
+pub fn stats(handle: &str, trades: &[Trade], actions: &[CorporateAction], prices: &[Price], rates: &[FxRate], base_currency: &str, period: Period) -> Vec<TradingStats> {
+
+    let mut g: BTreeMap<NaiveDate, Vec<&Trade>> = BTreeMap::new();
+    for t in trades.iter().filter(|t| t.tx().is_trade()) {
+        g.entry(period.bounds(utils::to_date(t.trade_date)).0).or_default().push(t);
+    }
+
+    let value_at = |date: NaiveDate| {
+        let close = date.and_hms_opt(23, 59, 59).unwrap().timestamp();
+        let held = positions::reconstruct(trades, actions, close);
+        nav::market_value(&held, prices, rates, base_currency, date).0
+    };
+
+    g.into_iter().map(|(start, ts)| {
+        let (_, end) = period.bounds(start);
+        let notional = |t: &Trade| {
+            let local = (t.quantity * t.price).abs();
+            match fx::convert(rates, local, &t.currency, base_currency, utils::to_date(t.trade_date)) {
+                Some(c) => c.converted,
+                None => {
+                    warn!("no {}/{} rate for trade {:?}, leaving it unconverted", t.currency, base_currency, t.id);
+                    local
+                }
+            }
+        };
+        let buys: Vec<&&Trade> = ts.iter().filter(|t| t.direction() == Direction::Buy).collect();
+        let sells: Vec<&&Trade> = ts.iter().filter(|t| t.direction() == Direction::Sell).collect();
+        let purchases: f64 = buys.iter().map(|t| notional(t)).sum();
+        let sales: f64 = sells.iter().map(|t| notional(t)).sum();
+
+        let opening_market_value = value_at(start - ChronoDuration::days(1));
+        let closing_market_value = value_at(end);
+        let average = (opening_market_value + closing_market_value) / 2.;
+
+        TradingStats {
+            handle: handle.to_string(),
+            period_type: period.to_string(),
+            period: period.label(start),
+            period_start: start,
+            period_end: end,
+            currency: base_currency.to_string(),
+            trades: ts.len() as i32,
+            buys: buys.len() as i32,
+            sells: sells.len() as i32,
+            purchases,
+            sales,
+            average_trade_size: (purchases + sales) / ts.len() as f64,
+            buy_sell_ratio: if sales != 0. { Some(purchases / sales) } else { None },
+            distinct_securities: ts.iter().map(|t| t.security_ticker.to_uppercase()).collect::<HashSet<String>>().len() as i32,
+            opening_market_value,
+            closing_market_value,
+            turnover_ratio: if average != 0. { purchases.min(sales) / average } else { 0. }
+        }
+    }).collect()
+}
+
+/// Monthly, quarterly and yearly stats for the handle, replacing what was there in one transaction.
+pub async fn compute(client: &mut tokio_postgres::Client, handle: &str) -> Result<(), Error> {
+
+    let all_trades = trades::get_all_trades(client, handle).await?;
+    let (Some(first), Some(last)) = (all_trades.iter().map(|t| t.trade_date).min(), all_trades.iter().map(|t| t.trade_date).max()) else {
+        info!("no trades for {:?}, no stats", handle);
+        return Ok(())
+    };
+    let from = Period::Year.bounds(utils::to_date(first)).0 - ChronoDuration::days(prices::MAX_STALE_DAYS + 1);
+    let to = Period::Year.bounds(utils::to_date(last)).1;
+    let all_prices = prices::get_all_prices(client, from, to).await?;
+    let base_currency = fx::get_base_currency(client, handle).await?;
+    let rates = fx::get_fx_rates(client).await?;
+    let actions = corporate_actions::get_corporate_actions(client).await?;
+
+    let tx = client.transaction().await?;
+    info!("first I'll clean up the trading stats for {:?}", handle);
+    clean_stats(&tx, handle).await?;
+
+    for period in Period::ALL {
+        for s in stats(handle, &all_trades, &actions, &all_prices, &rates, &base_currency, period) {
+            info!("{:?}", s);
+            insert_stats(&tx, &s).await?;
+        }
+    }
+    tx.commit().await?;
+
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::prices::PriceType;
+
+    fn trade(id: i32, tx_type: &str, month: u32, day: u32, ticker: &str, quantity: f64, price: f64) -> Trade {
+        Trade { security_ticker: ticker.to_string(), ..Trade::test(id, tx_type, day, quantity, price).at(month, day, 16) }
+    }
+
+    #[test]
+    fn turnover_and_activity_by_period() {
+        let (start, end) = Period::Quarter.bounds(NaiveDate::from_ymd_opt(2019, 11, 20).unwrap());
+        assert_eq!((start, end), (NaiveDate::from_ymd_opt(2019, 10, 1).unwrap(), NaiveDate::from_ymd_opt(2019, 12, 31).unwrap()));
+        assert_eq!(Period::Quarter.label(start), "2019-Q4");
+
+        let trades = vec![
+            trade(1, "BUY", 11, 4, "OPP", 100., 10.),
+            trade(2, "SELL", 11, 20, "OPP", -50., 12.),
+            trade(3, "DIVIDEND", 11, 25, "OPP", 0., 0.),
+            trade(4, "BUY", 12, 2, "XYZ", 10., 5.),
+        ];
+        let prices = vec![
+            Price { security_id: "OPP".to_string(), price_date: NaiveDate::from_ymd_opt(2019, 11, 29).unwrap(), source: "admin".to_string(), price_type: PriceType::Close, price: 12. },
+        ];
+
+        let months = stats("rivernorth", &trades, &[], &prices, &[], "USD", Period::Month);
+        assert_eq!(months.iter().map(|s| s.period.as_str()).collect::<Vec<_>>(), vec!["2019-11", "2019-12"]);
+        let nov = &months[0];
+        assert_eq!((nov.trades, nov.buys, nov.sells, nov.distinct_securities), (2, 1, 1, 1));
+        assert_eq!((nov.purchases, nov.sales, nov.average_trade_size), (1000., 600., 800.));
+        assert_eq!((nov.opening_market_value, nov.closing_market_value), (0., 600.));
+        assert!((nov.turnover_ratio - 2.).abs() < 1e-9);
+        assert!((nov.buy_sell_ratio.unwrap() - 1000. / 600.).abs() < 1e-9);
+        assert_eq!(months[1].buy_sell_ratio, None);
+
+        let quarter = stats("rivernorth", &trades, &[], &prices, &[], "USD", Period::Quarter);
+        assert_eq!(quarter.len(), 1);
+        assert_eq!((quarter[0].trades, quarter[0].distinct_securities), (3, 2));
+        assert_eq!(Period::Year.label(quarter[0].period_start), "2019");
+    }
+}
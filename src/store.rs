@@ -0,0 +1,338 @@
+use crate::commissions::{CommissionSummary, CommissionTarget};
+use crate::corporate_actions::CorporateAction;
+use crate::error::Error;
+use crate::fx::{self, Conversion, FxRate};
+use crate::hierarchy::Hierarchy;
+use crate::securities::SecurityMaster;
+use crate::trades::{AccountSummary, ChainRun, FileSummary, HierarchySummary, SecuritySummary, Trade, TradeChain};
+use crate::tx_types::TxTypeMap;
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Mutex;
+
+/// Which database parse, summarize and chain run against, Postgres unless told otherwise.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Backend {
+    #[default]
+    Postgres,
+    Sqlite
+}
+
+impl fmt::Display for Backend {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Backend::Postgres => write!(f, "postgres"),
+            Backend::Sqlite => write!(f, "sqlite"),
+        }
+    }
+}
+
+impl FromStr for Backend {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "postgres" | "postgresql" => Ok(Backend::Postgres),
+            "sqlite" => Ok(Backend::Sqlite),
+            other => Err(Error::Validation(format!("no storage backend called {:?}", other)))
+        }
+    }
+}
+
+/// What parse, summarize and chain read and write on the tradellama side.
+#[async_trait]
+pub trait TradeStore: Send + Sync {
+    async fn insert_trade(&self, trade: &Trade) -> Result<(), Error>;
+    /// All of them or none of them. A trade already in, the same handle, file hash and row, is left as it is, so
+    /// loading a file again adds nothing and chain ids, which come off those three, stay one per trade.
+    async fn insert_trades(&self, trades: &[Trade]) -> Result<(), Error>;
+    async fn get_all_trades(&self, handle: &str) -> Result<Vec<Trade>, Error>;
+    async fn get_security_master(&self) -> Result<SecurityMaster, Error>;
+    async fn get_tx_type_map(&self, source: &str) -> Result<TxTypeMap, Error>;
+    async fn get_hierarchy(&self) -> Result<Hierarchy, Error>;
+    async fn get_corporate_actions(&self) -> Result<Vec<CorporateAction>, Error>;
+    async fn get_fx_rates(&self) -> Result<Vec<FxRate>, Error>;
+    async fn get_base_currency(&self, handle: &str) -> Result<String, Error>;
+    async fn insert_fx_audit(&self, handle: &str, context: &str, reference: &str, c: &Conversion) -> Result<(), Error>;
+    async fn get_commission_targets(&self, handle: &str) -> Result<Vec<CommissionTarget>, Error>;
+}
+
+/// Where summaries and chains end up, altpilot in production.
+#[async_trait]
+pub trait SummaryStore: Send + Sync {
+    async fn insert_file_summary(&self, s: &FileSummary) -> Result<(), Error>;
+    async fn insert_account_summary(&self, s: &AccountSummary) -> Result<(), Error>;
+    async fn insert_security_summary(&self, s: &SecuritySummary) -> Result<(), Error>;
+    async fn insert_hierarchy_summary(&self, s: &HierarchySummary) -> Result<(), Error>;
+    async fn insert_commission_summary(&self, s: &CommissionSummary) -> Result<(), Error>;
+    /// Drops the handle's chains, writes the new ones and records the run as one unit, handing back the run id.
+    async fn replace_chains(&self, handle: &str, chains: &[TradeChain], run: &ChainRun) -> Result<i32, Error>;
+    /// The same but only the removed chain ids go.
+    async fn update_chains(&self, handle: &str, chains: &[TradeChain], removed: &[String], run: &ChainRun) -> Result<i32, Error>;
+    async fn get_chains(&self, handle: &str) -> Result<Vec<TradeChain>, Error>;
+    async fn get_last_chain_run(&self, handle: &str) -> Result<Option<ChainRun>, Error>;
+    /// Replaces the handle's rows in altpilot's chains layout, a head row then one per member.
+    async fn publish_chains(&self, handle: &str, chains: &[TradeChain]) -> Result<(), Error>;
+}
+
+/// Security master, tx type mappings, hierarchy, corporate actions and fx for the backends that don't keep their own.
+/// Defaults are what an empty tradellama would hand back.
+#[derive(Clone, Debug, Default)]
+pub struct ReferenceData {
+    pub master: SecurityMaster,
+    pub tx_type_maps: HashMap<String, TxTypeMap>,
+    pub hierarchy: Hierarchy,
+    pub corporate_actions: Vec<CorporateAction>,
+    pub fx_rates: Vec<FxRate>,
+    pub base_currencies: HashMap<String, String>,
+    pub commission_targets: Vec<CommissionTarget>
+}
+
+impl ReferenceData {
+    pub fn tx_type_map(&self, source: &str) -> TxTypeMap {
+        self.tx_type_maps.get(source).cloned().unwrap_or_else(|| TxTypeMap::defaults(source))
+    }
+
+    pub fn base_currency(&self, handle: &str) -> String {
+        self.base_currencies.get(handle).cloned().unwrap_or(fx::DEFAULT_CURRENCY.to_string())
+    }
+
+    pub fn commission_targets(&self, handle: &str) -> Vec<CommissionTarget> {
+        self.commission_targets.iter().filter(|t| t.handle == handle).cloned().collect()
+    }
+}
+
+/// Both sides in memory, reference data set up front and everything written kept for a test to look at.
+/// Trades get ids in insert order the way the SERIAL column hands them out.
+#[derive(Default)]
+pub struct MemoryStore {
+    pub reference: ReferenceData,
+    pub trades: Mutex<Vec<Trade>>,
+    pub fx_audit: Mutex<Vec<(String, String, String, Conversion)>>,
+    pub file_summaries: Mutex<Vec<FileSummary>>,
+    pub account_summaries: Mutex<Vec<AccountSummary>>,
+    pub security_summaries: Mutex<Vec<SecuritySummary>>,
+    pub hierarchy_summaries: Mutex<Vec<HierarchySummary>>,
+    pub commission_summaries: Mutex<Vec<CommissionSummary>>,
+    pub chains: Mutex<Vec<TradeChain>>,
+    pub chain_runs: Mutex<Vec<ChainRun>>,
+    pub published_chains: Mutex<Vec<TradeChain>>
+}
+
+#[async_trait]
+impl TradeStore for MemoryStore {
+    async fn insert_trade(&self, trade: &Trade) -> Result<(), Error> {
+        self.insert_trades(std::slice::from_ref(trade)).await
+    }
+
+    async fn insert_trades(&self, trades: &[Trade]) -> Result<(), Error> {
+        let mut v = self.trades.lock().unwrap();
+        for trade in trades {
+            if v.iter().any(|t| (&t.handle, &t.filehash, t.row) == (&trade.handle, &trade.filehash, trade.row)) {
+                continue
+            }
+            let mut t = trade.clone();
+            t.id = Some(v.len() as i32 + 1);
+            v.push(t);
+        }
+        Ok(())
+    }
+
+    async fn get_all_trades(&self, handle: &str) -> Result<Vec<Trade>, Error> {
+        Ok(self.trades.lock().unwrap().iter().filter(|t| t.handle == handle).cloned().collect())
+    }
+
+    async fn get_security_master(&self) -> Result<SecurityMaster, Error> {
+        Ok(self.reference.master.clone())
+    }
+
+    async fn get_tx_type_map(&self, source: &str) -> Result<TxTypeMap, Error> {
+        Ok(self.reference.tx_type_map(source))
+    }
+
+    async fn get_hierarchy(&self) -> Result<Hierarchy, Error> {
+        Ok(self.reference.hierarchy.clone())
+    }
+
+    async fn get_corporate_actions(&self) -> Result<Vec<CorporateAction>, Error> {
+        Ok(self.reference.corporate_actions.clone())
+    }
+
+    async fn get_fx_rates(&self) -> Result<Vec<FxRate>, Error> {
+        Ok(self.reference.fx_rates.clone())
+    }
+
+    async fn get_base_currency(&self, handle: &str) -> Result<String, Error> {
+        Ok(self.reference.base_currency(handle))
+    }
+
+    async fn insert_fx_audit(&self, handle: &str, context: &str, reference: &str, c: &Conversion) -> Result<(), Error> {
+        self.fx_audit.lock().unwrap().push((handle.to_string(), context.to_string(), reference.to_string(), c.clone()));
+        Ok(())
+    }
+
+    async fn get_commission_targets(&self, handle: &str) -> Result<Vec<CommissionTarget>, Error> {
+        Ok(self.reference.commission_targets(handle))
+    }
+}
+
+#[async_trait]
+impl SummaryStore for MemoryStore {
+    async fn insert_file_summary(&self, s: &FileSummary) -> Result<(), Error> {
+        self.file_summaries.lock().unwrap().push(s.clone());
+        Ok(())
+    }
+
+    async fn insert_account_summary(&self, s: &AccountSummary) -> Result<(), Error> {
+        self.account_summaries.lock().unwrap().push(s.clone());
+        Ok(())
+    }
+
+    async fn insert_security_summary(&self, s: &SecuritySummary) -> Result<(), Error> {
+        self.security_summaries.lock().unwrap().push(s.clone());
+        Ok(())
+    }
+
+    async fn insert_hierarchy_summary(&self, s: &HierarchySummary) -> Result<(), Error> {
+        self.hierarchy_summaries.lock().unwrap().push(s.clone());
+        Ok(())
+    }
+
+    async fn insert_commission_summary(&self, s: &CommissionSummary) -> Result<(), Error> {
+        self.commission_summaries.lock().unwrap().push(s.clone());
+        Ok(())
+    }
+
+    async fn replace_chains(&self, handle: &str, chains: &[TradeChain], run: &ChainRun) -> Result<i32, Error> {
+        let ids: Vec<String> = self.get_chains(handle).await?.into_iter().map(|c| c.chain_id).collect();
+        self.update_chains(handle, chains, &ids, run).await
+    }
+
+    async fn update_chains(&self, handle: &str, chains: &[TradeChain], removed: &[String], run: &ChainRun) -> Result<i32, Error> {
+        let mut v = self.chains.lock().unwrap();
+        v.retain(|c| c.head.handle != handle || !removed.contains(&c.chain_id));
+        v.extend(chains.iter().cloned());
+        let mut runs = self.chain_runs.lock().unwrap();
+        // only the latest run's hashes are kept, the way chain_trades has them
+        for old in runs.iter_mut().filter(|r| r.handle == handle) {
+            old.trade_hashes.clear();
+        }
+        let mut r = run.clone();
+        r.run_id = Some(runs.len() as i32 + 1);
+        runs.push(r);
+        Ok(runs.len() as i32)
+    }
+
+    async fn get_chains(&self, handle: &str) -> Result<Vec<TradeChain>, Error> {
+        Ok(self.chains.lock().unwrap().iter().filter(|c| c.head.handle == handle).cloned().collect())
+    }
+
+    async fn get_last_chain_run(&self, handle: &str) -> Result<Option<ChainRun>, Error> {
+        Ok(self.chain_runs.lock().unwrap().iter().rev().find(|r| r.handle == handle).cloned())
+    }
+
+    async fn publish_chains(&self, handle: &str, chains: &[TradeChain]) -> Result<(), Error> {
+        let mut v = self.published_chains.lock().unwrap();
+        v.retain(|c| c.head.handle != handle);
+        v.extend(chains.iter().cloned());
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::trades;
+    use chrono::NaiveDate;
+    use itertools::Itertools;
+
+    fn trade(tx_type: &str, day: u32, quantity: f64) -> Trade {
+        Trade { id: None, row: day as i32, ..Trade::test(0, tx_type, day, quantity, 10.) }
+    }
+
+    #[tokio::test]
+    async fn summarize_and_chain_in_memory() {
+        let mut store = MemoryStore::default();
+        store.reference.base_currencies.insert("rivernorth".to_string(), "EUR".to_string());
+        for day in [1, 20] {
+            store.reference.fx_rates.push(FxRate {
+                base_currency: "EUR".to_string(),
+                quote_currency: "USD".to_string(),
+                rate_date: NaiveDate::from_ymd_opt(2019, 11, day).unwrap(),
+                rate: 1.25,
+                source: "wm".to_string()
+            });
+        }
+        for t in [trade("BUY", 1, 100.), trade("SELL", 2, -100.), trade("BUY", 20, 50.)] {
+            store.insert_trade(&t).await.unwrap();
+        }
+
+        trades::summarize(&store, &store, "rivernorth").await.unwrap();
+        let files = store.file_summaries.lock().unwrap().clone();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].calc, 3.);
+        assert_eq!(store.fx_audit.lock().unwrap().len(), 3);
+        let commissions = store.commission_summaries.lock().unwrap().clone();
+        assert_eq!(commissions.len(), 6);
+        assert!(commissions.iter().all(|s| s.currency == "EUR" && s.trades == 3));
+
+        trades::chain(&store, &store, &store, "rivernorth").await.unwrap();
+        let first = store.get_chains("rivernorth").await.unwrap();
+        let run = trades::chain(&store, &store, &store, "rivernorth").await.unwrap();
+        assert_eq!((run.run_id, run.trades, run.chains, run.members), (Some(2), 3, 1, 2));
+        let chains = store.chains.lock().unwrap().clone();
+        assert_eq!(chains.len(), 1);
+        assert_eq!(chains[0].chain_id, first[0].chain_id);
+        assert_eq!(chains[0].chain_id, trades::chain_id(&trade("BUY", 1, 100.)));
+        assert_eq!(chains[0].chain.iter().map(|t| t.id.unwrap()).collect::<Vec<_>>(), vec![1, 2]);
+        assert_eq!(chains[0].metrics, trades::ChainMetrics {
+            holding_secs: 86400, net_quantity: 0., gross_notional: 2000., realized_pnl: 0., costs: 0., flat: true
+        });
+    }
+
+    #[tokio::test]
+    async fn incremental_chains_leave_the_rest_alone() {
+        let store = MemoryStore::default();
+        let other = |tx_type: &str, day: u32, quantity: f64| Trade { security_ticker: "XYZ".to_string(), row: 10 + day as i32, ..trade(tx_type, day, quantity) };
+        for t in [trade("BUY", 1, 100.), trade("SELL", 2, -100.), other("BUY", 1, 10.), other("SELL", 2, -10.)] {
+            store.insert_trade(&t).await.unwrap();
+        }
+        trades::chain(&store, &store, &store, "rivernorth").await.unwrap();
+        let before = store.get_chains("rivernorth").await.unwrap();
+
+        store.insert_trade(&trade("BUY", 3, 50.)).await.unwrap();
+        let run = trades::chain_incremental(&store, &store, &store, "rivernorth").await.unwrap();
+        assert_eq!((run.mode.as_str(), run.trades, run.chains, run.removed), ("incremental", 3, 1, 0));
+
+        let after = store.get_chains("rivernorth").await.unwrap();
+        let ids = |v: &[TradeChain]| v.iter().map(|c| c.chain_id.clone()).sorted().collect::<Vec<_>>();
+        assert_eq!(ids(&before), ids(&after));
+        assert_eq!(ids(&store.published_chains.lock().unwrap()), ids(&after));
+        assert_eq!(after.len(), trades::detect_chains(&store.get_all_trades("rivernorth").await.unwrap()).len());
+    }
+
+    #[tokio::test]
+    async fn incremental_chains_see_a_trade_changed_in_place() {
+        let store = MemoryStore::default();
+        for t in [trade("BUY", 1, 100.), trade("SELL", 2, -100.), trade("SELL", 10, -20.)] {
+            store.insert_trade(&t).await.unwrap();
+        }
+        trades::chain(&store, &store, &store, "rivernorth").await.unwrap();
+        assert_eq!(store.get_chains("rivernorth").await.unwrap().len(), 1);
+
+        store.trades.lock().unwrap()[1].security_ticker = "XYZ".to_string();
+        let run = trades::chain_incremental(&store, &store, &store, "rivernorth").await.unwrap();
+        assert_eq!((run.mode.as_str(), run.chains, run.removed), ("incremental", 0, 1));
+        assert!(store.get_chains("rivernorth").await.unwrap().is_empty());
+
+        // and back again, the trade on the 10th was never looked at
+        store.trades.lock().unwrap()[1].security_ticker = "OPP".to_string();
+        let run = trades::chain_incremental(&store, &store, &store, "rivernorth").await.unwrap();
+        assert_eq!((run.trades, run.chains, run.removed), (2, 1, 0));
+        assert_eq!(store.get_chains("rivernorth").await.unwrap().len(), trades::detect_chains(&store.get_all_trades("rivernorth").await.unwrap()).len());
+    }
+}
@@ -0,0 +1,545 @@
+use crate::cash::{self, CashEntry};
+use crate::corporate_actions;
+use crate::error::{self, Error};
+use crate::good_faith::{self, ViolationType};
+use crate::hierarchy::{self, Hierarchy};
+use crate::positions;
+use crate::recon::QUANTITY_TOLERANCE;
+use crate::trades::{self, Trade};
+use crate::tx_types::Direction;
+use crate::utils;
+use chrono::{NaiveDate, NaiveDateTime, NaiveTime};
+use serde::{Serialize,Deserialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::str::FromStr;
+use std::time::SystemTime;
+use tokio_postgres::{Row, Transaction};
+use tracing::{info, warn};
+
+/// regular session, trade times are whatever the file had so the administrator's clock
+pub fn market_open() -> NaiveTime {
+    NaiveTime::from_hms_opt(9, 30, 0).unwrap()
+}
+
+pub fn market_close() -> NaiveTime {
+    NaiveTime::from_hms_opt(16, 0, 0).unwrap()
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    Low,
+    Medium,
+    High
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Severity::Low => write!(f, "LOW"),
+            Severity::Medium => write!(f, "MEDIUM"),
+            Severity::High => write!(f, "HIGH"),
+        }
+    }
+}
+
+impl FromStr for Severity {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_uppercase().as_ref() {
+            "LOW" => Ok(Severity::Low),
+            "MEDIUM" => Ok(Severity::Medium),
+            "HIGH" => Ok(Severity::High),
+            other => Err(format!("{} is not a severity", other))
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Rule {
+    IntradayRoundTrip,
+    FreeRiding,
+    CrossTrade,
+    RestrictedSecurity,
+    Concentration,
+    OutsideMarketHours
+}
+
+impl Rule {
+    pub const ALL: [Rule; 6] = [Rule::IntradayRoundTrip, Rule::FreeRiding, Rule::CrossTrade, Rule::RestrictedSecurity, Rule::Concentration, Rule::OutsideMarketHours];
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Rule::IntradayRoundTrip => write!(f, "INTRADAY_ROUND_TRIP"),
+            Rule::FreeRiding => write!(f, "FREE_RIDING"),
+            Rule::CrossTrade => write!(f, "CROSS_TRADE"),
+            Rule::RestrictedSecurity => write!(f, "RESTRICTED_SECURITY"),
+            Rule::Concentration => write!(f, "CONCENTRATION"),
+            Rule::OutsideMarketHours => write!(f, "OUTSIDE_MARKET_HOURS"),
+        }
+    }
+}
+
+impl FromStr for Rule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_uppercase().replace([' ', '-'], "_").as_ref() {
+            "INTRADAY_ROUND_TRIP" => Ok(Rule::IntradayRoundTrip),
+            "FREE_RIDING" => Ok(Rule::FreeRiding),
+            "CROSS_TRADE" => Ok(Rule::CrossTrade),
+            "RESTRICTED_SECURITY" | "RESTRICTED" => Ok(Rule::RestrictedSecurity),
+            "CONCENTRATION" => Ok(Rule::Concentration),
+            "OUTSIDE_MARKET_HOURS" => Ok(Rule::OutsideMarketHours),
+            other => Err(format!("{} is not a surveillance rule", other))
+        }
+    }
+}
+
+/// How one rule runs. threshold means something different to each:
+/// the smallest gross notional worth flagging for a round trip, how far apart two prices can be and still be
+/// a cross, and the largest share of an account one name can be. The rest don't use it.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RuleConfig {
+    pub rule: Rule,
+    pub enabled: bool,
+    pub severity: Severity,
+    pub threshold: f64
+}
+
+/// A rule or severity nobody knows is a surveillance_rules row edited by hand, and running some other rule in its place is worse than not running.
+impl TryFrom<Row> for RuleConfig {
+    type Error = Error;
+
+    fn try_from(row: tokio_postgres::Row) -> Result<Self, Self::Error> {
+        let rule: String = row.get("rule");
+        let severity: String = row.get("severity");
+        Ok(Self {
+            rule: rule.parse().map_err(|e| Error::Validation(format!("in surveillance_rules: {}", e)))?,
+            enabled: row.get("enabled"),
+            severity: severity.parse().map_err(|e| Error::Validation(format!("in surveillance_rules for {}: {}", rule, e)))?,
+            threshold: row.get("threshold"),
+        })
+    }
+}
+
+impl RuleConfig {
+    pub fn default_for(rule: Rule) -> Self {
+        let (severity, threshold) = match rule {
+            Rule::IntradayRoundTrip => (Severity::Low, 0.),
+            Rule::FreeRiding => (Severity::High, 0.),
+            Rule::CrossTrade => (Severity::Medium, 0.005),
+            Rule::RestrictedSecurity => (Severity::High, 0.),
+            Rule::Concentration => (Severity::Medium, 0.25),
+            Rule::OutsideMarketHours => (Severity::Low, 0.),
+        };
+        RuleConfig { rule, enabled: true, severity, threshold }
+    }
+
+    /// Every rule, the surveillance_rules row where there is one and the default where there isn't.
+    pub fn resolve(configured: &[RuleConfig]) -> Vec<RuleConfig> {
+        Rule::ALL.iter()
+            .map(|r| configured.iter().find(|c| c.rule == *r).cloned().unwrap_or_else(|| RuleConfig::default_for(*r)))
+            .collect()
+    }
+}
+
+/// A name nobody's to trade from from_date through to_date, open ended when there's no to_date.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RestrictedSecurity {
+    pub security_ticker: String,
+    pub from_date: NaiveDate,
+    pub to_date: Option<NaiveDate>,
+    pub reason: String
+}
+
+impl From<Row> for RestrictedSecurity {
+    fn from(row: tokio_postgres::Row) -> Self {
+        Self {
+            security_ticker: row.get("security_ticker"),
+            from_date: row.get("from_date"),
+            to_date: row.get("to_date"),
+            reason: row.get("reason"),
+        }
+    }
+}
+
+impl RestrictedSecurity {
+    pub fn covers(&self, trade: &Trade) -> bool {
+        let d = utils::to_date(trade.trade_date);
+        self.security_ticker.eq_ignore_ascii_case(&trade.security_ticker) && self.from_date <= d && self.to_date.map(|t| d <= t).unwrap_or(true)
+    }
+}
+
+/// evidence is the ids of the trades that tripped the rule.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Alert {
+    pub handle: String,
+    pub rule: Rule,
+    pub severity: Severity,
+    pub account_name: String,
+    pub security_ticker: String,
+    pub alert_date: NaiveDate,
+    pub description: String,
+    pub evidence: Vec<i32>
+}
+
+impl Alert {
+    fn new(config: &RuleConfig, t: &Trade, description: String, evidence: &[&Trade]) -> Self {
+        Self {
+            handle: t.handle.clone(),
+            rule: config.rule,
+            severity: config.severity,
+            account_name: t.account_name.to_uppercase(),
+            security_ticker: t.security_ticker.to_uppercase(),
+            alert_date: utils::to_date(t.trade_date),
+            description,
+            evidence: evidence.iter().filter_map(|t| t.id).collect()
+        }
+    }
+}
+
+pub async fn build_surveillance_tables(client: &tokio_postgres::Client) -> Result<(), Error> {
+
+    client.query("CREATE TABLE surveillance_rules (id SERIAL PRIMARY KEY,
+        rule VARCHAR NOT NULL UNIQUE,
+        enabled BOOLEAN NOT NULL,
+        severity VARCHAR NOT NULL,
+        threshold FLOAT8 NOT NULL
+        )", &[]).await?;
+
+    client.query("CREATE TABLE restricted_securities (id SERIAL PRIMARY KEY,
+        security_ticker VARCHAR NOT NULL,
+        from_date DATE NOT NULL,
+        to_date DATE,
+        reason VARCHAR NOT NULL
+        )", &[]).await?;
+
+    client.query("CREATE TABLE alerts (id SERIAL PRIMARY KEY,
+        handle VARCHAR NOT NULL,
+        rule VARCHAR NOT NULL,
+        severity VARCHAR NOT NULL,
+        account_name VARCHAR NOT NULL,
+        security_ticker VARCHAR NOT NULL,
+        alert_date DATE NOT NULL,
+        description VARCHAR NOT NULL,
+        evidence INT[] NOT NULL,
+        inserted_at TIMESTAMP NOT NULL
+        )", &[]).await?;
+
+    Ok(())
+}
+
+pub async fn drop_surveillance_tables(client: &tokio_postgres::Client) -> Result<(), Error> {
+
+    client.query("drop TABLE surveillance_rules", &[]).await?;
+    client.query("drop TABLE restricted_securities", &[]).await?;
+    client.query("drop TABLE alerts", &[]).await?;
+
+    Ok(())
+}
+
+pub async fn get_rule_configs(client: &tokio_postgres::Client) -> Result<Vec<RuleConfig>, Error> {
+
+    let rows = client.query("SELECT * FROM surveillance_rules", &[]).await?;
+    rows.into_iter().map(RuleConfig::try_from).collect()
+}
+
+pub async fn get_restricted(client: &tokio_postgres::Client) -> Result<Vec<RestrictedSecurity>, Error> {
+
+    let rows = client.query("SELECT * FROM restricted_securities ORDER BY security_ticker, from_date", &[]).await?;
+    Ok(rows.into_iter().map(RestrictedSecurity::from).collect())
+}
+
+async fn insert_alert(client: &Transaction<'_>, a: &Alert) -> Result<(), Error> {
+
+    let statement = client.prepare("INSERT INTO alerts (
+        handle,
+        rule,
+        severity,
+        account_name,
+        security_ticker,
+        alert_date,
+        description,
+        evidence,
+        inserted_at
+        ) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)").await?;
+
+    client.execute(&statement,&[
+        &a.handle,
+        &a.rule.to_string(),
+        &a.severity.to_string(),
+        &a.account_name,
+        &a.security_ticker,
+        &a.alert_date,
+        &a.description,
+        &a.evidence,
+        &SystemTime::now()
+        ]).await?;
+    Ok(())
+}
+
+async fn clean_alerts(client: &Transaction<'_>, handle: &str) -> Result<(), Error> {
+
+    let statement = client.prepare("delete from alerts where handle = $1").await?;
+    client.execute(&statement,&[&handle]).await?;
+
+    Ok(())
+}
+
+/// Expects rule, enabled (true/false), severity and threshold columns, a rule can only be in there once.
+pub async fn load_rules(client: &tokio_postgres::Client) -> Result<(), Error> {
+    let ifile = "/tmp/surveillance_rules.csv";
+
+    for (row, r) in utils::read_csv_records(ifile, 4)? {
+        let config = RuleConfig {
+            rule: error::parse_field(ifile, row, "rule", &r[0])?,
+            enabled: error::parse_field(ifile, row, "enabled", &r[1])?,
+            severity: error::parse_field(ifile, row, "severity", &r[2])?,
+            threshold: error::parse_field(ifile, row, "threshold", &r[3])?
+        };
+        info!("{:?}", config);
+        client.execute("INSERT INTO surveillance_rules (rule, enabled, severity, threshold) VALUES ($1, $2, $3, $4)
+            ON CONFLICT (rule) DO UPDATE SET enabled = EXCLUDED.enabled, severity = EXCLUDED.severity, threshold = EXCLUDED.threshold",
+            &[&config.rule.to_string(), &config.enabled, &config.severity.to_string(), &config.threshold]).await?;
+    }
+
+    Ok(())
+}
+
+/// Expects security_ticker, from_date, to_date (yyyy-mm-dd, blank when it's still restricted) and reason columns.
+pub async fn load_restricted(client: &tokio_postgres::Client) -> Result<(), Error> {
+    let ifile = "/tmp/restricted.csv";
+
+    for (row, r) in utils::read_csv_records(ifile, 4)? {
+        let restricted = RestrictedSecurity {
+            security_ticker: r[0].trim().to_uppercase(),
+            from_date: error::parse_field(ifile, row, "from_date", &r[1])?,
+            to_date: if r[2].trim().is_empty() { None } else { Some(error::parse_field(ifile, row, "to_date", &r[2])?) },
+            reason: r[3].trim().to_string()
+        };
+        info!("{:?}", restricted);
+        client.execute("INSERT INTO restricted_securities (security_ticker, from_date, to_date, reason) VALUES ($1, $2, $3, $4)",
+            &[&restricted.security_ticker, &restricted.from_date, &restricted.to_date, &restricted.reason]).await?;
+    }
+
+    Ok(())
+}
+
+/// Buys and sells, what every rule but market hours looks at.
+fn executions(trades: &[Trade]) -> impl Iterator<Item = &Trade> {
+    trades.iter().filter(|t| t.tx().is_trade())
+}
+
+/// The same account buying and selling the same name on the same day.
+pub fn intraday_round_trips(trades: &[Trade], config: &RuleConfig) -> Vec<Alert> {
+
+    let mut g: BTreeMap<(String, String, NaiveDate), Vec<&Trade>> = BTreeMap::new();
+    for t in executions(trades) {
+        g.entry((t.account_name.to_uppercase(), t.security_ticker.to_uppercase(), utils::to_date(t.trade_date))).or_default().push(t);
+    }
+
+    g.into_values()
+        .filter(|v| v.iter().any(|t| t.direction() == Direction::Buy) && v.iter().any(|t| t.direction() == Direction::Sell))
+        .filter(|v| v.iter().map(|t| (t.quantity * t.price).abs()).sum::<f64>() >= config.threshold)
+        .map(|v| Alert::new(config, v[0], format!("{} bought and sold {} on the same day", v[0].account_name, v[0].security_ticker), &v))
+        .collect()
+}
+
+/// The free riding good_faith finds in cash accounts off the cash ledger, the buy and the sale that paid for it.
+pub fn free_riding(trades: &[Trade], ledger: &[CashEntry], hierarchy: &Hierarchy, config: &RuleConfig) -> Vec<Alert> {
+
+    let by_id = |id: Option<i32>| trades.iter().find(|t| id.is_some() && t.id == id);
+    good_faith::detect(trades, ledger, hierarchy).iter()
+        .filter(|v| v.violation_type == ViolationType::FreeRiding)
+        .filter_map(|v| Some((v, by_id(v.buy_trade_id)?, by_id(v.sell_trade_id)?)))
+        .map(|(v, b, s)| Alert::new(config, b, format!("{} sold {} before paying for it, {:.2} short", b.account_name, b.security_ticker, v.shortfall), &[b, s]))
+        .collect()
+}
+
+/// Which fund an account belongs to as the hierarchy has it, the trade's handle when it isn't in there.
+fn fund_of(hierarchy: &Hierarchy, t: &Trade) -> String {
+    hierarchy.resolve(&t.handle, &t.account_name)
+        .and_then(|a| hierarchy.fund_of(a))
+        .map(|f| f.handle.clone())
+        .unwrap_or_else(|| t.handle.clone())
+}
+
+/// One account of a fund buying what another of the same fund sold that day, the same quantity at near enough the same price.
+pub fn cross_trades(trades: &[Trade], hierarchy: &Hierarchy, config: &RuleConfig) -> Vec<Alert> {
+
+    let mut g: BTreeMap<(String, String, NaiveDate), Vec<&Trade>> = BTreeMap::new();
+    for t in executions(trades) {
+        g.entry((fund_of(hierarchy, t), t.security_ticker.to_uppercase(), utils::to_date(t.trade_date))).or_default().push(t);
+    }
+
+    let mut alerts: Vec<Alert> = Vec::new();
+    for v in g.values() {
+        let mut matched: BTreeSet<usize> = BTreeSet::new();
+        for b in v.iter().filter(|t| t.direction() == Direction::Buy) {
+            let found = v.iter().enumerate().find(|(i, s)| {
+                !matched.contains(i) &&
+                s.direction() == Direction::Sell &&
+                !s.account_name.eq_ignore_ascii_case(&b.account_name) &&
+                (s.quantity.abs() - b.quantity.abs()).abs() < QUANTITY_TOLERANCE &&
+                b.price != 0. && ((s.price - b.price) / b.price).abs() <= config.threshold
+            });
+            if let Some((i, s)) = found {
+                matched.insert(i);
+                alerts.push(Alert::new(config, b, format!("{} bought {} {} that {} sold", b.account_name, b.quantity.abs(), b.security_ticker, s.account_name), &[b, s]));
+            }
+        }
+    }
+
+    alerts
+}
+
+pub fn restricted_trades(trades: &[Trade], restricted: &[RestrictedSecurity], config: &RuleConfig) -> Vec<Alert> {
+
+    executions(trades)
+        .filter_map(|t| restricted.iter().find(|r| r.covers(t)).map(|r| (t, r)))
+        .map(|(t, r)| Alert::new(config, t, format!("{} traded restricted {}: {}", t.account_name, t.security_ticker, r.reason), &[t]))
+        .collect()
+}
+
+/// Checked at the end of every day an account traded, flagged the day a name goes over threshold of the
+/// account's long market value at last execution prices, not again until it's come back under.
+pub fn concentration(trades: &[Trade], config: &RuleConfig) -> Vec<Alert> {
+
+    let mut by_account: BTreeMap<String, Vec<Trade>> = BTreeMap::new();
+    for t in trades {
+        by_account.entry(t.account_name.to_uppercase()).or_default().push(t.clone());
+    }
+
+    let mut alerts: Vec<Alert> = Vec::new();
+    for account_trades in by_account.values() {
+        let days: BTreeSet<NaiveDate> = executions(account_trades).map(|t| utils::to_date(t.trade_date)).collect();
+        let mut over: BTreeSet<String> = BTreeSet::new();
+        for d in days {
+            let end_of_day = d.and_hms_opt(23, 59, 59).unwrap().timestamp();
+            let long: Vec<(String, f64)> = positions::derive_positions(account_trades, end_of_day).into_iter()
+                .filter(|p| p.quantity > QUANTITY_TOLERANCE)
+                .map(|p| (p.security_ticker, p.quantity * p.last_price))
+                .collect();
+            let total: f64 = long.iter().map(|x| x.1).sum();
+            let now: BTreeSet<String> = long.iter().filter(|x| total > 0. && x.1 / total > config.threshold).map(|x| x.0.clone()).collect();
+            for ticker in now.difference(&over) {
+                let evidence: Vec<&Trade> = executions(account_trades)
+                    .filter(|t| utils::to_date(t.trade_date) == d && t.security_ticker.eq_ignore_ascii_case(ticker))
+                    .collect();
+                let share = long.iter().find(|x| &x.0 == ticker).map(|x| x.1 / total).unwrap_or(0.);
+                if let Some(t) = evidence.first() {
+                    alerts.push(Alert::new(config, t, format!("{} is {:.0}% of {}", ticker, share * 100., t.account_name), &evidence));
+                }
+            }
+            over = now;
+        }
+    }
+
+    alerts
+}
+
+/// Only trades that carry a time, a file with dates alone puts everything at midnight and that's left be.
+pub fn outside_market_hours(trades: &[Trade], config: &RuleConfig) -> Vec<Alert> {
+
+    trades.iter()
+        .filter(|t| t.tx().is_trade())
+        .filter(|t| {
+            let time = NaiveDateTime::from_timestamp_opt(t.trade_date, 0).unwrap_or_default().time();
+            time != NaiveTime::from_hms_opt(0, 0, 0).unwrap() && (time < market_open() || time > market_close())
+        })
+        .map(|t| Alert::new(config, t, format!("{} traded {} at {}", t.account_name, t.security_ticker,
+            NaiveDateTime::from_timestamp_opt(t.trade_date, 0).unwrap_or_default().time()), &[t]))
+        .collect()
+}
+
+/// Every enabled rule over a handle's trades, free riding checked against its cash ledger.
+pub fn run_rules(trades: &[Trade], ledger: &[CashEntry], hierarchy: &Hierarchy, restricted: &[RestrictedSecurity], configs: &[RuleConfig]) -> Vec<Alert> {
+
+    RuleConfig::resolve(configs).iter().filter(|c| c.enabled).flat_map(|c| match c.rule {
+        Rule::IntradayRoundTrip => intraday_round_trips(trades, c),
+        Rule::FreeRiding => free_riding(trades, ledger, hierarchy, c),
+        Rule::CrossTrade => cross_trades(trades, hierarchy, c),
+        Rule::RestrictedSecurity => restricted_trades(trades, restricted, c),
+        Rule::Concentration => concentration(trades, c),
+        Rule::OutsideMarketHours => outside_market_hours(trades, c),
+    }).collect()
+}
+
+/// Reruns every rule over the handle's trades and replaces its alerts in one transaction.
+pub async fn surveil(client: &mut tokio_postgres::Client, handle: &str) -> Result<(), Error> {
+
+    let all_trades = trades::get_all_trades(client, handle).await?;
+    let hierarchy = hierarchy::get_hierarchy(client).await?;
+    let restricted = get_restricted(client).await?;
+    let configs = get_rule_configs(client).await?;
+    let mut ledger = cash::get_ledger(client, handle).await?;
+    if ledger.is_empty() {
+        warn!("no cash ledger posted for {:?}, working off the trades", handle);
+        ledger = cash::ledger(&all_trades, &corporate_actions::get_corporate_actions(client).await?);
+    }
+
+    // the old alerts only go once the new ones are in, a run that fails part way leaves the last run's
+    let tx = client.transaction().await?;
+    info!("first I'll clean up alerts for {:?}", handle);
+    clean_alerts(&tx, handle).await?;
+
+    for a in run_rules(&all_trades, &ledger, &hierarchy, &restricted, &configs) {
+        info!("{:?}", a);
+        insert_alert(&tx, &a).await?;
+    }
+    tx.commit().await?;
+
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn trade(id: i32, account: &str, tx_type: &str, day: u32, hour: u32, quantity: f64, price: f64) -> Trade {
+        Trade { account_name: account.to_string(), account_number: account.to_string(), account_type: "CASH".to_string(), ..Trade::test(id, tx_type, day, quantity, price).at(11, day, hour) }
+    }
+
+    #[test]
+    fn rules_flag_what_they_should() {
+        let trades = vec![
+            trade(1, "RN1", "BUY", 4, 10, 100., 10.),
+            trade(2, "RN1", "SELL", 4, 15, -100., 10.5),
+            trade(3, "RN2", "BUY", 5, 11, 50., 20.),
+            trade(4, "RN1", "SELL", 5, 11, -50., 20.01),
+            trade(5, "RN2", "BUY", 6, 18, 10., 20.),
+        ];
+        let restricted = vec![RestrictedSecurity {
+            security_ticker: "OPP".to_string(),
+            from_date: NaiveDate::from_ymd_opt(2019, 11, 6).unwrap(),
+            to_date: None,
+            reason: "mnpi".to_string()
+        }];
+
+        let ledger = cash::ledger(&trades, &[]);
+        let alerts = run_rules(&trades, &ledger, &Hierarchy::default(), &restricted, &[]);
+        let found: BTreeSet<(Rule, Vec<i32>)> = alerts.iter().map(|a| (a.rule, a.evidence.clone())).collect();
+
+        assert!(found.contains(&(Rule::IntradayRoundTrip, vec![1, 2])));
+        assert!(found.contains(&(Rule::FreeRiding, vec![1, 2])));
+        assert!(found.contains(&(Rule::CrossTrade, vec![3, 4])));
+        assert!(found.contains(&(Rule::RestrictedSecurity, vec![5])));
+        assert!(found.contains(&(Rule::OutsideMarketHours, vec![5])));
+        assert!(found.contains(&(Rule::Concentration, vec![3])));
+
+        // money in the account before the buy and there's nothing to free ride on
+        let mut funded = trades.clone();
+        funded.push(Trade { net_amount: 5000., principal: 5000., ..trade(6, "RN1", "SUBSCRIPTION", 1, 10, 0., 0.) });
+        let alerts = run_rules(&funded, &cash::ledger(&funded, &[]), &Hierarchy::default(), &[], &[]);
+        assert!(alerts.iter().all(|a| a.rule != Rule::FreeRiding));
+
+        let quiet = run_rules(&trades, &ledger, &Hierarchy::default(), &[], &[RuleConfig { enabled: false, ..RuleConfig::default_for(Rule::Concentration) }]);
+        assert!(quiet.iter().all(|a| a.rule != Rule::Concentration && a.rule != Rule::RestrictedSecurity));
+    }
+}
@@ -1,41 +1,197 @@
-use bson::oid::ObjectId;
+use crate::commissions;
+use crate::corporate_actions;
+use crate::error::Error;
+use crate::fx;
+use crate::hierarchy::Level;
+use crate::positions;
+use crate::recon;
+use crate::store::{SummaryStore, TradeStore};
+use crate::tx_types::{Direction, TxType, TxTypeMap};
+use crate::utils;
 use chrono::NaiveDateTime;
-use tokio_postgres::Row;
-use std::collections::{HashSet, HashMap};
+use futures::future::try_join_all;
+use tokio_postgres::{Row, Transaction};
+use std::collections::{BTreeMap, HashSet, HashMap};
 use itertools::Itertools;
 use serde::{Serialize,Deserialize};
-use tokio_postgres::{Error};
-use tracing::{info, debug};
-use std::time::{SystemTime};
+use tracing::{info, debug, warn};
+use std::time::{Instant, SystemTime};
+
+/// same ticker, traded before the other trade and still unsettled when it happened
+pub const CHAIN_RULE: &str = "ticker within settlement window";
+/// a chain has to mix at least this many tx types, a run of buys on their own isn't one
+pub const MIN_CHAIN_TX_TYPES: usize = 2;
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct FileSummary {
-    handle: String,
-    filename: String,
-    filehash: String,
-    calc: f64
+    pub handle: String,
+    pub filename: String,
+    pub filehash: String,
+    pub calc: f64
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct AccountSummary {
-    handle: String,
-    tx_type: String,
-    account_name: String,
-    calc: f64
+    pub handle: String,
+    pub tx_type: String,
+    pub direction: String,
+    pub account_name: String,
+    pub currency: String,
+    pub calc: f64
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct SecuritySummary {
-    handle: String,
-    tx_type: String,
-    security_ticker: String,
-    calc: f64
+    pub handle: String,
+    pub tx_type: String,
+    pub direction: String,
+    pub security_ticker: String,
+    pub currency: String,
+    pub calc: f64
 }
 
+/// The same sums as the account summaries, rolled up to a node at any level of the hierarchy.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct HierarchySummary {
+    pub handle: String,
+    pub level: String,
+    pub node: String,
+    pub tx_type: String,
+    pub direction: String,
+    pub currency: String,
+    pub calc: f64
+}
+
+/// chain_id comes from the head trade (see `chain_id`) so the same chain gets the same id on every rebuild.
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct TradeChain {
+    pub chain_id: String,
     pub head: Trade,
-    pub chain: Vec<Trade>
+    pub chain: Vec<Trade>,
+    pub metrics: ChainMetrics
+}
+
+/// What a chain came to, worked out off its members when it's detected and kept on its chains row.
+/// realized_pnl is on the quantity that was both bought and sold, at the average price each way, less costs.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct ChainMetrics {
+    /// seconds from the head's trade date to the last member's
+    pub holding_secs: i64,
+    pub net_quantity: f64,
+    pub gross_notional: f64,
+    pub realized_pnl: f64,
+    /// commissions and fees
+    pub costs: f64,
+    /// net quantity back to nothing, so the round trip's closed
+    pub flat: bool
+}
+
+impl From<&Row> for ChainMetrics {
+    fn from(row: &tokio_postgres::Row) -> Self {
+        Self {
+            holding_secs: row.get("holding_secs"),
+            net_quantity: row.get("net_quantity"),
+            gross_notional: row.get("gross_notional"),
+            realized_pnl: row.get("realized_pnl"),
+            costs: row.get("costs"),
+            flat: row.get("flat"),
+        }
+    }
+}
+
+impl ChainMetrics {
+    pub fn of(head: &Trade, members: &[Trade]) -> Self {
+        let last = members.iter().map(|t| t.trade_date).max().unwrap_or(head.trade_date);
+        let (mut bought, mut bought_notional, mut sold, mut sold_notional) = (0., 0., 0., 0.);
+        for t in members.iter().filter(|t| t.tx().moves_position()) {
+            let q = positions::signed_quantity(t);
+            if q > 0. {
+                bought += q;
+                bought_notional += q * t.price;
+            } else {
+                sold -= q;
+                sold_notional -= q * t.price;
+            }
+        }
+        let costs = members.iter().map(|t| t.commission + t.fee).sum::<f64>();
+        let matched = f64::min(bought, sold);
+        let realized = if matched > 0. { matched * (sold_notional / sold - bought_notional / bought) } else { 0. };
+        let net_quantity = bought - sold;
+        ChainMetrics {
+            holding_secs: last - head.trade_date,
+            net_quantity,
+            gross_notional: bought_notional + sold_notional,
+            realized_pnl: realized - costs,
+            costs,
+            flat: net_quantity.abs() < recon::QUANTITY_TOLERANCE
+        }
+    }
+}
+
+/// the role the head's row has in chain_members, every other row is a MEMBER_ROLE
+pub const HEAD_ROLE: &str = "head";
+pub const MEMBER_ROLE: &str = "member";
+
+impl TradeChain {
+    /// The head then every member in order, which is how they go into chain_members with their position.
+    pub fn rows(&self) -> impl Iterator<Item = (&'static str, &Trade)> {
+        std::iter::once((HEAD_ROLE, &self.head)).chain(self.chain.iter().map(|t| (MEMBER_ROLE, t)))
+    }
+}
+
+/// chain_members points at trades by id, so a trade that never came out of the trades table can't go in a chain.
+pub(crate) fn member_id(t: &Trade) -> Result<i32, Error> {
+    t.id.ok_or_else(|| Error::Validation(format!("{} row {} has no trade id to chain on", t.filename, t.row)))
+}
+
+/// What chain detection runs with, kept on every run so a change in the rules shows up against the counts.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ChainParameters {
+    pub rule: String,
+    pub min_tx_types: usize,
+    pub corporate_actions: usize
+}
+
+/// One rebuild of a handle's chains, recorded in chain_runs in the same transaction as the chains themselves.
+/// trades is how many were evaluated, chains and members what got written and removed the chain ids that went.
+/// last_trade_id is the newest trade the run saw, the next incremental run treats anything past it as new.
+/// trade_hashes is every trade the run saw by id, as trade_hash had it, so the next one can tell a trade changed in place.
+/// They're kept in chain_trades, one row a trade overwritten by each run, so only the latest run's come back.
+/// Duration covers loading and detection, the write happens after it's taken.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ChainRun {
+    pub run_id: Option<i32>,
+    pub handle: String,
+    pub mode: String,
+    pub parameters: ChainParameters,
+    pub trades: i32,
+    pub chains: i32,
+    pub members: i32,
+    pub removed: i32,
+    pub last_trade_id: i32,
+    pub trade_hashes: BTreeMap<i32, String>,
+    pub started_at: NaiveDateTime,
+    pub duration_ms: i64
+}
+
+impl From<Row> for ChainRun {
+    fn from(row: tokio_postgres::Row) -> Self {
+        let parameters: String = row.get("parameters");
+        Self {
+            run_id: Some(row.get("run_id")),
+            handle: row.get("handle"),
+            mode: row.get("mode"),
+            parameters: serde_json::from_str(&parameters).unwrap_or(ChainParameters { rule: parameters, min_tx_types: 0, corporate_actions: 0 }),
+            trades: row.get("trades"),
+            chains: row.get("chains"),
+            members: row.get("members"),
+            removed: row.get("removed"),
+            last_trade_id: row.get("last_trade_id"),
+            trade_hashes: BTreeMap::new(),
+            started_at: row.get("started_at"),
+            duration_ms: row.get("duration_ms"),
+        }
+    }
 }
 
 
@@ -48,11 +204,14 @@ pub struct Trade {
     pub row: i32,
     pub account_name: String,
     pub account_number: String,
+    pub account_type: String,
+    pub account_id: Option<i32>,
     pub security_description: String,
     pub security_ticker: String,
     pub asset_class: String,
     pub security_type: String,
     pub tx_type: String,
+    pub source_tx_type: String,
     pub cusip: String,
     pub price: f64,
     pub quantity: f64,
@@ -60,6 +219,7 @@ pub struct Trade {
     pub fee: f64,
     pub principal: f64,
     pub net_amount: f64,
+    pub currency: String,
     pub trade_date: i64,
     pub settlement_date: i64,
     pub broker: String,     
@@ -76,11 +236,14 @@ impl From<Row> for Trade {
             row: row.get("row"),
             account_name: row.get("account_name"),
             account_number: row.get("account_number"),
+            account_type: row.get("account_type"),
+            account_id: row.get("account_id"),
             security_description: row.get("security_description"),
             security_ticker: row.get("security_ticker"),
             asset_class: row.get("asset_class"),
             security_type: row.get("security_type"),
             tx_type: row.get("tx_type"),
+            source_tx_type: row.get("source_tx_type"),
             cusip: row.get("cusip"),
             price: row.get("price"),
             quantity: row.get("quantity"),
@@ -88,6 +251,7 @@ impl From<Row> for Trade {
             fee: row.get("fee"),
             principal: row.get("principal"),
             net_amount: row.get("net_amount"),
+            currency: row.get("currency"),
             trade_date: row.get("trade_date"),
             settlement_date: row.get("settlement_date"),
             broker: row.get("broker"),
@@ -97,17 +261,82 @@ impl From<Row> for Trade {
 }
 
 impl Trade {
+    /// tx_type is canonical for anything ingested through a TxTypeMap, older rows get mapped on the fly.
+    pub fn tx(&self) -> TxType {
+        self.tx_type.parse()
+            .or_else(|_| TxTypeMap::defaults(&self.handle).map(&self.tx_type).ok_or(()))
+            .unwrap_or(TxType::Other)
+    }
+
+    pub fn direction(&self) -> Direction {
+        Direction::of(self.tx(), self.quantity, self.net_amount)
+    }
+
+    /// Puts quantity and cash on the convention in tx_types whatever signs the file used.
+    pub fn normalize_signs(&mut self) {
+        let tx = self.tx();
+        if let Some(sign) = tx.quantity_sign() {
+            self.quantity = sign * self.quantity.abs();
+        }
+        if let Some(sign) = tx.cash_sign() {
+            self.principal = sign * self.principal.abs();
+            self.net_amount = sign * self.net_amount.abs();
+        }
+        self.price = self.price.abs();
+        self.commission = self.commission.abs();
+        self.fee = self.fee.abs();
+    }
+
     pub fn is_chained(&self, other_trade: &Trade) -> bool {
-        if self.security_ticker == other_trade.security_ticker &&
+        self.security_ticker == other_trade.security_ticker &&
         self.trade_date <= other_trade.trade_date &&
-        self.settlement_date > other_trade.trade_date  {
-            true        
-        } else {
-            false
-        }
+        self.settlement_date > other_trade.trade_date
+    }
+}
+
+/// The one trade the tests build on: rivernorth, RN1, OPP, 16:00 on a day in November 2019 settling two days later,
+/// cash on the sign convention. Anything else goes on with struct update syntax.
+#[cfg(test)]
+impl Trade {
+    pub(crate) fn test(id: i32, tx_type: &str, day: u32, quantity: f64, price: f64) -> Trade {
+        Trade {
+            id: Some(id),
+            handle: "rivernorth".to_string(),
+            filename: "/tmp/2019-11.xlsx".to_string(),
+            filehash: "abc".to_string(),
+            row: id,
+            account_name: "RN1".to_string(),
+            account_number: "RN1".to_string(),
+            account_type: "".to_string(),
+            account_id: None,
+            security_description: "".to_string(),
+            security_ticker: "OPP".to_string(),
+            asset_class: "FUND".to_string(),
+            security_type: "".to_string(),
+            tx_type: tx_type.to_string(),
+            source_tx_type: tx_type.to_string(),
+            cusip: "".to_string(),
+            price,
+            quantity,
+            commission: 0.,
+            fee: 0.,
+            principal: quantity * price,
+            net_amount: -quantity * price,
+            currency: "USD".to_string(),
+            broker: "".to_string(),
+            trader: "".to_string(),
+            trade_date: 0,
+            settlement_date: 0
+        }.at(11, day, 16)
+    }
 
+    /// moves it to another month, day and hour of 2019, still settling two days later
+    pub(crate) fn at(self, month: u32, day: u32, hour: u32) -> Trade {
+        let ts = chrono::NaiveDate::from_ymd_opt(2019, month, day).unwrap().and_hms_opt(hour, 0, 0).unwrap().timestamp();
+        Trade { trade_date: ts, settlement_date: ts + 2 * 86400, ..self }
     }
 }
+
 pub async fn build_trades_table(client: &tokio_postgres::Client) -> Result<(), Error> {
 
     // Now we can execute a simple statement that just returns its parameter.
@@ -118,11 +347,14 @@ pub async fn build_trades_table(client: &tokio_postgres::Client) -> Result<(), E
         row INT NOT NULL,
         account_name VARCHAR NOT NULL,
         account_number VARCHAR NOT NULL,
+        account_type VARCHAR NOT NULL,
+        account_id INT,
         security_ticker VARCHAR NOT NULL,
         security_description VARCHAR NOT NULL,
         asset_class VARCHAR NOT NULL,
         security_type VARCHAR NOT NULL,
         tx_type VARCHAR NOT NULL,
+        source_tx_type VARCHAR NOT NULL,
         cusip VARCHAR NOT NULL,
         price FLOAT8 NOT NULL,
         quantity FLOAT8 NOT NULL,
@@ -130,10 +362,12 @@ pub async fn build_trades_table(client: &tokio_postgres::Client) -> Result<(), E
         fee FLOAT8 NOT NULL,
         principal FLOAT8 NOT NULL,
         net_amount FLOAT8 NOT NULL,
+        currency VARCHAR NOT NULL,
         trade_date BIGINT NOT NULL,
         settlement_date BIGINT NOT NULL,
         broker VARCHAR NOT NULL,
-        trader VARCHAR NOT NULL
+        trader VARCHAR NOT NULL,
+        UNIQUE (handle, filehash, row)
         )", &[]).await?;
 
     Ok(())
@@ -161,28 +395,211 @@ pub async fn get_all_trades(client: &tokio_postgres::Client, handle: &str) -> Re
                 debug!("pushed")
             }
         },
-        Err(_) => debug!("{:?}", "wtf")
+        Err(e) => return Err(e.into())
     }
 
     Ok(v)
 }
 
 
-async fn clean_chains(client: &tokio_postgres::Client, handle: &str) -> Result<(), Error> {
+/// The chains live in tradellama next to the trades their members point at, with chain_runs alongside so a rebuild
+/// and its run commit together. chain_rows lays them out the way altpilot's chains table has them, a head row
+/// then a row for every member, with the trade's fields as they are now rather than copies taken at chain time.
+/// altpilot can't see tradellama, so publish_chains copies them over into that table after every run.
+/// direction is worked out in code off the tx type mappings, so chain_members keeps it rather than the view.
+pub async fn build_chain_tables(client: &tokio_postgres::Client) -> Result<(), Error> {
 
-    let statement = client.prepare("delete from chains where handle = $1").await?;
-    client.execute(&statement,&[&handle]).await?;
+    client.query("CREATE TABLE chains (id VARCHAR PRIMARY KEY,
+        handle VARCHAR NOT NULL,
+        rule VARCHAR NOT NULL,
+        members INT NOT NULL,
+        holding_secs BIGINT NOT NULL,
+        net_quantity FLOAT8 NOT NULL,
+        gross_notional FLOAT8 NOT NULL,
+        realized_pnl FLOAT8 NOT NULL,
+        costs FLOAT8 NOT NULL,
+        flat BOOLEAN NOT NULL,
+        inserted_at TIMESTAMP NOT NULL,
+        updated_at TIMESTAMP NOT NULL
+        )", &[]).await?;
+
+    client.query("CREATE TABLE chain_members (id SERIAL PRIMARY KEY,
+        chain_id VARCHAR NOT NULL REFERENCES chains (id) ON DELETE CASCADE,
+        trade_id INT NOT NULL REFERENCES trades (id),
+        role VARCHAR NOT NULL,
+        position INT NOT NULL,
+        direction VARCHAR NOT NULL,
+        UNIQUE (chain_id, position)
+        )", &[]).await?;
+
+    client.query("CREATE INDEX chain_members_trade_id ON chain_members (trade_id)", &[]).await?;
+
+    client.query("CREATE TABLE chain_runs (run_id SERIAL PRIMARY KEY,
+        handle VARCHAR NOT NULL,
+        mode VARCHAR NOT NULL,
+        parameters VARCHAR NOT NULL,
+        trades INT NOT NULL,
+        chains INT NOT NULL,
+        members INT NOT NULL,
+        removed INT NOT NULL,
+        last_trade_id INT NOT NULL,
+        started_at TIMESTAMP NOT NULL,
+        duration_ms BIGINT NOT NULL,
+        inserted_at TIMESTAMP NOT NULL,
+        updated_at TIMESTAMP NOT NULL
+        )", &[]).await?;
+
+    client.query("CREATE TABLE chain_trades (trade_id INT PRIMARY KEY REFERENCES trades (id) ON DELETE CASCADE,
+        handle VARCHAR NOT NULL,
+        hash VARCHAR NOT NULL
+        )", &[]).await?;
+
+    client.query("CREATE INDEX chain_trades_handle ON chain_trades (handle)", &[]).await?;
+
+    client.query("CREATE VIEW chain_rows AS SELECT
+        m.id,
+        c.handle,
+        t.filename,
+        t.filehash,
+        t.row,
+        c.id AS chain_id,
+        m.role = 'head' AS head,
+        t.security_ticker,
+        t.account_name,
+        t.tx_type,
+        m.direction,
+        t.price,
+        t.quantity,
+        t.commission,
+        t.net_amount,
+        t.currency,
+        t.broker,
+        to_timestamp(t.trade_date) AT TIME ZONE 'UTC' AS trade_date,
+        to_timestamp(t.settlement_date) AT TIME ZONE 'UTC' AS settlement_date,
+        c.inserted_at,
+        c.updated_at
+        FROM chain_members m
+        JOIN chains c ON c.id = m.chain_id
+        JOIN trades t ON t.id = m.trade_id", &[]).await?;
 
     Ok(())
+}
+
+pub async fn drop_chain_tables(client: &tokio_postgres::Client) -> Result<(), Error> {
 
+    client.query("drop VIEW chain_rows", &[]).await?;
+    client.query("drop TABLE chain_members", &[]).await?;
+    client.query("drop TABLE chains", &[]).await?;
+    client.query("drop TABLE chain_runs", &[]).await?;
+    client.query("drop TABLE chain_trades", &[]).await?;
+
+    Ok(())
+}
+
+async fn insert_chain_run(client: &Transaction<'_>, run: &ChainRun) -> Result<i32, Error> {
+
+    let statement = client.prepare("INSERT INTO chain_runs (
+        handle,
+        mode,
+        parameters,
+        trades,
+        chains,
+        members,
+        removed,
+        last_trade_id,
+        started_at,
+        duration_ms,
+        inserted_at,
+        updated_at
+        ) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12) RETURNING run_id").await?;
+
+    let row = client.query_one(&statement,&[
+        &run.handle,
+        &run.mode,
+        &serde_json::to_string(&run.parameters).unwrap_or_default(),
+        &run.trades,
+        &run.chains,
+        &run.members,
+        &run.removed,
+        &run.last_trade_id,
+        &run.started_at,
+        &run.duration_ms,
+        &SystemTime::now(),
+        &SystemTime::now()
+        ]).await?;
+    let run_id = row.get("run_id");
+
+    // the handle's hashes are swapped for this run's, nothing older is worth keeping
+    client.execute("DELETE FROM chain_trades WHERE handle = $1", &[&run.handle]).await?;
+    let statement = client.prepare("INSERT INTO chain_trades (trade_id, handle, hash) VALUES ($1, $2, $3)").await?;
+    for (trade_id, hash) in &run.trade_hashes {
+        client.execute(&statement, &[trade_id, &run.handle, hash]).await?;
+    }
+
+    Ok(run_id)
 }
 
-async fn insert_chain(client: &tokio_postgres::Client, chain: &TradeChain) -> Result<(), Error> {
+pub(crate) async fn get_last_chain_run(client: &tokio_postgres::Client, handle: &str) -> Result<Option<ChainRun>, Error> {
+
+    let rows = client.query("SELECT * FROM chain_runs WHERE handle = $1 ORDER BY run_id DESC LIMIT 1", &[&handle]).await?;
+    let Some(mut run) = rows.into_iter().next().map(ChainRun::from) else {
+        return Ok(None)
+    };
+    let rows = client.query("SELECT trade_id, hash FROM chain_trades WHERE handle = $1", &[&handle]).await?;
+    run.trade_hashes = rows.into_iter().map(|r| (r.get("trade_id"), r.get("hash"))).collect();
+    Ok(Some(run))
+}
 
-    let chain_id = ObjectId::new().to_string();
+pub(crate) async fn get_chains(client: &tokio_postgres::Client, handle: &str) -> Result<Vec<TradeChain>, Error> {
+
+    let rows = client.query("SELECT m.chain_id, m.role, c.holding_secs, c.net_quantity, c.gross_notional, c.realized_pnl, c.costs, c.flat, t.*
+        FROM chain_members m
+        JOIN chains c ON c.id = m.chain_id
+        JOIN trades t ON t.id = m.trade_id
+        WHERE c.handle = $1 ORDER BY m.chain_id, m.position", &[&handle]).await?;
+    let mut v: Vec<TradeChain> = Vec::new();
+    for r in rows {
+        let chain_id: String = r.get("chain_id");
+        let role: String = r.get("role");
+        if role == HEAD_ROLE {
+            let metrics = ChainMetrics::from(&r);
+            v.push(TradeChain { chain_id, head: Trade::from(r), chain: Vec::new(), metrics });
+        } else if let Some(c) = v.last_mut().filter(|c| c.chain_id == chain_id) {
+            c.chain.push(Trade::from(r));
+        }
+    }
+    Ok(v)
+}
 
+/// Writes chains and records the run in one transaction so altpilot never sees a handle with no chains or half of them.
+/// removed None clears every chain the handle has first, otherwise just those chain ids go, their members with them.
+/// The transaction rolls back if this errors or is dropped part way, so it needs a connection of its own.
+pub(crate) async fn write_chains(client: &mut tokio_postgres::Client, handle: &str, chains: &[TradeChain], removed: Option<&[String]>, run: &ChainRun) -> Result<i32, Error> {
+
+    let tx = client.transaction().await?;
+    match removed {
+        None => clean_chains(&tx, handle).await?,
+        Some(ids) => {
+            tx.execute("DELETE FROM chains WHERE handle = $1 AND id = ANY($2)", &[&handle, &ids]).await?;
+        }
+    }
+    for c in chains {
+        insert_chain(&tx, c, &run.parameters.rule).await?;
+    }
+    let run_id = insert_chain_run(&tx, run).await?;
+    tx.commit().await?;
+
+    Ok(run_id)
+}
 
-    let statement = client.prepare("INSERT INTO chains (
+/// Swaps the handle's rows in altpilot's chains table for these chains, a head row then a row per member, in one
+/// transaction. Amounts go in unsigned the way altpilot has always had them.
+pub(crate) async fn publish_chains(client: &mut tokio_postgres::Client, handle: &str, chains: &[TradeChain]) -> Result<(), Error> {
+
+    let tx = client.transaction().await?;
+    clean_chains(&tx, handle).await?;
+
+    let statement = tx.prepare("INSERT INTO chains (
         handle,
         filename,
         filehash,
@@ -203,91 +620,101 @@ async fn insert_chain(client: &tokio_postgres::Client, chain: &TradeChain) -> Re
         updated_at
         ) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16, $17, $18)").await?;
 
-    info!("{:?}", &chain.head);
+    for chain in chains {
+        for (role, t) in chain.rows() {
+            tx.execute(&statement,&[
+                &t.handle,
+                &t.filename,
+                &t.filehash,
+                &t.row,
+                &chain.chain_id,
+                &(role == HEAD_ROLE),
+                &t.security_ticker,
+                &t.account_name,
+                &t.tx_type,
+                &t.price.abs(),
+                &t.quantity.abs(),
+                &t.commission.abs(),
+                &t.net_amount.abs(),
+                &t.broker,
+                &NaiveDateTime::from_timestamp_opt(t.trade_date,0),
+                &NaiveDateTime::from_timestamp_opt(t.settlement_date,0),
+                &SystemTime::now(),
+                &SystemTime::now()
+                ]).await?;
+        }
+    }
+    tx.commit().await?;
 
-    client.execute(&statement,&[
-        &chain.head.handle,
-        &chain.head.filename,
-        &chain.head.filehash,
-        &chain.head.row,
-        &chain_id,
-        &true,
-        &chain.head.security_ticker,
-        &chain.head.account_name,
-        &chain.head.tx_type,
-        &chain.head.price.abs(),
-        &chain.head.quantity.abs(),
-        &chain.head.commission.abs(),
-        &chain.head.net_amount.abs(),
-        &chain.head.broker,
-        &NaiveDateTime::from_timestamp_opt(chain.head.trade_date,0),
-        &NaiveDateTime::from_timestamp_opt(chain.head.settlement_date,0),
-        &SystemTime::now(),
-        &SystemTime::now()
-        ]).await?;
+    Ok(())
+}
 
+async fn clean_chains(client: &Transaction<'_>, handle: &str) -> Result<(), Error> {
 
+    let statement = client.prepare("delete from chains where handle = $1").await?;
+    client.execute(&statement,&[&handle]).await?;
 
-    for t in &chain.chain {
+    Ok(())
 
+}
 
-        let statement = client.prepare("INSERT INTO chains (
-            handle,
-            filename,
-            filehash,
-            row,
-            chain_id,
-            head,
-            security_ticker,
-            account_name,
-            tx_type,
-            price,
-            quantity,
-            commission,
-            net_amount,
-            broker,
-            trade_date,
-            settlement_date,
-            inserted_at,
-            updated_at
-            ) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16, $17, $18)").await?;
+async fn insert_chain(client: &Transaction<'_>, chain: &TradeChain, rule: &str) -> Result<(), Error> {
 
-        info!("{:?}", &chain.head);
+    info!("{:?}", &chain.head);
 
-        client.execute(&statement,&[
-            &t.handle,
-            &t.filename,
-            &t.filehash,
-            &t.row,
-            &chain_id,
-            &false,
-            &t.security_ticker,
-            &t.account_name,
-            &t.tx_type,
-            &t.price.abs(),
-            &t.quantity.abs(),
-            &t.commission.abs(),
-            &t.net_amount.abs(),
-            &t.broker,
-            &NaiveDateTime::from_timestamp_opt(t.trade_date,0),
-            &NaiveDateTime::from_timestamp_opt(t.settlement_date,0),
-            &SystemTime::now(),
-            &SystemTime::now()
-            ]).await?;
+    client.execute("INSERT INTO chains (
+        id,
+        handle,
+        rule,
+        members,
+        holding_secs,
+        net_quantity,
+        gross_notional,
+        realized_pnl,
+        costs,
+        flat,
+        inserted_at,
+        updated_at
+        ) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12)", &[
+        &chain.chain_id,
+        &chain.head.handle,
+        &rule,
+        &(chain.chain.len() as i32),
+        &chain.metrics.holding_secs,
+        &chain.metrics.net_quantity,
+        &chain.metrics.gross_notional,
+        &chain.metrics.realized_pnl,
+        &chain.metrics.costs,
+        &chain.metrics.flat,
+        &SystemTime::now(),
+        &SystemTime::now()
+        ]).await?;
 
+    // the one prepared statement does the head and every member
+    let statement = client.prepare("INSERT INTO chain_members (
+        chain_id,
+        trade_id,
+        role,
+        position,
+        direction
+        ) VALUES ($1, $2, $3, $4, $5)").await?;
 
+    for (position, (role, t)) in chain.rows().enumerate() {
+        client.execute(&statement,&[
+            &chain.chain_id,
+            &member_id(t)?,
+            &role,
+            &(position as i32),
+            &t.direction().to_string()
+            ]).await?;
     }
 
-
     Ok(())
-
-
-
 }
 
 
 
-async fn insert_file_summary(client: &tokio_postgres::Client, summary: &FileSummary) -> Result<(), Error> {
+pub(crate) async fn insert_file_summary(client: &tokio_postgres::Client, summary: &FileSummary) -> Result<(), Error> {
 
     let statement = client.prepare("INSERT INTO file_summaries (
         handle,
@@ -310,21 +737,25 @@ async fn insert_file_summary(client: &tokio_postgres::Client, summary: &FileSumm
 }
 
 
-async fn insert_account_summary(client: &tokio_postgres::Client, summary: &AccountSummary) -> Result<(), Error> {
+pub(crate) async fn insert_account_summary(client: &tokio_postgres::Client, summary: &AccountSummary) -> Result<(), Error> {
 
     let statement = client.prepare("INSERT INTO account_summaries (
         handle,
         tx_type,
+        direction,
         account_name,
+        currency,
         calc,
         inserted_at,
         updated_at
-        ) VALUES ($1, $2, $3, $4, $5, $6)").await?;
+        ) VALUES ($1, $2, $3, $4, $5, $6, $7, $8)").await?;
 
     client.execute(&statement,&[
         &summary.handle,
         &summary.tx_type, 
+        &summary.direction,
         &summary.account_name,
+        &summary.currency,
         &summary.calc,
         &SystemTime::now(),
         &SystemTime::now()
@@ -333,21 +764,53 @@ async fn insert_account_summary(client: &tokio_postgres::Client, summary: &Accou
 }
 
 
-async fn insert_security_summary(client: &tokio_postgres::Client, summary: &SecuritySummary) -> Result<(), Error> {
+pub(crate) async fn insert_security_summary(client: &tokio_postgres::Client, summary: &SecuritySummary) -> Result<(), Error> {
 
     let statement = client.prepare("INSERT INTO security_summaries (
         handle,
         tx_type,
+        direction,
         security_ticker,
+        currency,
         calc,
         inserted_at,
         updated_at
-        ) VALUES ($1, $2, $3, $4, $5, $6)").await?;
+        ) VALUES ($1, $2, $3, $4, $5, $6, $7, $8)").await?;
 
     client.execute(&statement,&[
         &summary.handle,
         &summary.tx_type, 
+        &summary.direction,
         &summary.security_ticker,
+        &summary.currency,
+        &summary.calc,
+        &SystemTime::now(),
+        &SystemTime::now()
+        ]).await?;
+    Ok(())
+}
+
+pub(crate) async fn insert_hierarchy_summary(client: &tokio_postgres::Client, summary: &HierarchySummary) -> Result<(), Error> {
+
+    let statement = client.prepare("INSERT INTO hierarchy_summaries (
+        handle,
+        level,
+        node,
+        tx_type,
+        direction,
+        currency,
+        calc,
+        inserted_at,
+        updated_at
+        ) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)").await?;
+
+    client.execute(&statement,&[
+        &summary.handle,
+        &summary.level,
+        &summary.node,
+        &summary.tx_type,
+        &summary.direction,
+        &summary.currency,
         &summary.calc,
         &SystemTime::now(),
         &SystemTime::now()
@@ -355,99 +818,216 @@ async fn insert_security_summary(client: &tokio_postgres::Client, summary: &Secu
     Ok(())
 }
 
-pub async fn summarize(client: &tokio_postgres::Client, alt_client: &tokio_postgres::Client, handle: &str) -> Result<(), Error> {
+/// Reads everything it needs once up front and writes each kind of summary as one batch of concurrent inserts,
+/// which a pool spreads over its connections and a single client pipelines.
+pub async fn summarize(store: &dyn TradeStore, summaries: &dyn SummaryStore, handle: &str) -> Result<(), Error> {
 
-    for t in get_all_trades(client, handle).await?.into_iter() {
+    let (all_trades, base_currency, rates, tree, targets) = futures::try_join!(
+        store.get_all_trades(handle),
+        store.get_base_currency(handle),
+        store.get_fx_rates(),
+        store.get_hierarchy(),
+        store.get_commission_targets(handle)
+    )?;
+
+    for t in &all_trades {
         info!("{:?}", t);
     }
 
-    let values: Vec<(String,String)> = get_all_trades(client, handle).await?.into_iter().map(|x| (x.filename.clone(), x.filehash.clone()) ).rev().collect();
+    let values: Vec<(String,String)> = all_trades.iter().map(|x| (x.filename.clone(), x.filehash.clone()) ).rev().collect();
     let g = values.iter().fold(HashMap::new(), |mut acc, c| {
         *acc.entry((c.0.clone(),c.1.clone())).or_insert(0) += 1;
         acc
     });
-    for k in g.keys() {
-        let s = FileSummary {
-            handle: handle.to_string(),
-            filename: k.0.clone(),
-            filehash: k.1.clone(),
-            calc: g[k] as f64
-        };
-        insert_file_summary(&alt_client, &s).await?;
+    let file_summaries: Vec<FileSummary> = g.keys().map(|k| FileSummary {
+        handle: handle.to_string(),
+        filename: k.0.clone(),
+        filehash: k.1.clone(),
+        calc: g[k] as f64
+    }).collect();
+    try_join_all(file_summaries.iter().map(|s| {
         info!("{:?}", s);
+        summaries.insert_file_summary(s)
+    })).await?;
+
+    // net amounts go into the fund's base currency at the trade date rate, and every conversion gets audited
+    let mut base_amounts: HashMap<i32, f64> = HashMap::new();
+    let mut base_rates: HashMap<i32, f64> = HashMap::new();
+    let mut audits: Vec<(String, fx::Conversion)> = Vec::new();
+    for t in &all_trades {
+        let amount = match fx::convert(&rates, t.net_amount, &t.currency, &base_currency, utils::to_date(t.trade_date)) {
+            Some(c) => {
+                let converted = c.converted;
+                base_rates.insert(t.id.unwrap_or(0), c.rate);
+                if c.from_currency != c.to_currency {
+                    audits.push((t.id.unwrap_or(0).to_string(), c));
+                }
+                converted
+            },
+            None => {
+                warn!("no {}/{} rate for trade {:?} on {:?}, leaving it unconverted", t.currency, base_currency, t.id, utils::to_date(t.trade_date));
+                t.net_amount
+            }
+        };
+        base_amounts.insert(t.id.unwrap_or(0), amount);
     }
+    try_join_all(audits.iter().map(|(reference, c)| store.insert_fx_audit(handle, "summary", reference, c))).await?;
 
-    let values: Vec<(String,String, f64)> = get_all_trades(client, handle).await?.into_iter().map(|x| (x.tx_type.clone().to_uppercase(), x.account_name.clone().to_uppercase(), x.net_amount.abs()) ).rev().collect();
+    // net amounts are signed cash flows, so calc keeps the direction
+    let values: Vec<(String,String,String, f64)> = all_trades.iter().map(|x| (x.tx().to_string(), x.direction().to_string(), x.account_name.clone().to_uppercase(), base_amounts[&x.id.unwrap_or(0)]) ).rev().collect();
     let g = values.iter().fold(HashMap::new(), |mut acc, c| {
-        *acc.entry((c.0.clone(),c.1.clone())).or_insert(c.2) += c.2;
+        *acc.entry((c.0.clone(),c.1.clone(),c.2.clone())).or_insert(0.) += c.3;
         acc
     });
 
-    println!("{:?}",g);
-
-    for k in g.keys() {
-        let s = AccountSummary {
-            handle: handle.to_string(),
-            tx_type: k.0.clone(),
-            account_name: k.1.clone(),
-            calc: g[k] as f64
-        };
-        insert_account_summary(&alt_client, &s).await?;
+    info!("{:?}",g);
+
+    let account_summaries: Vec<AccountSummary> = g.keys().map(|k| AccountSummary {
+        handle: handle.to_string(),
+        tx_type: k.0.clone(),
+        direction: k.1.clone(),
+        account_name: k.2.clone(),
+        currency: base_currency.clone(),
+        calc: g[k]
+    }).collect();
+    try_join_all(account_summaries.iter().map(|s| {
         info!("{:?}", s);
-    }
+        summaries.insert_account_summary(s)
+    })).await?;
  
  
-    let values: Vec<(String,String, f64)> = get_all_trades(client, handle).await?.into_iter().map(|x| (x.tx_type.clone().to_uppercase(), x.security_ticker.clone().to_uppercase(), x.net_amount.abs()) ).rev().collect();
+    let values: Vec<(String,String,String, f64)> = all_trades.iter().map(|x| (x.tx().to_string(), x.direction().to_string(), x.security_ticker.clone().to_uppercase(), base_amounts[&x.id.unwrap_or(0)]) ).rev().collect();
     let g = values.iter().fold(HashMap::new(), |mut acc, c| {
-        *acc.entry((c.0.clone(),c.1.clone())).or_insert(c.2) += c.2;
+        *acc.entry((c.0.clone(),c.1.clone(),c.2.clone())).or_insert(0.) += c.3;
         acc
     });
 
-    for k in g.keys() {
-        let s = SecuritySummary {
-            handle: handle.to_string(),
-            tx_type: k.0.clone(),
-            security_ticker: k.1.clone(),
-            calc: g[k] as f64
-        };
-        insert_security_summary(&alt_client, &s).await?;
+    let security_summaries: Vec<SecuritySummary> = g.keys().map(|k| SecuritySummary {
+        handle: handle.to_string(),
+        tx_type: k.0.clone(),
+        direction: k.1.clone(),
+        security_ticker: k.2.clone(),
+        currency: base_currency.clone(),
+        calc: g[k]
+    }).collect();
+    try_join_all(security_summaries.iter().map(|s| {
         info!("{:?}", s);
+        summaries.insert_security_summary(s)
+    })).await?;
+
+    let mut hierarchy_summaries: Vec<HierarchySummary> = Vec::new();
+    for level in Level::ALL {
+        let g = all_trades.iter().fold(HashMap::new(), |mut acc, t| {
+            *acc.entry((tree.node(t, level), t.tx().to_string(), t.direction().to_string())).or_insert(0.) += base_amounts[&t.id.unwrap_or(0)];
+            acc
+        });
+
+        for (k, calc) in g {
+            hierarchy_summaries.push(HierarchySummary {
+                handle: handle.to_string(),
+                level: level.to_string(),
+                node: k.0,
+                tx_type: k.1,
+                direction: k.2,
+                currency: base_currency.clone(),
+                calc
+            });
+        }
     }
+    try_join_all(hierarchy_summaries.iter().map(|s| {
+        info!("{:?}", s);
+        summaries.insert_hierarchy_summary(s)
+    })).await?;
+
+    // commission goes over at the same rate as the trade's net amount
+    let commission_summaries = commissions::summaries(handle, &all_trades, &base_rates, &targets, &base_currency);
+    try_join_all(commission_summaries.iter().map(|s| {
+        info!("{:?}", s);
+        summaries.insert_commission_summary(s)
+    })).await?;
 
     Ok(())
 
 }
 
-pub async fn chain(client: &tokio_postgres::Client, alt_client: &tokio_postgres::Client, handle: &str) -> Result<(), Error> {
+/// Summaries then chains for each handle, the handles running alongside each other since nothing they write overlaps.
+/// chains is wherever the chain tables are, which has to be where the trades are for their foreign keys, and
+/// altpilot is the one the chains get published to.
+pub async fn summarize_and_chain(store: &dyn TradeStore, summaries: &dyn SummaryStore, chains: &dyn SummaryStore, altpilot: &dyn SummaryStore, handles: &[&str]) -> Result<(), Error> {
+
+    try_join_all(handles.iter().map(|handle| async move {
+        summarize(store, summaries, handle).await?;
+        chain(store, chains, altpilot, handle).await
+    })).await?;
+
+    Ok(())
+}
+
+/// A line per chain with what it came to, and the realized P&L and costs over all of them. Reads what's stored,
+/// so the numbers are the ones worked out on the trades as adjusted for corporate actions when the chains were built.
+pub async fn chain_report(chains: &dyn SummaryStore, handle: &str) -> Result<Vec<TradeChain>, Error> {
+
+    let v = chains.get_chains(handle).await?;
+    for c in &v {
+        let m = &c.metrics;
+        info!("{} {} {} members held {}s net {} gross {:.2} pnl {:.2} costs {:.2}{}",
+            c.chain_id, c.head.security_ticker, c.chain.len(), m.holding_secs, m.net_quantity, m.gross_notional,
+            m.realized_pnl, m.costs, if m.flat { " flat" } else { "" });
+    }
+    info!("{} chains for {:?}, {} flat, pnl {:.2} after {:.2} of costs", v.len(), handle,
+        v.iter().filter(|c| c.metrics.flat).count(),
+        v.iter().map(|c| c.metrics.realized_pnl).sum::<f64>(),
+        v.iter().map(|c| c.metrics.costs).sum::<f64>());
+
+    Ok(v)
+}
+
+/// A chain's id is down to its head alone, the handle, file and row it came from, so rebuilding gives every chain
+/// back the id it had and altpilot's links to it hold. 24 hex characters like the ObjectIds it used to get.
+pub fn chain_id(head: &Trade) -> String {
+    let mut id = utils::sha_str(&format!("{}|{}|{}", head.handle, head.filehash, head.row));
+    id.truncate(24);
+    id
+}
+
+/// What a trade looked like to a chain run, any change to it after corporate actions gives a different hash.
+pub fn trade_hash(t: &Trade) -> String {
+    let mut hash = utils::sha_str(&serde_json::to_string(t).unwrap_or_default());
+    hash.truncate(16);
+    hash
+}
 
-    info!("first I'll clean up for {:?}", handle);
-    clean_chains(alt_client, handle).await?;
+fn trade_hashes(trades: &[Trade]) -> BTreeMap<i32, String> {
+    trades.iter().filter_map(|t| t.id.map(|id| (id, trade_hash(t)))).collect()
+}
+
+/// Chains over trades in id order. Each trade goes in at most one chain, headed by the earliest trade it's chained to.
+pub fn detect_chains(all_trades: &[Trade]) -> Vec<TradeChain> {
 
     let mut payload: Vec<TradeChain> = Vec::new();
 
     let mut already_in_a_chain: HashSet<i32> = HashSet::new();
 
-    let all_trades = get_all_trades(client, handle).await?;
-    for t in &all_trades {
+    for t in all_trades {
         let mut ch: Vec<Trade> = Vec::new();
-        for t2 in &all_trades {
+        for t2 in all_trades {
             // we do this because if already in a chain, i don't need to make an inner chain
-            if !already_in_a_chain.contains(&t2.id.unwrap()) {
-                if t.is_chained(&t2) {
-                    ch.push(t2.clone());
-                    already_in_a_chain.insert(t2.id.unwrap());
-                }                
+            if !already_in_a_chain.contains(&t2.id.unwrap()) && t.is_chained(t2) {
+                ch.push(t2.clone());
+                already_in_a_chain.insert(t2.id.unwrap());
             }
         }
 
-        let tx_types: Vec<String> = ch.iter().map(|x| x.tx_type.clone()).collect();
-        info!("{:?}", tx_types);
+        let tx_types: Vec<TxType> = ch.iter().map(|x| x.tx()).collect();
+        debug!("{:?}", tx_types);
         let tx_types_u: Vec<_> = tx_types.into_iter().unique().collect();
-        info!("{:?}", tx_types_u);
+        debug!("{:?}", tx_types_u);
 
-        if ch.len() > 0 && tx_types_u.len() > 1 { //there's distinct tx type greater than 2
+        if !ch.is_empty() && tx_types_u.len() >= MIN_CHAIN_TX_TYPES { //there's distinct tx type greater than 2
             let tc = TradeChain {
+                chain_id: chain_id(t),
                 head: t.clone(),
+                metrics: ChainMetrics::of(t, &ch),
                 chain: ch
             };
             payload.push(tc);            
@@ -455,15 +1035,159 @@ pub async fn chain(client: &tokio_postgres::Client, alt_client: &tokio_postgres:
 
     }
 
-    for c in payload {
-        // info!("HEAD {:?} {:?} {:?} {:?} {:?}", c.head.id, c.head.security_ticker, c.head.tx_type, c.head.trade_date, c.head.settlement_date);
-        // for cm in c.chain {
-        //     info!("MEMB {:?} {:?} {:?} {:?} {:?}", cm.id, cm.security_ticker, cm.tx_type, cm.trade_date, cm.settlement_date);
-        // }
-        insert_chain(alt_client, &c).await?;
+    payload
+}
+
+/// Groups trades of the same ticker whose windows, trade date up to settlement, overlap one after another.
+/// Nothing chains across two groups, so a group can be re-evaluated without looking at the rest.
+pub fn window_groups(trades: &[Trade]) -> Vec<Vec<&Trade>> {
+
+    let mut sorted: Vec<&Trade> = trades.iter().collect();
+    sorted.sort_by(|a, b| a.security_ticker.cmp(&b.security_ticker).then(a.trade_date.cmp(&b.trade_date)));
+
+    let mut groups: Vec<Vec<&Trade>> = Vec::new();
+    let mut end = i64::MIN;
+    for t in sorted {
+        match groups.last_mut() {
+            Some(g) if g[0].security_ticker == t.security_ticker && t.trade_date < end => {
+                g.push(t);
+                end = end.max(t.settlement_date);
+            },
+            _ => {
+                groups.push(vec![t]);
+                end = t.settlement_date;
+            }
+        }
     }
+    groups
+}
 
-    Ok(())
+async fn chain_parameters(store: &dyn TradeStore, handle: &str) -> Result<(ChainParameters, Vec<Trade>), Error> {
+
+    // chains key on ticker, so line history up with renames and splits first
+    let actions = store.get_corporate_actions().await?;
+    let all_trades = corporate_actions::apply(&store.get_all_trades(handle).await?, &actions);
+    let parameters = ChainParameters {
+        rule: CHAIN_RULE.to_string(),
+        min_tx_types: MIN_CHAIN_TX_TYPES,
+        corporate_actions: actions.len()
+    };
+    Ok((parameters, all_trades))
+}
+
+/// Rebuilds a handle's chains from scratch, the old ones stay put until the new set's ready to swap in.
+/// summaries has the chain tables, next to the trades, and altpilot gets its copy once they're in.
+pub async fn chain(store: &dyn TradeStore, summaries: &dyn SummaryStore, altpilot: &dyn SummaryStore, handle: &str) -> Result<ChainRun, Error> {
+
+    let started = Instant::now();
+    let started_at = chrono::Utc::now().naive_utc();
+
+    let (parameters, all_trades) = chain_parameters(store, handle).await?;
+    let payload = detect_chains(&all_trades);
+
+    // info!("HEAD {:?} {:?} {:?} {:?} {:?}", c.head.id, c.head.security_ticker, c.head.tx_type, c.head.trade_date, c.head.settlement_date);
+    // for cm in c.chain {
+    //     info!("MEMB {:?} {:?} {:?} {:?} {:?}", cm.id, cm.security_ticker, cm.tx_type, cm.trade_date, cm.settlement_date);
+    // }
+    let mut run = ChainRun {
+        run_id: None,
+        handle: handle.to_string(),
+        mode: "full".to_string(),
+        parameters,
+        trades: all_trades.len() as i32,
+        chains: payload.len() as i32,
+        members: payload.iter().map(|c| c.chain.len() as i32).sum(),
+        removed: 0,
+        last_trade_id: all_trades.iter().filter_map(|t| t.id).max().unwrap_or(0),
+        trade_hashes: trade_hashes(&all_trades),
+        started_at,
+        duration_ms: started.elapsed().as_millis() as i64
+    };
+    info!("swapping in {} chains for {:?}", run.chains, handle);
+    run.run_id = Some(summaries.replace_chains(handle, &payload, &run).await?);
+    altpilot.publish_chains(handle, &payload).await?;
+
+    Ok(run)
+
+}
+
+/// Only re-evaluates the window groups holding trades ingested or changed in place since the last run, or chain
+/// members whose trades changed, rewrites the chains in them (a chain whose head hasn't changed comes back with the same chain_id),
+/// and leaves every other chain alone. Falls back to a full rebuild when there's no earlier run or the rules
+/// it ran with aren't the ones we'd run with now. A trade in a chain can't be deleted, chain_members holds onto it.
+pub async fn chain_incremental(store: &dyn TradeStore, summaries: &dyn SummaryStore, altpilot: &dyn SummaryStore, handle: &str) -> Result<ChainRun, Error> {
+
+    let started = Instant::now();
+    let started_at = chrono::Utc::now().naive_utc();
+
+    let (parameters, all_trades) = chain_parameters(store, handle).await?;
+    let last = match summaries.get_last_chain_run(handle).await? {
+        Some(last) if last.parameters == parameters => last,
+        _ => {
+            info!("nothing comparable to build on for {:?}, rebuilding every chain", handle);
+            return chain(store, summaries, altpilot, handle).await
+        }
+    };
+    let stored = summaries.get_chains(handle).await?;
+
+    let key = |t: &Trade| (t.filehash.clone(), t.row);
+    let groups = window_groups(&all_trades);
+    let mut group_of: HashMap<(String, i32), usize> = HashMap::new();
+    for (i, g) in groups.iter().enumerate() {
+        for t in g {
+            group_of.insert(key(t), i);
+        }
+    }
+    let changed: Vec<&Trade> = all_trades.iter()
+        .filter(|t| t.id.unwrap_or(0) > last.last_trade_id || last.trade_hashes.get(&t.id.unwrap_or(0)) != Some(&trade_hash(t)))
+        .collect();
+    let mut dirty: HashSet<usize> = changed.iter().filter_map(|t| group_of.get(&key(t)).copied()).collect();
+    // a changed trade may have moved out of the window or ticker its chain was in
+    let mut affected: HashSet<usize> = stored.iter().enumerate()
+        .filter(|(_, c)| c.chain.iter().any(|m| changed.iter().any(|t| t.id == m.id)))
+        .map(|(i, _)| i)
+        .collect();
+
+    // a dirty group drags in the chains that live in it, and a changed chain dirties the groups its other members are in
+    loop {
+        let before = (dirty.len(), affected.len());
+        for (i, c) in stored.iter().enumerate() {
+            if c.chain.iter().any(|m| group_of.get(&key(m)).map(|g| dirty.contains(g)).unwrap_or(false)) {
+                affected.insert(i);
+            }
+        }
+        for i in &affected {
+            dirty.extend(stored[*i].chain.iter().filter_map(|m| group_of.get(&key(m)).copied()));
+        }
+        if (dirty.len(), affected.len()) == before {
+            break
+        }
+    }
+
+    let evaluate: Vec<Trade> = all_trades.iter().filter(|t| group_of.get(&key(t)).map(|g| dirty.contains(g)).unwrap_or(false)).cloned().collect();
+    let payload = detect_chains(&evaluate);
+    let removed: Vec<String> = affected.iter().map(|i| stored[*i].chain_id.clone()).collect();
+
+    let mut run = ChainRun {
+        run_id: None,
+        handle: handle.to_string(),
+        mode: "incremental".to_string(),
+        parameters,
+        trades: evaluate.len() as i32,
+        chains: payload.len() as i32,
+        members: payload.iter().map(|c| c.chain.len() as i32).sum(),
+        removed: removed.iter().filter(|id| !payload.iter().any(|c| &c.chain_id == *id)).count() as i32,
+        last_trade_id: all_trades.iter().filter_map(|t| t.id).max().unwrap_or(0).max(last.last_trade_id),
+        trade_hashes: trade_hashes(&all_trades),
+        started_at,
+        duration_ms: started.elapsed().as_millis() as i64
+    };
+    info!("re-evaluated {} trades in {} window groups for {:?}, writing {} chains", run.trades, dirty.len(), handle, run.chains);
+    run.run_id = Some(summaries.update_chains(handle, &payload, &removed, &run).await?);
+    // altpilot's copy is the handle's rows wholesale, so it gets every chain and not just the ones rewritten
+    altpilot.publish_chains(handle, &summaries.get_chains(handle).await?).await?;
+
+    Ok(run)
 
 }
 
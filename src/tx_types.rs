@@ -0,0 +1,246 @@
+//! Sign convention for everything that lands in trades:
+//! quantity is positive when the position goes up (buys, covers) and negative when it goes down (sells, shorts),
+//! principal and net_amount are cash from the account's point of view, negative when it leaves and positive when it comes in,
+//! price, commission and fee are always magnitudes.
+//! Anything we report on carries the signed numbers along with a Direction so nobody has to guess.
+
+use crate::error::Error;
+use serde::{Serialize,Deserialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+use tracing::info;
+
+/// What a row in an activity file actually is, whatever the administrator called it.
+/// trades.tx_type holds the Display form of these, the file's own wording is kept in source_tx_type.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum TxType {
+    Buy,
+    Sell,
+    SellShort,
+    BuyToCover,
+    Dividend,
+    Interest,
+    Fee,
+    Subscription,
+    Redemption,
+    Transfer,
+    Other
+}
+
+impl fmt::Display for TxType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TxType::Buy => write!(f, "BUY"),
+            TxType::Sell => write!(f, "SELL"),
+            TxType::SellShort => write!(f, "SELL_SHORT"),
+            TxType::BuyToCover => write!(f, "BUY_TO_COVER"),
+            TxType::Dividend => write!(f, "DIVIDEND"),
+            TxType::Interest => write!(f, "INTEREST"),
+            TxType::Fee => write!(f, "FEE"),
+            TxType::Subscription => write!(f, "SUBSCRIPTION"),
+            TxType::Redemption => write!(f, "REDEMPTION"),
+            TxType::Transfer => write!(f, "TRANSFER"),
+            TxType::Other => write!(f, "OTHER"),
+        }
+    }
+}
+
+impl FromStr for TxType {
+    type Err = String;
+
+    /// Only the canonical names, file wording goes through a TxTypeMap.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "BUY" => Ok(TxType::Buy),
+            "SELL" => Ok(TxType::Sell),
+            "SELL_SHORT" => Ok(TxType::SellShort),
+            "BUY_TO_COVER" => Ok(TxType::BuyToCover),
+            "DIVIDEND" => Ok(TxType::Dividend),
+            "INTEREST" => Ok(TxType::Interest),
+            "FEE" => Ok(TxType::Fee),
+            "SUBSCRIPTION" => Ok(TxType::Subscription),
+            "REDEMPTION" => Ok(TxType::Redemption),
+            "TRANSFER" => Ok(TxType::Transfer),
+            "OTHER" => Ok(TxType::Other),
+            other => Err(format!("{} is not a canonical tx type", other))
+        }
+    }
+}
+
+impl TxType {
+    /// Buys, sells and their short cousins, the things chains and execution analysis care about.
+    pub fn is_trade(&self) -> bool {
+        matches!(self, TxType::Buy | TxType::Sell | TxType::SellShort | TxType::BuyToCover)
+    }
+
+    /// Transfers move securities without a trade, everything else in the files only moves cash.
+    pub fn moves_position(&self) -> bool {
+        self.is_trade() || matches!(self, TxType::Transfer | TxType::Other)
+    }
+
+    /// Which way the position goes, None when the file's own sign has to be trusted.
+    pub fn quantity_sign(&self) -> Option<f64> {
+        match self {
+            TxType::Buy | TxType::BuyToCover => Some(1.),
+            TxType::Sell | TxType::SellShort => Some(-1.),
+            TxType::Transfer | TxType::Other => None,
+            _ => Some(0.)
+        }
+    }
+
+    /// Which way cash goes from the account's point of view, None when the file's own sign has to be trusted.
+    pub fn cash_sign(&self) -> Option<f64> {
+        match self {
+            TxType::Buy | TxType::BuyToCover | TxType::Fee | TxType::Redemption => Some(-1.),
+            TxType::Sell | TxType::SellShort | TxType::Dividend | TxType::Interest | TxType::Subscription => Some(1.),
+            TxType::Transfer | TxType::Other => None
+        }
+    }
+}
+
+/// Which way a row goes, buys and sells for trades, in and out for cash only activity.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Direction {
+    Buy,
+    Sell,
+    In,
+    Out
+}
+
+impl fmt::Display for Direction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Direction::Buy => write!(f, "BUY"),
+            Direction::Sell => write!(f, "SELL"),
+            Direction::In => write!(f, "IN"),
+            Direction::Out => write!(f, "OUT"),
+        }
+    }
+}
+
+impl Direction {
+    /// Goes off the tx type, and the signed amounts for transfers and anything else the type can't settle.
+    pub fn of(tx_type: TxType, quantity: f64, net_amount: f64) -> Self {
+        match tx_type {
+            TxType::Buy | TxType::BuyToCover => Direction::Buy,
+            TxType::Sell | TxType::SellShort => Direction::Sell,
+            TxType::Transfer | TxType::Other if quantity != 0. => if quantity > 0. { Direction::Buy } else { Direction::Sell },
+            _ => if net_amount < 0. || tx_type.cash_sign() == Some(-1.) { Direction::Out } else { Direction::In }
+        }
+    }
+}
+
+/// How one source spells its tx types, keyed on the lowercased file value.
+#[derive(Clone, Debug, Default)]
+pub struct TxTypeMap {
+    pub source: String,
+    map: HashMap<String, TxType>
+}
+
+impl TxTypeMap {
+    /// What we know a source sends out of the box, tx_type_mappings rows get layered on top.
+    pub fn defaults(source: &str) -> Self {
+        let pairs: Vec<(&str, TxType)> = match source {
+            "rivernorth" => vec![
+                ("buy", TxType::Buy),
+                ("purchase", TxType::Buy),
+                ("sell", TxType::Sell),
+                ("sale", TxType::Sell),
+                ("sell short", TxType::SellShort),
+                ("short sale", TxType::SellShort),
+                ("buy to cover", TxType::BuyToCover),
+                ("cover short", TxType::BuyToCover),
+                ("dividend", TxType::Dividend),
+                ("cash dividend", TxType::Dividend),
+                ("interest", TxType::Interest),
+                ("fee", TxType::Fee),
+                ("expense", TxType::Fee),
+                ("subscription", TxType::Subscription),
+                ("contribution", TxType::Subscription),
+                ("redemption", TxType::Redemption),
+                ("withdrawal", TxType::Redemption),
+                ("transfer in", TxType::Transfer),
+                ("transfer out", TxType::Transfer),
+                ("journal", TxType::Transfer),
+            ],
+            _ => vec![]
+        };
+        let mut m = TxTypeMap { source: source.to_string(), map: HashMap::new() };
+        for (k, v) in pairs {
+            m.insert(k, v);
+        }
+        m
+    }
+
+    pub fn insert(&mut self, source_tx_type: &str, tx_type: TxType) {
+        self.map.insert(source_tx_type.trim().to_lowercase(), tx_type);
+    }
+
+    /// Canonical names always map to themselves, so already normalized rows go through fine.
+    pub fn map(&self, source_tx_type: &str) -> Option<TxType> {
+        self.map.get(&source_tx_type.trim().to_lowercase()).copied()
+            .or_else(|| source_tx_type.trim().to_uppercase().replace(' ', "_").parse().ok())
+    }
+}
+
+pub async fn build_tx_type_mappings_table(client: &tokio_postgres::Client) -> Result<(), Error> {
+
+    client.query("CREATE TABLE tx_type_mappings (id SERIAL PRIMARY KEY,
+        source VARCHAR NOT NULL,
+        source_tx_type VARCHAR NOT NULL,
+        tx_type VARCHAR NOT NULL,
+        UNIQUE (source, source_tx_type)
+        )", &[]).await?;
+
+    Ok(())
+}
+
+pub async fn drop_tx_type_mappings_table(client: &tokio_postgres::Client) -> Result<(), Error> {
+
+    client.query("drop TABLE tx_type_mappings", &[]).await?;
+
+    Ok(())
+}
+
+/// The built in mapping for a source with whatever's been added in tx_type_mappings.
+pub async fn get_tx_type_map(client: &tokio_postgres::Client, source: &str) -> Result<TxTypeMap, Error> {
+
+    let mut m = TxTypeMap::defaults(source);
+    let rows = client.query("SELECT * FROM tx_type_mappings WHERE source = $1", &[&source]).await?;
+    for r in rows {
+        let source_tx_type: String = r.get("source_tx_type");
+        let tx_type: String = r.get("tx_type");
+        match tx_type.parse() {
+            Ok(t) => m.insert(&source_tx_type, t),
+            Err(e) => info!("ignoring mapping for {:?}: {:?}", source_tx_type, e)
+        }
+    }
+
+    Ok(m)
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn rivernorth_spellings_map() {
+        let m = TxTypeMap::defaults("rivernorth");
+        assert_eq!(m.map("Buy"), Some(TxType::Buy));
+        assert_eq!(m.map("SELL"), Some(TxType::Sell));
+        assert_eq!(m.map(" Sell Short "), Some(TxType::SellShort));
+        assert_eq!(m.map("BUY_TO_COVER"), Some(TxType::BuyToCover));
+        assert_eq!(m.map("Return of Capital"), None);
+    }
+
+    #[test]
+    fn direction_follows_the_convention() {
+        assert_eq!(Direction::of(TxType::SellShort, -100., 1000.), Direction::Sell);
+        assert_eq!(Direction::of(TxType::Transfer, 50., 0.), Direction::Buy);
+        assert_eq!(Direction::of(TxType::Fee, 0., -10.), Direction::Out);
+        assert_eq!(Direction::of(TxType::Other, 0., 25.), Direction::In);
+    }
+}
@@ -1,13 +1,14 @@
 //use core::error::Error;
 
-use std::error::Error;
-use data_encoding::HEXUPPER;
+use crate::error::Error;
+use data_encoding::{HEXLOWER, HEXUPPER};
 use ring::digest::{Context, Digest, SHA256};
 use std::fs::File;
 use std::io::{BufReader, Read};
+use chrono::{NaiveDate, NaiveDateTime};
 
 
-pub fn sha256_digest<R: Read>(mut reader: R) -> Result<Digest, Box<dyn Error>> {
+pub fn sha256_digest<R: Read>(mut reader: R) -> Result<Digest, std::io::Error> {
     let mut context = Context::new(&SHA256);
     let mut buffer = [0; 1024];
 
@@ -22,15 +23,40 @@ pub fn sha256_digest<R: Read>(mut reader: R) -> Result<Digest, Box<dyn Error>> {
     Ok(context.finish())
 }
 
-pub fn sha_fmt(ifile: &str) -> Result<String, Box<dyn Error>> {
+pub fn sha_fmt(ifile: &str) -> Result<String, Error> {
 
-    let input = File::open(ifile).unwrap();
+    let input = File::open(ifile).map_err(|e| Error::io(ifile, e))?;
     let reader = BufReader::new(input);
 
-    let digest = sha256_digest(reader)?;
+    let digest = sha256_digest(reader).map_err(|e| Error::io(ifile, e))?;
     Ok(HEXUPPER.encode(digest.as_ref()))
 }
 
+/// sha256 of a string in lower hex.
+pub fn sha_str(s: &str) -> String {
+    HEXLOWER.encode(ring::digest::digest(&SHA256, s.as_bytes()).as_ref())
+}
+
+
+/// Every record of a csv with its line number, after checking the header has at least the columns we index by.
+pub fn read_csv_records(ifile: &str, columns: usize) -> Result<Vec<(usize, csv::StringRecord)>, Error> {
+
+    let mut rdr = csv::Reader::from_path(ifile).map_err(|e| Error::csv(ifile, e))?;
+    let found = rdr.headers().map_err(|e| Error::csv(ifile, e))?.len();
+    if found < columns {
+        return Err(Error::schema(ifile, format!("expected {} columns, found {}", columns, found)));
+    }
+    rdr.records().map(|record| {
+        let r = record.map_err(|e| Error::csv(ifile, e))?;
+        Ok((r.position().map(|p| p.line() as usize).unwrap_or(0), r))
+    }).collect()
+}
+
+/// trades carry unix seconds, marks and balances are kept by calendar day
+pub fn to_date(ts: i64) -> NaiveDate {
+    NaiveDateTime::from_timestamp_opt(ts, 0).unwrap_or_default().date()
+}
+
 
 #[cfg(test)]
 mod tests {